@@ -0,0 +1,84 @@
+use anyhow::Result;
+use log::{debug, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::SocketAddr;
+use tokio_util::sync::CancellationToken;
+
+const SERVICE_TYPE: &str = "_openrgb._tcp.local.";
+const SERVER_NAME: &str = "Color Hoster";
+
+/// Advertises the server over mDNS, so clients on the network can discover it, until the
+/// interrupt. Servers only reachable over loopback are not advertised.
+pub fn announce(address: SocketAddr, interrupt: CancellationToken) -> Result<()> {
+    if address.ip().is_loopback() {
+        warn!("Not announcing the server, as it only listens on {address}!");
+        return Ok(());
+    }
+
+    let host = hostname::get()?.to_string_lossy().into_owned();
+    let info = service_info(address, &host)?;
+    let fullname = info.get_fullname().to_string();
+
+    let daemon = ServiceDaemon::new()?;
+    daemon.register(info)?;
+    debug!("Announced the server as {fullname}!");
+
+    tokio::spawn(async move {
+        interrupt.cancelled().await;
+        _ = daemon.unregister(&fullname);
+        _ = daemon.shutdown();
+    });
+    Ok(())
+}
+
+fn service_info(address: SocketAddr, host: &str) -> Result<ServiceInfo> {
+    let instance = format!("{SERVER_NAME} on {host}");
+    let host_name = format!("{host}.local.");
+    let properties = [("name", SERVER_NAME)];
+
+    // Servers listening on every interface are announced with all of their addresses
+    let info = if address.ip().is_unspecified() {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host_name,
+            "",
+            address.port(),
+            &properties[..],
+        )?
+        .enable_addr_auto()
+    } else {
+        let ip = address.ip();
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host_name,
+            ip,
+            address.port(),
+            &properties[..],
+        )?
+    };
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_server() {
+        let info = service_info("192.168.1.5:6742".parse().unwrap(), "desk").unwrap();
+        assert_eq!(
+            info.get_fullname(),
+            "Color Hoster on desk._openrgb._tcp.local."
+        );
+        assert_eq!(info.get_hostname(), "desk.local.");
+        assert_eq!(info.get_port(), 6742);
+        assert_eq!(info.get_property_val_str("name"), Some("Color Hoster"));
+        assert!(!info.is_addr_auto());
+
+        let info = service_info("0.0.0.0:6742".parse().unwrap(), "desk").unwrap();
+        assert!(info.is_addr_auto());
+        assert!(info.get_addresses().is_empty());
+    }
+}
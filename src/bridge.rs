@@ -0,0 +1,453 @@
+use anyhow::Result;
+use colored::Colorize;
+use log::{debug, warn};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex as AsyncMutex,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    consts::{OPENRGB_PROTOCOL_VERSION, Request},
+    error::Error,
+    handlers::{check_length, negotiate_protocol},
+    utils::BufferExt,
+};
+
+const PACKET_MAGIC: u32 = 1111970383;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The last answers of the upstream server, served while it is unreachable
+#[derive(Default)]
+struct Cache {
+    count: u32,
+    controllers: HashMap<(u32, u32), Vec<u8>>,
+}
+
+/// Re-exposes the controllers of another OpenRGB server to local clients
+#[derive(Clone)]
+pub struct Bridge {
+    address: String,
+    max_request_length: u32,
+    cache: Arc<AsyncMutex<Cache>>,
+}
+
+/// A downstream client connection with its own connection to the upstream server
+pub struct Session {
+    bridge: Bridge,
+    upstream: Option<TcpStream>,
+    pub client: Option<String>,
+    protocol_version: u32,
+    handshake: Vec<Packet>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Packet {
+    device: u32,
+    kind: u32,
+    data: Vec<u8>,
+}
+
+impl Bridge {
+    pub fn new(address: String, max_request_length: u32) -> Self {
+        Bridge {
+            address,
+            max_request_length,
+            cache: Arc::new(AsyncMutex::new(Cache::default())),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Session {
+    pub async fn new(bridge: Bridge) -> Self {
+        let mut session = Session {
+            bridge,
+            upstream: None,
+            client: None,
+            protocol_version: 0,
+            handshake: Vec::new(),
+        };
+        session.connect().await;
+        session
+    }
+
+    pub async fn serve(
+        &mut self,
+        mut stream: TcpStream,
+        interrupt: CancellationToken,
+    ) -> Result<()> {
+        let (mut probe, mut upstream_probe) = ([0; 1], [0; 1]);
+        loop {
+            let offline = self.upstream.is_none();
+            tokio::select! {
+                _ = interrupt.cancelled() => return Ok(()),
+                peeked = stream.peek(&mut probe) => {
+                    peeked?;
+                    let packet = read_packet(&mut stream, self.bridge.max_request_length).await?;
+                    self.forward(&mut stream, packet).await?;
+                }
+                peeked = peek_upstream(&self.upstream, &mut upstream_probe) => {
+                    let limit = self.bridge.max_request_length;
+                    let result = match (peeked, self.upstream.as_mut()) {
+                        (Ok(0), _) | (Err(_), _) | (_, None) => None,
+                        (Ok(_), Some(upstream)) => read_packet(upstream, limit).await.ok(),
+                    };
+                    match result {
+                        Some(packet) if packet.kind == Request::DeviceListUpdated as u32 => {
+                            write_packet(&mut stream, &packet).await?;
+                        }
+                        Some(_) => {}
+                        None => self.disconnect(&mut stream).await?,
+                    }
+                }
+                _ = tokio::time::sleep(RECONNECT_INTERVAL), if offline => {
+                    if self.connect().await {
+                        notify(&mut stream).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connects to the upstream server and replays the client's handshake
+    async fn connect(&mut self) -> bool {
+        let address = self.bridge.address.clone();
+        let connection = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&address));
+        let Ok(Ok(mut upstream)) = connection.await else {
+            return false;
+        };
+
+        for packet in &self.handshake {
+            if write_packet(&mut upstream, packet).await.is_err() {
+                return false;
+            }
+            if packet.kind == Request::GetProtocolVersion as u32
+                && read_packet(&mut upstream, self.bridge.max_request_length)
+                    .await
+                    .is_err()
+            {
+                return false;
+            }
+        }
+
+        debug!("Connected to upstream server {}.", address.bold());
+        self.upstream = Some(upstream);
+        true
+    }
+
+    async fn disconnect(&mut self, stream: &mut TcpStream) -> Result<()> {
+        if self.upstream.take().is_some() {
+            warn!(
+                "Lost connection to upstream server {}\x1B[33m, reporting its devices as offline.",
+                self.bridge.address.bold()
+            );
+            notify(stream).await?;
+        }
+        Ok(())
+    }
+
+    async fn forward(&mut self, stream: &mut TcpStream, mut packet: Packet) -> Result<()> {
+        match Request::try_from(packet.kind).ok() {
+            Some(Request::GetProtocolVersion) => {
+                let requested = packet.data.read_u32_le(0)?;
+                self.protocol_version = negotiate_protocol(requested);
+                packet.data = self.protocol_version.to_le_bytes().to_vec();
+                self.remember(packet.clone());
+            }
+            Some(Request::SetClientName) => {
+                let name = packet.data.strip_suffix(&[0]).unwrap_or(&packet.data);
+                self.client = Some(String::from_utf8_lossy(name).to_string());
+                self.remember(packet.clone());
+            }
+            _ => {}
+        }
+
+        if self.upstream.is_some() {
+            match self.request(stream, &packet).await {
+                Ok(Some(response)) => {
+                    self.store(&packet, &response.data).await;
+                    return write_packet(stream, &response).await;
+                }
+                Ok(None) => return Ok(()),
+                Err(_) => self.disconnect(stream).await?,
+            }
+        }
+
+        match self.offline_response(&packet).await? {
+            Some(data) => write_packet(stream, &Packet { data, ..packet }).await,
+            None => Ok(()),
+        }
+    }
+
+    fn remember(&mut self, packet: Packet) {
+        self.handshake.retain(|x| x.kind != packet.kind);
+        self.handshake.push(packet);
+    }
+
+    /// Sends a packet upstream and waits for its response if it expects one.
+    /// Device list notifications arriving in the meantime are passed on to the client.
+    async fn request(&mut self, stream: &mut TcpStream, packet: &Packet) -> Result<Option<Packet>> {
+        let limit = self.bridge.max_request_length;
+        let Some(upstream) = self.upstream.as_mut() else {
+            return Ok(None);
+        };
+
+        write_packet(upstream, packet).await?;
+        if !expects_response(packet.kind) {
+            return Ok(None);
+        }
+
+        loop {
+            let response = read_packet(upstream, limit).await?;
+            if response.kind == packet.kind {
+                return Ok(Some(response));
+            }
+            if response.kind == Request::DeviceListUpdated as u32 {
+                write_packet(stream, &response).await?;
+            }
+        }
+    }
+
+    async fn store(&self, request: &Packet, response: &[u8]) {
+        let mut cache = self.bridge.cache.lock().await;
+        match Request::try_from(request.kind).ok() {
+            Some(Request::GetControllerCount) => {
+                cache.count = response.to_vec().read_u32_le(0).unwrap_or(0);
+            }
+            Some(Request::GetControllerData) => {
+                let key = (request.device, self.requested_protocol(request));
+                cache.controllers.insert(key, response.to_vec());
+            }
+            _ => {}
+        }
+    }
+
+    fn requested_protocol(&self, packet: &Packet) -> u32 {
+        match packet.data.len() {
+            0 => self.protocol_version,
+            _ => negotiate_protocol(packet.data.read_u32_le(0).unwrap_or(0)),
+        }
+    }
+
+    /// Answers from the cache, marking the devices as offline. Writes are dropped.
+    async fn offline_response(&self, packet: &Packet) -> Result<Option<Vec<u8>>> {
+        let cache = self.bridge.cache.lock().await;
+        Ok(match Request::try_from(packet.kind).ok() {
+            Some(Request::GetProtocolVersion) => {
+                Some(OPENRGB_PROTOCOL_VERSION.to_le_bytes().to_vec())
+            }
+            Some(Request::GetControllerCount) => Some(cache.count.to_le_bytes().to_vec()),
+            Some(Request::GetControllerData) => {
+                let protocol = self.requested_protocol(packet);
+                let data = cache
+                    .controllers
+                    .get(&(packet.device, protocol))
+                    .ok_or(Error::Protocol("Unknown device!".into()))?;
+                Some(mark_offline(data, protocol)?)
+            }
+            Some(Request::GetProfileList) => {
+                let mut buffer = 6u32.to_le_bytes().to_vec();
+                buffer.extend_from_slice(&0u16.to_le_bytes());
+                Some(buffer)
+            }
+            Some(Request::SaveProfile | Request::LoadProfile | Request::DeleteProfile) => {
+                let mut buffer = 1u32.to_le_bytes().to_vec();
+                buffer.extend_from_str("Upstream server is not connected!");
+                Some(buffer)
+            }
+            _ if expects_response(packet.kind) => Err(Error::Device(format!(
+                "Upstream server {} is not connected!",
+                self.bridge.address
+            )))?,
+            _ => None,
+        })
+    }
+}
+
+fn expects_response(kind: u32) -> bool {
+    matches!(
+        Request::try_from(kind).ok(),
+        Some(
+            Request::GetControllerCount
+                | Request::GetControllerData
+                | Request::GetProtocolVersion
+                | Request::GetProfileList
+                | Request::SaveProfile
+                | Request::LoadProfile
+                | Request::DeleteProfile
+                | Request::GetServerInfo
+        )
+    )
+}
+
+/// Waits for data from the upstream server without consuming it, so it is safe to cancel
+async fn peek_upstream(upstream: &Option<TcpStream>, probe: &mut [u8]) -> std::io::Result<usize> {
+    match upstream {
+        Some(upstream) => upstream.peek(probe).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn notify(stream: &mut TcpStream) -> Result<()> {
+    let packet = Packet {
+        device: 0,
+        kind: Request::DeviceListUpdated.into(),
+        data: Vec::new(),
+    };
+    write_packet(stream, &packet).await
+}
+
+async fn read_packet(stream: &mut TcpStream, limit: u32) -> Result<Packet> {
+    if stream.read_u32_le().await? != PACKET_MAGIC {
+        return Err(Error::Protocol("Invalid packet header!".into()).into());
+    }
+
+    let device = stream.read_u32_le().await?;
+    let kind = stream.read_u32_le().await?;
+    let mut data = vec![0; check_length(stream.read_u32_le().await?, limit)?];
+    stream.read_exact(&mut data).await?;
+    Ok(Packet { device, kind, data })
+}
+
+async fn write_packet(stream: &mut TcpStream, packet: &Packet) -> Result<()> {
+    let mut buffer = b"ORGB".to_vec();
+    buffer.extend_from_u32s(&[packet.device, packet.kind, packet.data.len() as u32]);
+    buffer.extend_from_slice(&packet.data);
+    stream.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// Appends " (Offline)" to the description of serialized controller data
+fn mark_offline(data: &[u8], protocol: u32) -> Result<Vec<u8>> {
+    let malformed = || Error::Protocol("Malformed upstream controller data!".into());
+    let string_at = |offset: usize| -> Result<usize> {
+        let bytes = data.get(offset..offset + 2).ok_or_else(malformed)?;
+        let end = offset + 2 + u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if end > data.len() {
+            Err(malformed())?
+        }
+        Ok(end)
+    };
+
+    // Data size and device type come first, followed by the name and vendor strings
+    let mut offset = string_at(8)?;
+    if protocol >= 1 {
+        offset = string_at(offset)?;
+    }
+    let end = string_at(offset)?;
+
+    let description = String::from_utf8_lossy(&data[offset + 2..end]);
+    let description = description.trim_end_matches('\0');
+    let description = description
+        .strip_suffix(" (Degraded)")
+        .unwrap_or(description)
+        .trim_end_matches(" (Offline)");
+
+    let mut buffer = data[..offset].to_vec();
+    buffer.extend_from_str(&format!("{description} (Offline)"));
+    buffer.extend_from_slice(&data[end..]);
+    let buffer_length = buffer.len() as u32;
+    buffer[0..4].copy_from_slice(&buffer_length.to_le_bytes());
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn controller() -> Vec<u8> {
+        let mut buffer = 0u32.to_le_bytes().to_vec();
+        buffer.extend_from_slice(&0i32.to_le_bytes());
+        buffer.extend_from_str("Board");
+        buffer.extend_from_str("Unknown");
+        buffer.extend_from_str("Board via ColorHoster");
+        buffer.extend_from_str("1.0.0");
+        let buffer_length = buffer.len() as u32;
+        buffer[0..4].copy_from_slice(&buffer_length.to_le_bytes());
+        buffer
+    }
+
+    async fn exchange(client: &mut TcpStream, kind: Request, data: &[u8]) -> Packet {
+        let kind = kind.into();
+        let packet = Packet {
+            device: 0,
+            kind,
+            data: data.to_vec(),
+        };
+        write_packet(client, &packet).await.unwrap();
+        loop {
+            let response = read_packet(client, u32::MAX).await.unwrap();
+            if response.kind == kind {
+                return response;
+            }
+        }
+    }
+
+    #[test]
+    fn marks_descriptions_offline() {
+        let offline = mark_offline(&controller(), OPENRGB_PROTOCOL_VERSION).unwrap();
+        let description = b"Board via ColorHoster (Offline)\0";
+        assert!(offline.windows(description.len()).any(|x| x == description));
+        assert_eq!(
+            offline.to_vec().read_u32_le(0).unwrap(),
+            offline.len() as u32
+        );
+        assert_eq!(
+            mark_offline(&offline, OPENRGB_PROTOCOL_VERSION).unwrap(),
+            offline
+        );
+    }
+
+    #[tokio::test]
+    async fn enumerates_upstream_controllers() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = upstream.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            while let Ok(packet) = read_packet(&mut stream, u32::MAX).await {
+                let data = match Request::try_from(packet.kind).ok() {
+                    Some(Request::GetControllerCount) => 1u32.to_le_bytes().to_vec(),
+                    Some(Request::GetControllerData) => controller(),
+                    _ => continue,
+                };
+                write_packet(&mut stream, &Packet { data, ..packet })
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let downstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = downstream.local_addr().unwrap();
+        let bridge = Bridge::new(address, u32::MAX);
+        tokio::spawn(async move {
+            let (stream, _) = downstream.accept().await.unwrap();
+            let mut session = Session::new(bridge).await;
+            _ = session.serve(stream, CancellationToken::new()).await;
+        });
+
+        let mut client = TcpStream::connect(local).await.unwrap();
+        let count = exchange(&mut client, Request::GetControllerCount, &[]).await;
+        assert_eq!(count.data, 1u32.to_le_bytes());
+        let version = OPENRGB_PROTOCOL_VERSION.to_le_bytes();
+        let data = exchange(&mut client, Request::GetControllerData, &version).await;
+        assert_eq!(data.data, controller());
+
+        server.abort();
+        _ = server.await;
+        let data = exchange(&mut client, Request::GetControllerData, &version).await;
+        let description = b"(Offline)";
+        assert!(
+            data.data
+                .windows(description.len())
+                .any(|x| x == description)
+        );
+    }
+}
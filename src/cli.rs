@@ -1,10 +1,16 @@
 use anyhow::Result;
-use clap::{Parser, ValueEnum};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
+use log::LevelFilter;
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
-use crate::consts::OPENRGB_SDK_DEFAULT_PORT;
+use crate::{consts::OPENRGB_SDK_DEFAULT_PORT, group::GroupConfig, profiles::ProfileFormat};
 
 /// Color Hoster is OpenRGB compatible high-performance SDK server for VIA per-key RGB
 #[derive(Parser, Debug, Serialize, Deserialize, Clone)]
@@ -14,12 +20,12 @@ use crate::consts::OPENRGB_SDK_DEFAULT_PORT;
     after_help = format!("{} ./ColorHoster -b -j ./p1_he_ansi_v1.0.json", "Example:".bold())
 )]
 pub struct CLI {
-    /// Set a directory to look for VIA `.json` definitions for keyboards [default: <executable directory>]
+    /// Set a directory to look for VIA `.json` (or OpenRGB `.orp`) definitions for keyboards [default: <executable directory>]
     #[arg(short, long)]
     #[serde(skip_serializing_if = "default")]
     pub directory: Option<PathBuf>,
 
-    /// Add a direct path to a VIA `.json` file (can be multiple)
+    /// Add a direct path to a VIA `.json` or OpenRGB `.orp` file (can be multiple)
     #[arg(short, long)]
     #[serde(skip_serializing_if = "default")]
     pub json: Vec<std::path::PathBuf>,
@@ -29,20 +35,288 @@ pub struct CLI {
     #[serde(skip_serializing_if = "default")]
     pub brightness: bool,
 
+    /// Only read the keymap and colors on startup, for boards that support nothing but direct mode
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub direct_only: bool,
+
+    /// Read the keyboards' state, but never write anything to them (for diagnosing issues)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub read_only: bool,
+
+    /// Simulate a keyboard for every definition instead of using the connected ones (for trying definitions and clients out)
+    #[arg(long, visible_alias = "virtual")]
+    #[serde(skip_serializing_if = "default")]
+    pub dry_run: bool,
+
+    /// Present the LEDs of rotary encoders to clients as an extra row below the keys
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub include_encoders: bool,
+
+    /// Clear per-LED colors to black when switching from the custom mode to another effect
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub clear_custom_colors: bool,
+
+    /// Remember speed, brightness and color of every effect, restoring them when switching back
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub remember_effect_settings: bool,
+
+    /// Never let the brightness of the keyboards go below this value (0-255), while clients still see the requested one
+    #[serde(skip_serializing_if = "default")]
+    #[arg(long, default_value_t = 0)]
+    pub min_brightness: u8,
+
+    /// Apply this gamma to the brightness of per-LED colors, so dim colors do not look washed out [default: 1.0]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub gamma: Option<f32>,
+
+    /// Milliseconds to wait for a keyboard to answer a request before retrying [default: 1000]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub report_timeout: Option<u64>,
+
+    /// Keep at most this many requests to the keyboards awaiting an answer at once [default: 8]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_requests: Option<usize>,
+
+    /// Write per-LED colors to the keyboards at most this many times a second, merging the frames in between [default: unlimited]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_fps: Option<u32>,
+
     /// Set a directory for storing and loading profiles [default: ./profiles]
     #[arg(long)]
     #[serde(skip_serializing_if = "default")]
     pub profiles: Option<PathBuf>,
 
+    /// Load this profile onto every compatible keyboard on startup and onto the ones connected later on
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub default_profile: Option<String>,
+
+    /// Reapply profiles to keyboards using them when their files change
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub watch_profiles: bool,
+
+    /// Save profiles as ColorHoster's own `.json` states instead of OpenRGB's `.orp` files
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub json_profiles: bool,
+
+    /// Move deleted profiles to a `.trash` directory, keeping them restorable for this many days
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub keep_deleted_profiles: Option<u64>,
+
+    /// Refuse to save new profiles once this many exist
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_profiles: Option<usize>,
+
+    /// Refuse to save profiles that would grow the profiles directory past this many bytes
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_profile_bytes: Option<u64>,
+
+    /// Save the state of every keyboard to its firmware when the server stops
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub persist_on_exit: bool,
+
+    /// Set the address to listen on (anything but loopback exposes the server to other machines)
+    #[serde(default = "default_host", skip_serializing_if = "is_default_host")]
+    #[arg(long, default_value_t = default_host())]
+    pub host: IpAddr,
+
+    /// Require clients to present this secret before they can change anything
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub auth_token: Option<String>,
+
+    /// Advertise the server over mDNS, so clients on the network can discover it (not on loopback)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub announce: bool,
+
+    /// Also listen on a Unix domain socket at this path (Linux and macOS only)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub socket: Option<PathBuf>,
+
     /// Set the port to listen on
     #[serde(default = "default_port", skip_serializing_if = "is_default_port")]
     #[arg(short, long, default_value_t = default_port())]
-    pub port: u32,
+    pub port: u16,
+
+    /// Also serve a JSON API over HTTP on this port, at the same address
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub http_port: Option<u16>,
+
+    /// Disconnect clients declaring requests longer than this many bytes [default: 1048576]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_request_bytes: Option<u32>,
+
+    /// Refuse new clients while this many are connected [default: 32]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_clients: Option<usize>,
+
+    /// Warn about clients sending more than this many requests per second
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub max_client_rate: Option<u32>,
+
+    /// Hold off the requests of clients over `--max-client-rate`, instead of only warning about them
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub throttle_clients: bool,
+
+    /// Drop clients that stall mid-request for this many seconds (also enables TCP keepalive)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub client_timeout: Option<u64>,
+
+    /// Record the colors sent by clients into a file, to be played back with the `replay` command
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub record: Option<PathBuf>,
+
+    /// Re-expose the devices of another OpenRGB server (`host:port`) instead of local keyboards
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub upstream: Option<String>,
+
+    /// Wait up to this many seconds for all keyboards to connect before reporting startup
+    #[serde(skip_serializing_if = "default")]
+    #[arg(long, default_value_t = 0)]
+    pub startup_grace: u64,
+
+    /// Exit with an error if not every keyboard definition has a connected device
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub require_all_devices: bool,
+
+    /// Write the process id into this file while the server runs (for process supervisors)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub pidfile: Option<PathBuf>,
+
+    /// Set how much is logged, `RUST_LOG` takes precedence when set [default: debug]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub log_level: Option<LogLevel>,
+
+    /// Log more than `--log-level` (can be repeated)
+    #[serde(skip)]
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log less than `--log-level` (can be repeated)
+    #[serde(skip)]
+    #[arg(short, long, action = ArgAction::Count)]
+    pub quiet: u8,
+
+    /// Start a new log file once it grows past this many megabytes (only when not in a terminal) [default: 10]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub log_max_size: Option<u64>,
+
+    /// Keep this many previous log files next to the current one [default: 3]
+    #[arg(long)]
+    #[serde(skip_serializing_if = "default")]
+    pub log_files: Option<usize>,
+
+    /// List devices to clients in this order, as `vvvv:pppp` models or `vvvv:pppp:<serial>` keyboards, followed by the unlisted ones (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "default")]
+    pub device_order: Vec<String>,
+
+    /// Only serve these keyboards, given as `vvvv:pppp` models or `vvvv:pppp:<serial>` keyboards (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "default")]
+    pub include: Vec<String>,
+
+    /// Leave these keyboards alone, given like `--include` and taking precedence over it (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "default")]
+    pub exclude: Vec<String>,
+
+    /// Present keyboards to clients under another name, given as `vvvv:pppp=<name>` for a model or `vvvv:pppp:<serial>=<name>` for a single keyboard (can be multiple)
+    #[arg(long = "name")]
+    #[serde(skip_serializing_if = "default")]
+    pub names: Vec<String>,
+
+    /// Keyboards presented as a single device (only set in `colorhoster.toml`)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "default")]
+    pub groups: Vec<GroupConfig>,
 
     /// Manage Color Hoster service
     #[serde(skip)]
     #[arg(short, long)]
     pub service: Option<ServiceAction>,
+
+    #[serde(skip)]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// One-off commands that run against the connected keyboards and exit without starting the server
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// List the keyboards matching the definitions, along with the definitions matching none
+    ListDevices,
+    /// Print the raw per-LED HSV state of every keyboard as JSON
+    DumpState,
+    /// Print the LED indices under every key with the given OpenRGB name (e.g. "Left Shift")
+    FindKey { name: String },
+    /// Render the current per-key colors of every device into a PNG image
+    Snapshot { path: PathBuf },
+    /// Light up LEDs one at a time in index order to verify the mapping, then restore the colors
+    TestPattern {
+        /// Milliseconds to keep each LED lit
+        #[arg(long, default_value_t = 500)]
+        dwell: u64,
+    },
+    /// Move a deleted profile back from the trash (see `--keep-deleted-profiles`)
+    RestoreProfile { name: String },
+    /// Play back colors recorded with `--record` at their original pacing
+    Replay { path: PathBuf },
+    /// Switch to an effect (by its value or name) and save it to the keyboards' memory
+    ApplyEffect {
+        effect: String,
+        /// Only change the device with this index (as listed for clients) or `vid:pid`
+        #[arg(long)]
+        device: Option<String>,
+    },
+    /// Set the color of effects (as `rrggbb`) and save it to the keyboards' memory
+    ApplyColor {
+        color: String,
+        /// Only change the device with this index (as listed for clients) or `vid:pid`
+        #[arg(long)]
+        device: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    #[default]
+    Debug,
+    Trace,
 }
 
 #[derive(Clone, Debug, ValueEnum, Serialize, Deserialize)]
@@ -65,13 +339,86 @@ impl CLI {
                 cli.json
             },
             brightness: cli.brightness || config.brightness,
+            direct_only: cli.direct_only || config.direct_only,
+            read_only: cli.read_only || config.read_only,
+            dry_run: cli.dry_run || config.dry_run,
+            include_encoders: cli.include_encoders || config.include_encoders,
+            clear_custom_colors: cli.clear_custom_colors || config.clear_custom_colors,
+            remember_effect_settings: cli.remember_effect_settings
+                || config.remember_effect_settings,
+            min_brightness: if cli.min_brightness == 0 {
+                config.min_brightness
+            } else {
+                cli.min_brightness
+            },
+            gamma: cli.gamma.or(config.gamma),
+            report_timeout: cli.report_timeout.or(config.report_timeout),
+            max_requests: cli.max_requests.or(config.max_requests),
+            max_fps: cli.max_fps.or(config.max_fps),
             profiles: cli.profiles.or(config.profiles),
-            port: if cli.port == 6742 {
+            default_profile: cli.default_profile.or(config.default_profile),
+            watch_profiles: cli.watch_profiles || config.watch_profiles,
+            json_profiles: cli.json_profiles || config.json_profiles,
+            keep_deleted_profiles: cli.keep_deleted_profiles.or(config.keep_deleted_profiles),
+            max_profiles: cli.max_profiles.or(config.max_profiles),
+            max_profile_bytes: cli.max_profile_bytes.or(config.max_profile_bytes),
+            persist_on_exit: cli.persist_on_exit || config.persist_on_exit,
+            host: if is_default_host(&cli.host) {
+                config.host
+            } else {
+                cli.host
+            },
+            auth_token: cli.auth_token.or(config.auth_token),
+            announce: cli.announce || config.announce,
+            socket: cli.socket.or(config.socket),
+            port: if cli.port == OPENRGB_SDK_DEFAULT_PORT {
                 config.port
             } else {
                 cli.port
             },
+            http_port: cli.http_port.or(config.http_port),
+            max_request_bytes: cli.max_request_bytes.or(config.max_request_bytes),
+            max_clients: cli.max_clients.or(config.max_clients),
+            max_client_rate: cli.max_client_rate.or(config.max_client_rate),
+            throttle_clients: cli.throttle_clients || config.throttle_clients,
+            client_timeout: cli.client_timeout.or(config.client_timeout),
+            record: cli.record.or(config.record),
+            upstream: cli.upstream.or(config.upstream),
+            startup_grace: if cli.startup_grace == 0 {
+                config.startup_grace
+            } else {
+                cli.startup_grace
+            },
+            require_all_devices: cli.require_all_devices || config.require_all_devices,
+            pidfile: cli.pidfile.or(config.pidfile),
+            log_level: cli.log_level.or(config.log_level),
+            verbose: cli.verbose,
+            quiet: cli.quiet,
+            log_max_size: cli.log_max_size.or(config.log_max_size),
+            log_files: cli.log_files.or(config.log_files),
+            device_order: if cli.device_order.is_empty() {
+                config.device_order
+            } else {
+                cli.device_order
+            },
+            include: if cli.include.is_empty() {
+                config.include
+            } else {
+                cli.include
+            },
+            exclude: if cli.exclude.is_empty() {
+                config.exclude
+            } else {
+                cli.exclude
+            },
+            names: if cli.names.is_empty() {
+                config.names
+            } else {
+                cli.names
+            },
+            groups: config.groups,
             service: cli.service.or(config.service),
+            command: cli.command,
         }
     }
 
@@ -97,6 +444,41 @@ impl CLI {
         CLI::current_dir().join("colorhoster.toml")
     }
 
+    pub fn profiles_dir(&self) -> PathBuf {
+        self.profiles
+            .clone()
+            .unwrap_or_else(|| CLI::current_dir().join(PathBuf::from("./profiles")))
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    pub fn profile_format(&self) -> ProfileFormat {
+        if self.json_profiles {
+            ProfileFormat::Json
+        } else {
+            ProfileFormat::Orp
+        }
+    }
+
+    pub fn profile_retention(&self) -> Option<Duration> {
+        self.keep_deleted_profiles
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    /// The level to log at, where a valid `RUST_LOG` level overrides the options
+    pub fn log_level(&self) -> LevelFilter {
+        if let Some(level) = std::env::var("RUST_LOG").ok().and_then(|x| x.parse().ok()) {
+            return level;
+        }
+
+        let levels = LevelFilter::iter().collect::<Vec<_>>();
+        let level = self.log_level.unwrap_or_default() as usize;
+        let level = (level + self.verbose as usize).saturating_sub(self.quiet as usize);
+        levels[level.min(levels.len() - 1)]
+    }
+
     pub fn current_dir() -> PathBuf {
         std::env::current_exe()
             .expect("Failed to get current executable path!")
@@ -112,14 +494,66 @@ impl Default for CLI {
             directory: None,
             json: Vec::new(),
             brightness: false,
+            direct_only: false,
+            read_only: false,
+            dry_run: false,
+            include_encoders: false,
+            clear_custom_colors: false,
+            remember_effect_settings: false,
+            min_brightness: 0,
+            gamma: None,
+            report_timeout: None,
+            max_requests: None,
+            max_fps: None,
             profiles: None,
+            default_profile: None,
+            watch_profiles: false,
+            json_profiles: false,
+            keep_deleted_profiles: None,
+            max_profiles: None,
+            max_profile_bytes: None,
+            persist_on_exit: false,
+            host: default_host(),
+            auth_token: None,
+            announce: false,
+            socket: None,
             port: OPENRGB_SDK_DEFAULT_PORT,
+            http_port: None,
+            max_request_bytes: None,
+            max_clients: None,
+            max_client_rate: None,
+            throttle_clients: false,
+            client_timeout: None,
+            record: None,
+            upstream: None,
+            startup_grace: 0,
+            require_all_devices: false,
+            pidfile: None,
+            log_level: None,
+            verbose: 0,
+            quiet: 0,
+            log_max_size: None,
+            log_files: None,
+            device_order: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            names: Vec::new(),
+            groups: Vec::new(),
             service: None,
+            command: None,
         }
     }
 }
 
-fn default_port() -> u32 {
+fn default_host() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn is_default_host(host: &IpAddr) -> bool {
+    *host == default_host()
+}
+
+fn default_port() -> u16 {
     OPENRGB_SDK_DEFAULT_PORT
 }
 
@@ -127,6 +561,6 @@ fn default<T: Default + PartialEq>(t: &T) -> bool {
     *t == T::default()
 }
 
-fn is_default_port(port: &u32) -> bool {
+fn is_default_port(port: &u16) -> bool {
     *port == OPENRGB_SDK_DEFAULT_PORT
 }
@@ -0,0 +1,305 @@
+use anyhow::Result;
+use colored::Colorize;
+use itertools::Itertools;
+use log::{info, warn};
+use palette::rgb::Rgb;
+use std::{path::Path, time::Duration};
+use tokio::runtime::Runtime;
+
+use crate::{
+    cli::CLI, cli::Command, config::Config, error::Error, group::Controller, http::parse_hex,
+    keyboard::KeyboardId, keyboards::Keyboards, load_keyboards, profiles, recorder, render,
+};
+
+pub fn run(command: Command, args: CLI) -> Result<()> {
+    if let Command::RestoreProfile { name } = &command {
+        profiles::restore(&args.profiles_dir(), name, args.profile_retention())?;
+        info!("Profile {} restored!", name.bold());
+        return Ok(());
+    }
+
+    let runtime = Runtime::new()?;
+    runtime.block_on(async {
+        let keyboards = load_keyboards(&args).await?;
+        match command {
+            Command::ListDevices => list_devices(&keyboards).await,
+            Command::DumpState => dump_state(&keyboards).await,
+            Command::FindKey { name } => find_key(&keyboards, &name).await,
+            Command::Snapshot { path } => snapshot(&keyboards, &path).await,
+            Command::TestPattern { dwell } => {
+                let dwell = Duration::from_millis(dwell);
+                test_pattern(&keyboards, dwell, args.brightness).await
+            }
+            Command::Replay { path } => replay(&keyboards, &path, args.brightness).await,
+            Command::ApplyEffect { effect, device } => {
+                apply_effect(&keyboards, &effect, device.as_deref()).await
+            }
+            Command::ApplyColor { color, device } => {
+                apply_color(&keyboards, &color, device.as_deref()).await
+            }
+            Command::RestoreProfile { .. } => Ok(()),
+        }
+    })
+}
+
+async fn list_devices(keyboards: &Keyboards) -> Result<()> {
+    for keyboard in keyboards.items().await.values() {
+        let device_id = match keyboard.device_id().await {
+            KeyboardId::Device(id) => format!("{id:?}"),
+            KeyboardId::Virtual(_) => "simulated".into(),
+        };
+        println!("{}", describe(&keyboard.config(), &device_id));
+    }
+    for config in keyboards.missing() {
+        println!(
+            "No device matches {} ({})",
+            config.name.bold(),
+            config.hardware_id()
+        );
+    }
+    Ok(())
+}
+
+fn describe(config: &Config, device_id: &str) -> String {
+    let effects = config.effects.iter().map(|(name, ..)| name).join(", ");
+    format!(
+        "{} ({})\n  Device: {device_id}\n  LEDs: {}\n  Effects: {}",
+        config.display_name().bold(),
+        config.hardware_id(),
+        config.count_leds(),
+        if effects.is_empty() { "none" } else { &effects },
+    )
+}
+
+async fn dump_state(keyboards: &Keyboards) -> Result<()> {
+    let mut dumps = Vec::new();
+    for keyboard in keyboards.items().await.values() {
+        dumps.push(keyboard.dump_state().await);
+    }
+
+    println!("{}", serde_json::to_string_pretty(&dumps)?);
+    Ok(())
+}
+
+async fn find_key(keyboards: &Keyboards, name: &str) -> Result<()> {
+    let mut matches = serde_json::Map::new();
+    for controller in keyboards.controllers().await {
+        let snapshot = controller.snapshot().await;
+        matches.insert(snapshot.config.name.clone(), snapshot.find_key(name).into());
+    }
+
+    println!("{}", serde_json::to_string_pretty(&matches)?);
+    Ok(())
+}
+
+async fn snapshot(keyboards: &Keyboards, path: &Path) -> Result<()> {
+    let mut snapshots = Vec::new();
+    for controller in keyboards.controllers().await {
+        snapshots.push(controller.snapshot().await);
+    }
+
+    let (width, height) = render::write_png(path, &snapshots)?;
+    info!("Snapshot {width}x{height} written to {path:?}");
+    Ok(())
+}
+
+async fn replay(keyboards: &Keyboards, path: &Path, with_brightness: bool) -> Result<()> {
+    let frames = recorder::read_frames(path)?;
+    let controllers = keyboards.controllers().await;
+    let playback = recorder::replay(&frames, async |frame| {
+        let Some(controller) = controllers.get(frame.device) else {
+            return;
+        };
+        let count = controller.config().await.count_leds() as usize;
+        controller.update_colors(frame.fit(count), frame.offset, with_brightness);
+    });
+
+    tokio::select! {
+        _ = playback => info!("Replayed {} frames from {path:?}", frames.len()),
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    Ok(())
+}
+
+async fn test_pattern(keyboards: &Keyboards, dwell: Duration, with_brightness: bool) -> Result<()> {
+    for controller in keyboards.controllers().await {
+        let snapshot = controller.snapshot().await;
+        let state = controller.save_state().await?;
+        let name = &snapshot.config.name;
+        if let Some(effect) = snapshot.config.custom_effect() {
+            controller.update_effect(effect);
+        }
+
+        let walk = async {
+            for (led, frame) in pattern_frames(snapshot.colors.len()).enumerate() {
+                let key = snapshot.key_name(led as u8).unwrap_or("no key");
+                info!("{} LED {} is under {}", name.bold(), led, key.bold());
+                controller.update_colors(frame, 0, with_brightness);
+                tokio::time::sleep(dwell).await;
+            }
+        };
+        let interrupted = tokio::select! {
+            _ = walk => false,
+            _ = tokio::signal::ctrl_c() => true,
+        };
+
+        controller.load_state(state, with_brightness)?;
+        controller.flush().await;
+        if interrupted {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_effect(keyboards: &Keyboards, effect: &str, device: Option<&str>) -> Result<()> {
+    let mut applied = 0;
+    for controller in targets(keyboards, device).await? {
+        let config = controller.config().await;
+        let Some(value) = find_effect(&config, effect) else {
+            warn!("{} has no effect {}!", config.name.bold(), effect.bold());
+            continue;
+        };
+        controller.update_effect(value);
+        controller.persist_state();
+        controller.flush().await;
+        info!(
+            "{} switched to effect {}",
+            config.name.bold(),
+            effect.bold()
+        );
+        applied += 1;
+    }
+
+    if applied == 0 {
+        return Err(Error::Config(format!("No device has effect {effect}!")).into());
+    }
+    Ok(())
+}
+
+async fn apply_color(keyboards: &Keyboards, color: &str, device: Option<&str>) -> Result<()> {
+    let rgb = parse_hex(color)
+        .ok_or_else(|| Error::Config(format!("Invalid color {color}, expected rrggbb!")))?;
+    for controller in targets(keyboards, device).await? {
+        controller.update_color(rgb);
+        controller.persist_state();
+        controller.flush().await;
+        let name = controller.config().await.name.clone();
+        info!("{} switched to color {}", name.bold(), color.bold());
+    }
+    Ok(())
+}
+
+/// Picks the devices a one-off change applies to, every one of them unless a target is given
+async fn targets(keyboards: &Keyboards, device: Option<&str>) -> Result<Vec<Controller>> {
+    let controllers = keyboards.controllers().await;
+    let Some(device) = device else {
+        return Ok(controllers);
+    };
+
+    let mut found = Vec::new();
+    for (index, controller) in controllers.into_iter().enumerate() {
+        let config = controller.config().await;
+        let model = format!("{:04x}:{:04x}", config.vendor_id, config.product_id);
+        if device == index.to_string() || device.eq_ignore_ascii_case(&model) {
+            found.push(controller);
+        }
+    }
+    if found.is_empty() {
+        return Err(Error::NoDevices(format!("No device matches {device}!")).into());
+    }
+    Ok(found)
+}
+
+/// Finds an effect of a definition by its value or its name (case-insensitive)
+fn find_effect(config: &Config, effect: &str) -> Option<u8> {
+    if let Ok(value) = effect.parse() {
+        return config.find_effect(value);
+    }
+    let found = config
+        .effects
+        .iter()
+        .find(|x| x.0.eq_ignore_ascii_case(effect))?;
+    config.find_effect(found.1)
+}
+
+/// Every frame lights a single LED white over red ones, which works without per-LED brightness
+fn pattern_frames(count: usize) -> impl Iterator<Item = Vec<Option<Rgb>>> {
+    (0..count).map(move |lit| {
+        (0..count)
+            .map(|led| {
+                if led == lit {
+                    Some(Rgb::new(1.0, 1.0, 1.0))
+                } else {
+                    Some(Rgb::new(1.0, 0.0, 0.0))
+                }
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::DEFAULT_MAX_REQUESTS, keyboards::Filter};
+
+    #[test]
+    fn lights_leds_in_ascending_order() {
+        let white = Some(Rgb::new(1.0, 1.0, 1.0));
+        let lit: Vec<Vec<_>> = pattern_frames(4)
+            .map(|frame| (0..frame.len()).filter(|&x| frame[x] == white).collect())
+            .collect();
+
+        assert_eq!(lit, vec![vec![0], vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn finds_effects_by_value_or_name() {
+        let config = Config::fixture("gradient");
+        assert_eq!(find_effect(&config, "2"), Some(2));
+        assert_eq!(find_effect(&config, "reactive gradient"), Some(3));
+        assert_eq!(find_effect(&config, "42"), None);
+        assert_eq!(find_effect(&config, "Rainbow"), None);
+    }
+
+    #[tokio::test]
+    async fn targets_devices_by_index_or_model() {
+        let config = Config::fixture("gradient");
+        let configs = [((config.vendor_id, config.product_id), config)].into();
+        let keyboards = Keyboards::simulate(
+            configs,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Filter::default(),
+            DEFAULT_MAX_REQUESTS,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(targets(&keyboards, None).await.unwrap().len(), 1);
+        assert_eq!(targets(&keyboards, Some("0")).await.unwrap().len(), 1);
+        assert_eq!(
+            targets(&keyboards, Some("362D:0210")).await.unwrap().len(),
+            1
+        );
+        assert!(targets(&keyboards, Some("1")).await.is_err());
+        assert!(targets(&keyboards, Some("362d:0000")).await.is_err());
+    }
+
+    #[test]
+    fn describes_matched_keyboards() {
+        let config = Config::fixture("gradient");
+        let description = describe(&config, "hid-1");
+
+        let lines: Vec<_> = description.lines().collect();
+        assert_eq!(lines[0], format!("{} (362d:0210)", config.name.bold()));
+        assert_eq!(lines[1], "  Device: hid-1");
+        assert_eq!(lines[2], format!("  LEDs: {}", config.count_leds()));
+        assert_eq!(
+            lines[3],
+            "  Effects: None, Solid Color, Gradient, Reactive Gradient"
+        );
+    }
+}
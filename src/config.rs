@@ -3,12 +3,21 @@ use evalexpr::{
     ContextWithMutableVariables, HashMapContext, Node, Value as EvalValue, build_operator_tree,
 };
 use itertools::Itertools;
+use log::warn;
+use palette::rgb::Rgb;
 use serde::Deserialize;
 use serde_json::Value;
+use std::{collections::HashMap, time::Duration};
 
-use crate::consts::{
-    MODE_FLAG_HAS_BRIGHTNESS, MODE_FLAG_HAS_MODE_SPECIFIC_COLOR, MODE_FLAG_HAS_PER_LED_COLOR,
-    MODE_FLAG_HAS_RANDOM_COLOR, MODE_FLAG_HAS_SPEED, MODE_FLAG_MANUAL_SAVE,
+use crate::{
+    consts::{
+        DEFAULT_REPORT_TIMEOUT, DIRECT_MODE, MODE_FLAG_HAS_BRIGHTNESS, MODE_FLAG_HAS_DIRECTION_HV,
+        MODE_FLAG_HAS_DIRECTION_LR, MODE_FLAG_HAS_DIRECTION_UD, MODE_FLAG_HAS_MODE_SPECIFIC_COLOR,
+        MODE_FLAG_HAS_PER_LED_COLOR, MODE_FLAG_HAS_RANDOM_COLOR, MODE_FLAG_HAS_SPEED,
+        MODE_FLAG_MANUAL_SAVE, QMK_MAX_LEDS, openrgb_keycode,
+    },
+    error::Error,
+    orp::{self, SavedController, SavedMode},
 };
 
 type Position = (u8, u8);
@@ -20,15 +29,335 @@ pub struct Config {
     pub name: String,
     pub vendor_id: u16,
     pub product_id: u16,
+    /// Serial number of the device, known once the definition is matched to one
+    pub serial: Option<String>,
+    /// Name presented to clients instead of the one of the definition, set with `--name`
+    pub display_name: Option<String>,
     pub leds: Vec<(u8, Position)>,
     pub effects: Vec<Effect>,
+    /// Whether the only effect is a direct mode made up for clients, unknown to the firmware
+    pub synthetic_direct: bool,
     pub speed: Range,
     pub brightness: Range,
+    pub speed_hints: RangeHints,
+    pub brightness_hints: RangeHints,
     pub matrix: (u32, u32),
+    pub corrections: Vec<ColorCorrection>,
+    pub remap: Vec<u32>,
+    pub zones: Vec<Zone>,
+    pub segments: Vec<Zone>,
+    pub direct_only: bool,
+    pub no_per_led_brightness: bool,
+    pub clear_custom_colors: bool,
+    pub remember_effect_settings: bool,
+    pub min_brightness: u8,
+    /// Exponent applied to the brightness of per-LED colors, `1.0` leaves them unchanged
+    pub gamma: f32,
+    pub report_timeout: Duration,
+    /// How many times a second per-LED colors may be written, `None` for as often as they come
+    pub max_fps: Option<u32>,
+    pub read_only: bool,
+    pub direction: Option<Direction>,
+    pub mode_colors: Vec<ModeColor>,
+    /// Names of custom keycodes (macros, layer keys, etc.) taking precedence over built-in ones
+    pub keycode_labels: HashMap<u16, String>,
+    /// LEDs lighting rotary encoders, which have no place in the key matrix
+    pub encoders: Vec<u8>,
+    pub include_encoders: bool,
+}
+
+/// Per-channel color multipliers applied to an inclusive range of LED indices
+#[derive(Debug, Clone, Deserialize)]
+pub struct ColorCorrection {
+    pub leds: Range,
+    pub factors: (f32, f32, f32),
+}
+
+/// A named inclusive range of LED indices presented to clients as a zone of its own,
+/// or as a segment within one
+#[derive(Debug, Clone, Deserialize)]
+pub struct Zone {
+    pub name: String,
+    pub leds: Range,
+}
+
+/// Optional `default` and `step` of a `range` menu item in richer definitions
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RangeHints {
+    pub default: Option<u32>,
+    pub step: Option<u32>,
+}
+
+impl RangeHints {
+    /// Brings a value into the range, rounded to its nearest step, since the firmware may
+    /// misbehave on anything else. Ranges missing from the definition leave values as they are.
+    pub fn snap(&self, range: Range, value: u8) -> u8 {
+        if range == (0, 0) {
+            return value;
+        }
+        let value = (value as u32).clamp(range.0, range.1.max(range.0));
+        let Some(step) = self.step else {
+            return value.min(u8::MAX as u32) as u8;
+        };
+        let offset = value - range.0;
+        let snapped = range.0 + (offset + step / 2) / step * step;
+        snapped.min(range.1).min(u8::MAX as u32) as u8
+    }
+
+    pub fn default_value(&self) -> u8 {
+        self.default.unwrap_or(0).min(u8::MAX as u32) as u8
+    }
+}
+
+/// OpenRGB directions in the order of their protocol values
+const DIRECTIONS: [&str; 6] = ["Left", "Right", "Up", "Down", "Horizontal", "Vertical"];
+
+/// A custom direction dropdown (`id_qmk_rgb_matrix_direction`) for firmware that supports one
+#[derive(Debug, Clone)]
+pub struct Direction {
+    pub channel: u8,
+    pub value_id: u8,
+    /// Firmware values indexed by the OpenRGB direction
+    pub values: [Option<u8>; 6],
+}
+
+impl Direction {
+    fn parse(content: &[Value], options: &[IndexedOption]) -> Option<Self> {
+        if content.first().and_then(Value::as_str) != Some("id_qmk_rgb_matrix_direction") {
+            return None;
+        }
+
+        let mut values = [None; 6];
+        for (index, option) in options.iter().enumerate() {
+            let (name, value) = match option {
+                IndexedOption::Explicit((name, value)) => (name, *value),
+                IndexedOption::Implicit(name) => (name, index as i32),
+            };
+            if let Some(direction) = DIRECTIONS.iter().position(|x| x.eq_ignore_ascii_case(name)) {
+                values[direction] = u8::try_from(value).ok();
+            }
+        }
+
+        Some(Direction {
+            channel: content.get(1)?.as_u64()? as u8,
+            value_id: content.get(2)?.as_u64()? as u8,
+            values,
+        })
+    }
+
+    fn flags(&self) -> u32 {
+        [
+            MODE_FLAG_HAS_DIRECTION_LR,
+            MODE_FLAG_HAS_DIRECTION_UD,
+            MODE_FLAG_HAS_DIRECTION_HV,
+        ]
+        .into_iter()
+        .enumerate()
+        .filter(|(pair, _)| {
+            self.values[pair * 2..pair * 2 + 2]
+                .iter()
+                .any(Option::is_some)
+        })
+        .fold(0, |flags, (_, flag)| flags | flag)
+    }
+
+    pub fn firmware_value(&self, direction: u8) -> Option<u8> {
+        *self.values.get(direction as usize)?
+    }
+}
+
+/// An additional color (`id_qmk_rgb_matrix_color_<n>`) for effects taking several of them
+#[derive(Debug, Clone)]
+pub struct ModeColor {
+    pub channel: u8,
+    pub value_id: u8,
+    /// Ids of the effects showing the control
+    pub effects: Vec<i32>,
 }
 
 impl Config {
+    pub fn display_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Identifies the device to clients as `vvvv:pppp`, followed by `:<serial>` when it has one,
+    /// so identical keyboards can be told apart
+    pub fn hardware_id(&self) -> String {
+        let model = format!("{:04x}:{:04x}", self.vendor_id, self.product_id);
+        match &self.serial {
+            Some(serial) if !serial.is_empty() => format!("{model}:{serial}"),
+            _ => model,
+        }
+    }
+
     pub fn from_str(json: &str) -> Result<Self> {
+        Self::from_json(serde_json::from_str(json).map_err(|x| Error::Config(x.to_string()))?)
+    }
+
+    /// Parses a file with a single definition, an array of them or an object mapping ids to them
+    pub fn all_from_str(json: &str) -> Result<Vec<Self>> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|x| Error::Config(x.to_string()))?;
+
+        let definitions: Vec<(String, serde_json::Value)> = match value {
+            serde_json::Value::Array(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| (index.to_string(), item))
+                .collect(),
+            serde_json::Value::Object(map)
+                if !map.is_empty()
+                    && !map.contains_key("vendorId")
+                    && map.values().all(|x| x.is_object()) =>
+            {
+                map.into_iter().collect()
+            }
+            // Parsing the text again keeps line numbers in the errors
+            _ => return Ok(vec![Self::from_str(json)?]),
+        };
+
+        definitions
+            .into_iter()
+            .map(|(id, value)| {
+                Self::from_value(value)
+                    .map_err(|x| Error::Config(format!("Definition {id}: {x}")).into())
+            })
+            .collect()
+    }
+
+    /// Builds definitions out of the devices saved in an OpenRGB profile, which have to be
+    /// identified by a `vvvv:pppp` serial, like the ones ColorHoster reports
+    pub fn all_from_profile(data: &[u8]) -> Result<Vec<Self>> {
+        orp::parse(data)?
+            .iter()
+            .map(|saved| {
+                Self::from_saved(saved)
+                    .map_err(|x| Error::Config(format!("Device {}: {x}", saved.name)).into())
+            })
+            .collect()
+    }
+
+    pub fn from_saved(saved: &SavedController) -> Result<Self> {
+        let mut ids = saved.serial.split(':').map(|x| Id::Text(x.into()));
+        let (Some(vendor_id), Some(product_id)) = (ids.next(), ids.next()) else {
+            return Err(Error::Config(format!(
+                "Serial `{}` does not start with `vvvv:pppp` ids!",
+                saved.serial
+            ))
+            .into());
+        };
+
+        // Zones of the same matrix are laid over each other, as ColorHoster saves them,
+        // others are placed one under another with linear ones taking a row each
+        let first = saved.zones.first().and_then(|x| x.matrix.as_ref());
+        let overlay = first.is_some()
+            && saved
+                .zones
+                .iter()
+                .all(|zone| zone.matrix.as_ref().map(|x| (x.0, x.1)) == first.map(|x| (x.0, x.1)));
+        let (mut leds, mut zones) = (Vec::new(), Vec::new());
+        let (mut width, mut height, mut start) = (0u32, 0u32, 0u32);
+        for zone in &saved.zones {
+            let top = if overlay { 0 } else { height };
+            let positions: Vec<_> = match &zone.matrix {
+                Some((columns, rows, map)) => {
+                    width = width.max(*columns);
+                    height = if overlay {
+                        height.max(*rows)
+                    } else {
+                        height + rows
+                    };
+                    (map.iter().enumerate())
+                        .filter(|(_, led)| **led < zone.leds)
+                        .map(|(index, led)| {
+                            let (row, col) = (index as u32 / columns, index as u32 % columns);
+                            (start + led, top + row, col)
+                        })
+                        .collect()
+                }
+                None => {
+                    width = width.max(zone.leds);
+                    height += 1;
+                    (0..zone.leds).map(|led| (start + led, top, led)).collect()
+                }
+            };
+            for (led, row, col) in positions {
+                let (Ok(led), Ok(row), Ok(col)) =
+                    (u8::try_from(led), u8::try_from(row), u8::try_from(col))
+                else {
+                    return Err(Error::Config(format!(
+                        "LED {led} at {row},{col} cannot be addressed, the firmware supports at most {QMK_MAX_LEDS} LEDs!"
+                    ))
+                    .into());
+                };
+                leds.push((led, (row, col)));
+            }
+            if zone.leds > 0 {
+                zones.push(Zone {
+                    name: zone.name.clone(),
+                    leds: (start, start + zone.leds - 1),
+                });
+            }
+            start += zone.leds;
+        }
+        leds.sort();
+        leds.dedup_by_key(|x| x.0);
+        if leds.is_empty() {
+            return Err(Error::Config("Device has no LEDs!".into()).into());
+        }
+
+        let range = |flag: u32, range: fn(&SavedMode) -> Option<(u32, u32)>| {
+            (saved.modes.iter())
+                .filter(|x| x.flags & flag != 0)
+                .find_map(range)
+                .unwrap_or_default()
+        };
+
+        let mut config = Self {
+            name: saved.name.clone(),
+            vendor_id: parse_id("vendorId", &vendor_id)?,
+            product_id: parse_id("productId", &product_id)?,
+            serial: None,
+            display_name: None,
+            leds,
+            effects: (saved.modes.iter())
+                .map(|x| (x.name.clone(), x.value, x.flags))
+                .collect(),
+            synthetic_direct: false,
+            speed: range(MODE_FLAG_HAS_SPEED, |x| Some(x.speed_range)),
+            brightness: range(MODE_FLAG_HAS_BRIGHTNESS, |x| x.brightness_range),
+            speed_hints: RangeHints::default(),
+            brightness_hints: RangeHints::default(),
+            matrix: (width, height),
+            corrections: Vec::new(),
+            remap: Vec::new(),
+            // A single zone is what every keyboard gets anyway
+            zones: if zones.len() > 1 { zones } else { Vec::new() },
+            segments: Vec::new(),
+            direct_only: false,
+            no_per_led_brightness: false,
+            clear_custom_colors: false,
+            remember_effect_settings: false,
+            min_brightness: 0,
+            gamma: default_gamma(),
+            report_timeout: Duration::from_millis(DEFAULT_REPORT_TIMEOUT),
+            max_fps: None,
+            read_only: false,
+            direction: None,
+            mode_colors: Vec::new(),
+            keycode_labels: HashMap::new(),
+            encoders: Vec::new(),
+            include_encoders: false,
+        };
+        config.add_direct_mode();
+        Ok(config)
+    }
+
+    fn from_value(value: serde_json::Value) -> Result<Self> {
+        Self::from_json(serde_json::from_value(value).map_err(|x| Error::Config(x.to_string()))?)
+    }
+
+    fn from_json(json: KeyboardJson) -> Result<Self> {
         let KeyboardJson {
             name,
             vendor_id,
@@ -36,24 +365,197 @@ impl Config {
             matrix,
             menus,
             layouts,
-        } = serde_json::from_str(json)?;
+            color_correction,
+            led_remap,
+            mut zones,
+            mut segments,
+            direct_only,
+            no_per_led_brightness,
+            clear_custom_colors,
+            remember_effect_settings,
+            min_brightness,
+            gamma,
+            report_timeout,
+            max_fps,
+            keycode_labels,
+            include_encoders,
+        } = json;
 
         let menus = Self::flatten_menus(menus);
+        let speed = Self::find_range(&menus, "id_qmk_rgb_matrix_effect_speed");
+        let brightness = Self::find_range(&menus, "id_qmk_rgb_matrix_brightness");
+        if let Some(led) = Self::find_unaddressable_led(&layouts.keymap) {
+            return Err(Error::Config(format!(
+                "LED {led} of {name} cannot be addressed, the firmware supports at most {QMK_MAX_LEDS} LEDs!"
+            ))
+            .into());
+        }
+        let leds = Self::parse_leds(&name, &layouts.keymap);
+        let encoders = Self::parse_encoders(&layouts.keymap, &leds);
+        let mode_colors = Self::collect_mode_colors(&menus);
 
-        Ok(Self {
+        let mut config = Self {
             name,
-            vendor_id: parse_hex(&vendor_id),
-            product_id: parse_hex(&product_id),
+            vendor_id: parse_id("vendorId", &vendor_id)?,
+            product_id: parse_id("productId", &product_id)?,
+            serial: None,
+            display_name: None,
             matrix: (matrix.cols, matrix.rows),
-            leds: Self::parse_leds(&layouts.keymap),
-            speed: Self::find_range(&menus, "id_qmk_rgb_matrix_effect_speed"),
-            brightness: Self::find_range(&menus, "id_qmk_rgb_matrix_brightness"),
+            leds,
+            speed: speed.0,
+            brightness: brightness.0,
+            speed_hints: speed.1,
+            brightness_hints: brightness.1,
+            direction: menus.iter().find_map(|m| match m {
+                MenuOption::Dropdown {
+                    content, options, ..
+                } => Direction::parse(content, options),
+                _ => None,
+            }),
             effects: Self::parse_effects(menus),
-        })
+            synthetic_direct: false,
+            mode_colors: Vec::new(),
+            corrections: color_correction,
+            remap: led_remap,
+            zones: {
+                zones.sort_by_key(|x| x.leds.0);
+                zones
+            },
+            segments: {
+                segments.sort_by_key(|x| x.leds.0);
+                segments
+            },
+            direct_only,
+            no_per_led_brightness,
+            clear_custom_colors,
+            remember_effect_settings,
+            min_brightness,
+            gamma,
+            report_timeout: Duration::from_millis(report_timeout),
+            max_fps,
+            read_only: false,
+            keycode_labels: HashMap::new(),
+            encoders,
+            include_encoders,
+        };
+
+        config.mode_colors = mode_colors
+            .into_iter()
+            .map(|(control, channel, value_id)| ModeColor {
+                channel,
+                value_id,
+                effects: config
+                    .effects
+                    .iter()
+                    .map(|x| x.1)
+                    .filter(|&id| control.is_active(id))
+                    .collect(),
+            })
+            .collect();
+
+        for (keycode, label) in keycode_labels {
+            let parsed = match keycode.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => keycode.parse(),
+            };
+            let Ok(parsed) = parsed else {
+                return Err(Error::Config(format!(
+                    "`keycodeLabels` has an invalid keycode `{keycode}`!"
+                ))
+                .into());
+            };
+            config.keycode_labels.insert(parsed, label);
+        }
+
+        if config.leds.is_empty() {
+            return Err(Error::Config(format!(
+                "{} has no LEDs, its keymap must place them like `\"0,0\\nl0\"`!",
+                config.name
+            ))
+            .into());
+        }
+
+        // Clients index the matrix and the keymap read from the firmware by these positions
+        let (cols, rows) = config.matrix;
+        if let Some((led, (row, col))) = config
+            .leds
+            .iter()
+            .find(|(_, (row, col))| *row as u32 >= rows || *col as u32 >= cols)
+        {
+            return Err(Error::Config(format!(
+                "LED {led} of {} is placed at {row},{col}, outside of its {rows}x{cols} matrix!",
+                config.name
+            ))
+            .into());
+        }
+
+        if config.gamma <= 0.0 || !config.gamma.is_finite() {
+            return Err(Error::Config("`gamma` must be a positive number!".into()).into());
+        }
+
+        let leds = config.count_leds();
+        if !config.remap.is_empty() && config.remap.iter().copied().sorted().ne(0..leds) {
+            return Err(Error::Config(format!(
+                "`ledRemap` must list every LED index from 0 to {} exactly once!",
+                leds.saturating_sub(1)
+            ))
+            .into());
+        }
+
+        if let Some(zone) = config
+            .zones
+            .iter()
+            .find(|x| x.leds.0 > x.leds.1 || x.leds.1 >= leds)
+        {
+            return Err(Error::Config(format!(
+                "Zone `{}` must cover LEDs between 0 and {}!",
+                zone.name,
+                leds.saturating_sub(1)
+            ))
+            .into());
+        }
+        if let Some(zones) = config.zones.windows(2).find(|x| x[1].leds.0 <= x[0].leds.1) {
+            return Err(Error::Config(format!(
+                "Zones `{}` and `{}` overlap!",
+                zones[0].name, zones[1].name
+            ))
+            .into());
+        }
+
+        let layout = config.zone_layout();
+        if let Some(segment) = config.segments.iter().find(|x| {
+            let (start, end) = (x.leds.0 as usize, x.leds.1 as usize);
+            start > end
+                || !layout
+                    .iter()
+                    .any(|(_, zone)| zone.contains(&start) && zone.contains(&end))
+        }) {
+            return Err(Error::Config(format!(
+                "Segment `{}` must cover LEDs within a single zone!",
+                segment.name
+            ))
+            .into());
+        }
+        if let Some(segments) = config
+            .segments
+            .windows(2)
+            .find(|x| x[1].leds.0 <= x[0].leds.1)
+        {
+            return Err(Error::Config(format!(
+                "Segments `{}` and `{}` overlap!",
+                segments[0].name, segments[1].name
+            ))
+            .into());
+        }
+
+        config.add_direct_mode();
+        Ok(config)
     }
 
-    fn parse_leds(keymap: &[Vec<KeymapEntry>]) -> Vec<(u8, Position)> {
-        keymap
+    /// Layout variants may place the same LED at several positions, the first one in
+    /// row-major order is kept so the physical map does not depend on the definition order
+    fn parse_leds(name: &str, keymap: &[Vec<KeymapEntry>]) -> Vec<(u8, Position)> {
+        let leds: Vec<_> = keymap
             .iter()
             .flatten()
             .filter_map(|entry| {
@@ -65,6 +567,50 @@ impl Config {
             })
             .filter_map(extract_led)
             .sorted()
+            .dedup()
+            .collect();
+
+        leds.into_iter()
+            .chunk_by(|x| x.0)
+            .into_iter()
+            .map(|(led, positions)| {
+                let positions: Vec<_> = positions.map(|x| x.1).collect();
+                if positions.len() > 1 {
+                    warn!(
+                        "LED {led} of {name} is placed at {}, using {:?}",
+                        positions.iter().map(|x| format!("{x:?}")).join(", "),
+                        positions[0]
+                    );
+                }
+                (led, positions[0])
+            })
+            .collect()
+    }
+
+    /// Finds an LED index past what reports can address, which would otherwise be dropped
+    fn find_unaddressable_led(keymap: &[Vec<KeymapEntry>]) -> Option<u32> {
+        keymap
+            .iter()
+            .flatten()
+            .filter_map(|entry| match entry {
+                KeymapEntry::Key(key) => key.split('\n').nth(1)?.strip_prefix("l")?.parse().ok(),
+                _ => None,
+            })
+            .find(|&led: &u32| led >= QMK_MAX_LEDS)
+    }
+
+    /// LEDs of encoder keys, unless the same LED also lights a regular key
+    fn parse_encoders(keymap: &[Vec<KeymapEntry>], leds: &[(u8, Position)]) -> Vec<u8> {
+        keymap
+            .iter()
+            .flatten()
+            .filter_map(|entry| match entry {
+                KeymapEntry::Key(key) => extract_encoder_led(key),
+                _ => None,
+            })
+            .filter(|led| !leds.iter().any(|x| x.0 == *led))
+            .sorted()
+            .dedup()
             .collect()
     }
 
@@ -76,13 +622,23 @@ impl Config {
             .collect()
     }
 
-    fn find_range(menus: &[MenuOption], target: &str) -> Range {
+    fn find_range(menus: &[MenuOption], target: &str) -> (Range, RangeHints) {
         menus
             .iter()
             .find_map(|m| match m {
                 MenuOption::Range {
-                    content, options, ..
-                } if content.first().and_then(Value::as_str) == Some(target) => Some(*options),
+                    content,
+                    options,
+                    default,
+                    step,
+                    ..
+                } if content.first().and_then(Value::as_str) == Some(target) => Some((
+                    *options,
+                    RangeHints {
+                        default: default.map(|x| x.clamp(options.0, options.1)),
+                        step: step.filter(|&x| x > 1),
+                    },
+                )),
                 _ => None,
             })
             .unwrap_or_default()
@@ -94,9 +650,10 @@ impl Config {
         menus
             .into_iter()
             .find_map(|m| match m {
-                MenuOption::Dropdown { content, options }
-                    if content.first().and_then(Value::as_str)
-                        == Some("id_qmk_rgb_matrix_effect") =>
+                MenuOption::Dropdown {
+                    content, options, ..
+                } if content.first().and_then(Value::as_str)
+                    == Some("id_qmk_rgb_matrix_effect") =>
                 {
                     Some(options)
                 }
@@ -123,7 +680,12 @@ impl Config {
                     flags = flags | MODE_FLAG_HAS_RANDOM_COLOR;
                 }
 
-                if flags & (MODE_FLAG_HAS_SPEED | MODE_FLAG_HAS_MODE_SPECIFIC_COLOR) != 0 {
+                let saved = MODE_FLAG_HAS_SPEED
+                    | MODE_FLAG_HAS_MODE_SPECIFIC_COLOR
+                    | MODE_FLAG_HAS_DIRECTION_LR
+                    | MODE_FLAG_HAS_DIRECTION_UD
+                    | MODE_FLAG_HAS_DIRECTION_HV;
+                if flags & saved != 0 {
                     flags = flags | MODE_FLAG_MANUAL_SAVE;
                 }
 
@@ -150,7 +712,15 @@ impl Config {
                         }
                         _ => None,
                     }),
-                MenuOption::Color { content, show_if } if is_color_control(content) => {
+                MenuOption::Dropdown {
+                    content,
+                    options,
+                    show_if,
+                } => Direction::parse(content, options)
+                    .map(|direction| Control::new(show_if, direction.flags())),
+                MenuOption::Color { content, show_if }
+                    if is_color_control(content) || is_mode_color_control(content) =>
+                {
                     Some(Control::new(show_if, MODE_FLAG_HAS_MODE_SPECIFIC_COLOR))
                 }
                 MenuOption::ColorPalette { content, show_if } if is_color_control(content) => {
@@ -161,10 +731,214 @@ impl Config {
             .collect()
     }
 
+    fn collect_mode_colors(menus: &[MenuOption]) -> Vec<(Control, u8, u8)> {
+        menus
+            .iter()
+            .filter_map(|m| match m {
+                MenuOption::Color { content, show_if } if is_mode_color_control(content) => Some((
+                    Control::new(show_if, MODE_FLAG_HAS_MODE_SPECIFIC_COLOR),
+                    content.get(1)?.as_u64()? as u8,
+                    content.get(2)?.as_u64()? as u8,
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Indices into `mode_colors` of the colors an effect takes after the main one
+    pub fn effect_colors(&self, effect: i32) -> Vec<usize> {
+        self.mode_colors
+            .iter()
+            .positions(|x| x.effects.contains(&effect))
+            .collect()
+    }
+
+    pub fn correct_color(&self, led: usize, color: Rgb) -> Rgb {
+        self.corrections
+            .iter()
+            .filter(|x| (x.leds.0 as usize..=x.leds.1 as usize).contains(&led))
+            .fold(color, |color, x| {
+                Rgb::new(
+                    (color.red * x.factors.0).clamp(0., 1.),
+                    (color.green * x.factors.1).clamp(0., 1.),
+                    (color.blue * x.factors.2).clamp(0., 1.),
+                )
+            })
+    }
+
+    pub fn firmware_led(&self, led: usize) -> usize {
+        self.remap.get(led).map_or(led, |x| *x as usize)
+    }
+
+    /// Translates an update of logical LEDs into the smallest firmware range covering it
+    pub fn remap_colors(
+        &self,
+        colors: Vec<Option<Rgb>>,
+        offset: usize,
+    ) -> (Vec<Option<Rgb>>, usize) {
+        if self.remap.is_empty() {
+            return (colors, offset);
+        }
+
+        let targets: Vec<_> = colors
+            .into_iter()
+            .enumerate()
+            .map(|(index, color)| (self.firmware_led(offset + index), color))
+            .collect();
+
+        let Some((start, end)) = targets.iter().map(|x| x.0).minmax().into_option() else {
+            return (Vec::new(), offset);
+        };
+
+        let mut remapped = vec![None; end - start + 1];
+        for (led, color) in targets {
+            remapped[led - start] = color;
+        }
+
+        (remapped, start)
+    }
+
+    /// Reorders values stored in firmware order back into the logical order
+    pub fn unmap_colors<T: Copy>(&self, firmware: &[T]) -> Vec<T> {
+        (0..firmware.len())
+            .map(|led| firmware[self.firmware_led(led)])
+            .collect()
+    }
+
+    /// Whether per-LED brightness should be written, which some firmware handles poorly
+    pub fn writes_brightness(&self, with_brightness: bool) -> bool {
+        with_brightness && !self.no_per_led_brightness && self.has_brightness()
+    }
+
+    /// Whether the firmware has brightness at all, as boards with chroma-only channels never
+    /// answer brightness requests. Such boards have no brightness range in their definition.
+    pub fn has_brightness(&self) -> bool {
+        self.brightness != (0, 0)
+    }
+
+    /// Shortest time between two writes of per-LED colors, if they are limited
+    pub fn frame_interval(&self) -> Option<Duration> {
+        self.max_fps
+            .filter(|x| *x > 0)
+            .map(|x| Duration::from_secs(1) / x)
+    }
+
+    /// Finds an effect the definition lists, as the firmware value it is set with
+    pub fn find_effect(&self, id: i32) -> Option<u8> {
+        let effect = self.effects.iter().find(|x| x.1 == id)?;
+        u8::try_from(effect.1).ok()
+    }
+
+    /// Boards without effects are driven by nothing but per-LED colors, which clients only let
+    /// be painted in a mode flagged for them, so such a mode is made up
+    fn add_direct_mode(&mut self) {
+        let direct = (DIRECT_MODE.to_string(), 0, MODE_FLAG_HAS_PER_LED_COLOR);
+        // Profiles of these boards have it saved along with the real modes
+        if self.effects.is_empty() || self.effects == [direct.clone()] {
+            self.effects = vec![direct];
+            self.synthetic_direct = true;
+        }
+    }
+
+    /// The first effect driven by per-LED colors
+    pub fn custom_effect(&self) -> Option<u8> {
+        self.effects
+            .iter()
+            .find(|x| x.2 & MODE_FLAG_HAS_PER_LED_COLOR != 0)
+            .map(|x| x.1 as u8)
+    }
+
+    /// Whether switching between these effects leaves the per-LED custom mode with clearing enabled
+    pub fn clears_colors(&self, from: u8, to: u8) -> bool {
+        let is_custom = |effect: u8| {
+            self.effects
+                .iter()
+                .any(|x| x.1 == effect as i32 && x.2 & MODE_FLAG_HAS_PER_LED_COLOR != 0)
+        };
+        self.clear_custom_colors && is_custom(from) && !is_custom(to)
+    }
+
+    /// Zones as presented to clients in LED order. LEDs outside of the declared zones
+    /// are put into zones of their own, since OpenRGB expects every LED to be in one.
+    pub fn zone_layout(&self) -> Vec<(&str, std::ops::Range<usize>)> {
+        let count = self.count_leds() as usize;
+        if self.zones.is_empty() {
+            return vec![("Keyboard", 0..count)];
+        }
+
+        let mut layout = Vec::new();
+        let mut cursor = 0;
+        for zone in &self.zones {
+            let (start, end) = (zone.leds.0 as usize, zone.leds.1 as usize + 1);
+            if start > cursor {
+                layout.push(("Other", cursor..start));
+            }
+            layout.push((zone.name.as_str(), start..end));
+            cursor = end;
+        }
+        if cursor < count {
+            layout.push(("Other", cursor..count));
+        }
+        layout
+    }
+
+    /// Segments of a zone from `zone_layout`, with LED ranges relative to the zone's start
+    pub fn zone_segments(
+        &self,
+        zone: &std::ops::Range<usize>,
+    ) -> Vec<(&str, std::ops::Range<usize>)> {
+        self.segments
+            .iter()
+            .map(|x| (x.name.as_str(), x.leds.0 as usize..x.leds.1 as usize + 1))
+            .filter(|(_, leds)| zone.contains(&leds.start))
+            .map(|(name, leds)| (name, leds.start - zone.start..leds.end - zone.start))
+            .collect()
+    }
+
+    /// Resolves the OpenRGB name of a keycode, preferring the labels of the definition
+    pub fn key_label(&self, keycode: u16) -> &str {
+        match self.keycode_labels.get(&keycode) {
+            Some(label) => label,
+            None => openrgb_keycode(keycode),
+        }
+    }
+
+    /// Problems which leave the keyboard usable, but likely not the way its definition intended
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.synthetic_direct {
+            warnings.push(format!(
+                "{} has no `id_qmk_rgb_matrix_effect` dropdown, so it only supports direct mode",
+                self.name
+            ));
+        }
+        if self.brightness == (0, 0) {
+            warnings.push(format!(
+                "{} has no `id_qmk_rgb_matrix_brightness` range, so its brightness cannot be changed",
+                self.name
+            ));
+        }
+        warnings
+    }
+
+    /// Encoder LEDs presented to clients, none unless they are included
+    pub fn encoder_leds(&self) -> &[u8] {
+        if self.include_encoders {
+            &self.encoders
+        } else {
+            &[]
+        }
+    }
+
     pub fn count_leds(&self) -> u32 {
-        let index = self.leds.iter().max();
+        let index = self
+            .leds
+            .iter()
+            .map(|x| x.0)
+            .chain(self.encoder_leds().iter().copied())
+            .max();
         if let Some(index) = index {
-            return index.0 as u32 + 1;
+            return index as u32 + 1;
         } else {
             return 0;
         }
@@ -203,11 +977,56 @@ fn is_color_control(content: &[Value]) -> bool {
     content.first().and_then(Value::as_str) == Some("id_qmk_rgb_matrix_color")
 }
 
-fn parse_hex(s: &str) -> u16 {
-    u16::from_str_radix(s.trim_start_matches("0x"), 16).unwrap_or(0)
+fn is_mode_color_control(content: &[Value]) -> bool {
+    content
+        .first()
+        .and_then(Value::as_str)
+        .is_some_and(|x| x.starts_with("id_qmk_rgb_matrix_color_"))
+}
+
+/// Strings are read as hex with an optional `0x` prefix, like VIA does,
+/// while plain JSON numbers are taken as decimal
+fn parse_id(field: &str, id: &Id) -> Result<u16> {
+    let parsed = match id {
+        Id::Number(value) => u16::try_from(*value).ok(),
+        Id::Text(text) => {
+            let text = text.trim();
+            let hex = text
+                .strip_prefix("0x")
+                .or_else(|| text.strip_prefix("0X"))
+                .unwrap_or(text);
+            u16::from_str_radix(hex, 16).ok()
+        }
+    };
+    parsed.ok_or_else(|| {
+        let value = match id {
+            Id::Number(value) => value.to_string(),
+            Id::Text(text) => text.clone(),
+        };
+        Error::Config(format!(
+            "`{field}` must be a hex string like `\"0x362D\"` or a decimal number, not `{value}`!"
+        ))
+        .into()
+    })
 }
 
 fn extract_led(key: &String) -> Option<(u8, Position)> {
+    match parse_key(key)? {
+        (led, position, false) => Some((led, position)),
+        // Encoder keys are positioned by the encoder index and direction, not in the matrix
+        (_, _, true) => None,
+    }
+}
+
+fn extract_encoder_led(key: &String) -> Option<u8> {
+    match parse_key(key)? {
+        (led, _, true) => Some(led),
+        (_, _, false) => None,
+    }
+}
+
+/// Reads the position and LED of a key, along with whether it belongs to an encoder
+fn parse_key(key: &str) -> Option<(u8, Position, bool)> {
     let mut flags = key.split('\n');
 
     let position: Vec<_> = flags.nth(0)?.split(',').collect();
@@ -217,30 +1036,66 @@ fn extract_led(key: &String) -> Option<(u8, Position)> {
     let led = flags
         .nth(0)
         .and_then(|x| x.strip_prefix("l"))
-        .and_then(|x| x.parse::<u8>().ok())
-        .and_then(|x| {
-            // Skip LEDs for encoder keys
-            if let Some(encoder) = flags.nth(7)
-                && encoder.starts_with("e")
-            {
-                return None;
-            }
-            Some(x)
-        })?;
+        .and_then(|x| x.parse::<u8>().ok())?;
+    let encoder = flags.nth(7).is_some_and(|x| x.starts_with("e"));
 
-    Some((led, (row, col)))
+    Some((led, (row, col), encoder))
 }
 
 #[derive(Debug, Deserialize)]
 struct KeyboardJson {
     name: String,
     #[serde(rename = "vendorId")]
-    vendor_id: String,
+    vendor_id: Id,
     #[serde(rename = "productId")]
-    product_id: String,
+    product_id: Id,
     matrix: MatrixDimensions,
     menus: Vec<Menu>,
     layouts: Layouts,
+    #[serde(default, rename = "colorCorrection")]
+    color_correction: Vec<ColorCorrection>,
+    #[serde(default, rename = "ledRemap")]
+    led_remap: Vec<u32>,
+    #[serde(default)]
+    zones: Vec<Zone>,
+    #[serde(default)]
+    segments: Vec<Zone>,
+    #[serde(default, rename = "directOnly")]
+    direct_only: bool,
+    #[serde(default, rename = "noPerLedBrightness")]
+    no_per_led_brightness: bool,
+    #[serde(default, rename = "clearCustomColors")]
+    clear_custom_colors: bool,
+    #[serde(default, rename = "rememberEffectSettings")]
+    remember_effect_settings: bool,
+    #[serde(default, rename = "minBrightness")]
+    min_brightness: u8,
+    #[serde(default = "default_gamma")]
+    gamma: f32,
+    #[serde(default = "default_report_timeout", rename = "reportTimeout")]
+    report_timeout: u64,
+    #[serde(default, rename = "maxFps")]
+    max_fps: Option<u32>,
+    /// Keycodes as decimal or `0x` prefixed hex strings
+    #[serde(default, rename = "keycodeLabels")]
+    keycode_labels: HashMap<String, String>,
+    #[serde(default, rename = "includeEncoders")]
+    include_encoders: bool,
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+fn default_report_timeout() -> u64 {
+    DEFAULT_REPORT_TIMEOUT
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Number(u64),
+    Text(String),
 }
 
 #[derive(Debug, Deserialize)]
@@ -266,6 +1121,8 @@ enum MenuOption {
     Range {
         content: Vec<Value>,
         options: Range,
+        default: Option<u32>,
+        step: Option<u32>,
         #[serde(rename = "showIf")]
         show_if: Option<String>,
     },
@@ -273,6 +1130,8 @@ enum MenuOption {
     Dropdown {
         content: Vec<Value>,
         options: Vec<IndexedOption>,
+        #[serde(rename = "showIf")]
+        show_if: Option<String>,
     },
     #[serde(rename = "color")]
     Color {
@@ -310,3 +1169,379 @@ enum KeymapEntry {
     #[allow(dead_code)]
     Other(Value),
 }
+
+#[cfg(test)]
+impl Config {
+    /// The JSON of a definition in `tests/fixtures`, for tests deriving variants of it
+    pub fn fixture_json(name: &str) -> Value {
+        let json = match name {
+            "keyboard" => include_str!("../tests/fixtures/keyboard.json"),
+            "gradient" => include_str!("../tests/fixtures/gradient.json"),
+            "combined" => include_str!("../tests/fixtures/combined.json"),
+            _ => panic!("Unknown fixture {name}!"),
+        };
+        serde_json::from_str(json).expect("Failed to parse fixture!")
+    }
+
+    /// Parses a definition in `tests/fixtures`
+    pub fn fixture(name: &str) -> Self {
+        Self::from_str(&Self::fixture_json(name).to_string()).expect("Failed to parse fixture!")
+    }
+
+    /// The JSON of the test keyboard split into three zones, listed out of the LED order
+    pub fn zoned_fixture() -> Value {
+        let mut json = Self::fixture_json("keyboard");
+        json["zones"] = serde_json::json!([
+            { "name": "Right", "leds": [4, 5] },
+            { "name": "Left", "leds": [0, 1] },
+            { "name": "Middle", "leds": [2, 3] },
+        ]);
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn remapped() -> Value {
+        let mut json = Config::fixture_json("keyboard");
+        json["ledRemap"] = json!([5, 4, 3, 0, 1, 2]);
+        json
+    }
+
+    fn parse(json: &Value) -> Config {
+        Config::from_str(&json.to_string()).expect("Failed to parse definition!")
+    }
+
+    #[test]
+    fn reads_combined_definitions() {
+        let configs = Config::all_from_str(&Config::fixture_json("combined").to_string())
+            .expect("Failed to parse fixture!");
+        let names: Vec<_> = configs.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["Test Keyboard", "Test Numpad"]);
+        assert_eq!(configs[1].product_id, 2);
+
+        let single = Config::fixture_json("keyboard");
+        assert_eq!(Config::all_from_str(&single.to_string()).unwrap().len(), 1);
+
+        let keyed = format!(r#"{{ "main": {single}, "spare": {single} }}"#);
+        assert_eq!(Config::all_from_str(&keyed).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn keeps_the_first_position_of_conflicting_leds() {
+        let mut json = Config::fixture_json("keyboard");
+        // A layout variant places LED 5 under another key as well
+        json["layouts"]["keymap"][0]
+            .as_array_mut()
+            .unwrap()
+            .push("0,1\nl5".into());
+
+        let config = Config::from_str(&json.to_string()).unwrap();
+        assert_eq!(config.count_leds(), 6);
+        assert_eq!(config.leds.len(), 6);
+        assert_eq!(config.leds[4], (4, (1, 1)));
+        assert_eq!(config.leds[5], (5, (0, 1)));
+    }
+
+    #[test]
+    fn includes_encoder_leds_on_request() {
+        let mut json = Config::fixture_json("keyboard");
+        let row = json["layouts"]["keymap"][1].as_array_mut().unwrap();
+        row.push("0,0\nl6\n\n\n\n\n\n\n\ne".into());
+        row.push("0,1\nl6\n\n\n\n\n\n\n\ne".into());
+
+        let mut config = Config::from_str(&json.to_string()).unwrap();
+        assert_eq!(config.encoders, vec![6]);
+        assert_eq!(config.count_leds(), 6);
+        assert_eq!(config.leds.len(), 6);
+
+        config.include_encoders = true;
+        assert_eq!(config.encoder_leds(), [6]);
+        assert_eq!(config.count_leds(), 7);
+    }
+
+    #[test]
+    fn rejects_definitions_that_do_nothing() {
+        let mut json = Config::fixture_json("keyboard");
+        assert!(
+            Config::from_str(&json.to_string())
+                .unwrap()
+                .warnings()
+                .is_empty()
+        );
+
+        json["menus"] = serde_json::json!([]);
+        let config = Config::from_str(&json.to_string()).unwrap();
+        assert_eq!(config.warnings().len(), 2);
+        // Clients still get a mode to paint the LEDs in
+        let direct = (DIRECT_MODE.to_string(), 0, MODE_FLAG_HAS_PER_LED_COLOR);
+        assert_eq!(config.effects, [direct]);
+        assert_eq!(config.custom_effect(), Some(0));
+
+        json["layouts"]["keymap"] = serde_json::json!([["0,0", "0,1"]]);
+        let error = Config::from_str(&json.to_string()).unwrap_err();
+        assert!(error.to_string().contains("has no LEDs"));
+
+        json["vendorId"] = "0xZZZZ".into();
+        let error = Config::from_str(&json.to_string()).unwrap_err();
+        assert!(error.to_string().contains("`vendorId`"));
+    }
+
+    #[test]
+    fn parses_hex_and_decimal_ids() {
+        let id = |json: &str| parse_id("vendorId", &serde_json::from_str(json).unwrap()).ok();
+        assert_eq!(id("\"0x5241\""), Some(0x5241));
+        assert_eq!(id("\"5241\""), Some(0x5241));
+        assert_eq!(id("\"0X362d\""), Some(0x362D));
+        assert_eq!(id("21057"), Some(0x5241));
+        assert_eq!(id("70000"), None);
+        assert_eq!(id("\"0x12345\""), None);
+        assert_eq!(id("\"Keychron\""), None);
+    }
+
+    #[test]
+    fn rejects_unaddressable_leds() {
+        let mut json = Config::fixture_json("keyboard");
+        json["layouts"]["keymap"][1] = serde_json::json!(["1,0\nl255"]);
+        assert_eq!(
+            Config::from_str(&json.to_string()).unwrap().count_leds(),
+            256
+        );
+
+        json["layouts"]["keymap"][1] = serde_json::json!(["1,0\nl256"]);
+        let error = Config::from_str(&json.to_string()).unwrap_err();
+        assert!(error.to_string().contains("LED 256"));
+    }
+
+    #[test]
+    fn rejects_leds_outside_the_matrix() {
+        let mut json = Config::fixture_json("keyboard");
+        json["layouts"]["keymap"][1][2] = "1,3\nl5".into();
+        let error = Config::from_str(&json.to_string()).unwrap_err();
+        assert!(error.to_string().contains("LED 5"));
+        assert!(error.to_string().contains("1,3"));
+
+        json["layouts"]["keymap"][1][2] = "2,2\nl5".into();
+        assert!(Config::from_str(&json.to_string()).is_err());
+        json["matrix"]["rows"] = 3.into();
+        assert!(Config::from_str(&json.to_string()).is_ok());
+    }
+
+    #[test]
+    fn extracts_leds_from_keys() {
+        assert_eq!(extract_led(&"1,2\nl5".to_string()), Some((5, (1, 2))));
+        assert_eq!(
+            extract_led(&"3, 4\nl12\n\n".to_string()),
+            Some((12, (3, 4)))
+        );
+        assert_eq!(extract_led(&"1,2".to_string()), None);
+        assert_eq!(extract_led(&"1,2\n\n".to_string()), None);
+        assert_eq!(extract_led(&"0,3\nl7\n\n\n\n\n\n\n\ne0".to_string()), None);
+        assert_eq!(
+            extract_led(&"0,3\nl7\n\n\n\n\n\n\n\nx".to_string()),
+            Some((7, (0, 3)))
+        );
+    }
+
+    #[test]
+    fn corrects_led_groups_independently() {
+        let mut json = Config::fixture_json("keyboard");
+        json["colorCorrection"] = json!([
+            { "leds": [0, 2], "factors": [1.0, 0.5, 0.5] },
+            { "leds": [3, 5], "factors": [0.5, 0.5, 1.0] },
+        ]);
+        let config = parse(&json);
+        let white = Rgb::new(1., 1., 1.);
+
+        assert_eq!(config.correct_color(0, white), Rgb::new(1., 0.5, 0.5));
+        assert_eq!(config.correct_color(2, white), Rgb::new(1., 0.5, 0.5));
+        assert_eq!(config.correct_color(3, white), Rgb::new(0.5, 0.5, 1.));
+        assert_eq!(config.correct_color(5, white), Rgb::new(0.5, 0.5, 1.));
+    }
+
+    #[test]
+    fn remaps_gradient_to_physical_order() {
+        let config = parse(&remapped());
+        let gradient: Vec<_> = (0..6)
+            .map(|x| Some(Rgb::new(x as f32 / 5., 0., 0.)))
+            .collect();
+
+        let (firmware, offset) = config.remap_colors(gradient.clone(), 0);
+        assert_eq!(offset, 0);
+        for (led, color) in gradient.iter().enumerate() {
+            assert_eq!(firmware[config.remap[led] as usize], *color);
+        }
+        assert_eq!(config.unmap_colors(&firmware), gradient);
+
+        let (firmware, offset) = config.remap_colors(vec![gradient[2], gradient[3]], 2);
+        assert_eq!(offset, 0);
+        assert_eq!(firmware, vec![gradient[3], None, None, gradient[2]]);
+    }
+
+    #[test]
+    fn rejects_incomplete_remap() {
+        let mut json = remapped();
+        json["ledRemap"] = serde_json::json!([0, 1, 2]);
+        assert!(Config::from_str(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn parses_range_defaults_and_steps() {
+        let mut json = Config::fixture_json("keyboard");
+        let controls = &mut json["menus"][0]["content"][0]["content"];
+        controls[0]["default"] = 200.into();
+        controls[2]["default"] = 128.into();
+        controls[2]["step"] = 16.into();
+        let config = parse(&json);
+        assert_eq!(config.speed, (0, 255));
+        assert_eq!(config.speed_hints.default, Some(128));
+        assert_eq!(config.speed_hints.step, Some(16));
+        assert_eq!(config.brightness_hints.default_value(), 200);
+        assert_eq!(config.brightness_hints.step, None);
+
+        assert_eq!(config.speed_hints.snap(config.speed, 30), 32);
+        assert_eq!(config.speed_hints.snap(config.speed, 255), 255);
+        assert_eq!(config.brightness_hints.snap(config.brightness, 30), 30);
+
+        let plain = Config::fixture("keyboard");
+        assert_eq!(plain.speed_hints, RangeHints::default());
+    }
+
+    #[test]
+    fn finds_only_listed_effects() {
+        let config = Config::fixture("keyboard");
+        assert_eq!(config.find_effect(3), Some(3));
+        assert_eq!(config.find_effect(4), None);
+        // Would wrap around to `None` once cast to a byte
+        assert_eq!(config.find_effect(256), None);
+    }
+
+    #[test]
+    fn clamps_values_to_the_range() {
+        let hints = RangeHints::default();
+        assert_eq!(hints.snap((0, 4), 200), 4);
+        assert_eq!(hints.snap((0, 4), 3), 3);
+        assert_eq!(hints.snap((10, 50), 0), 10);
+        // Definitions without the range accept anything
+        assert_eq!(hints.snap((0, 0), 200), 200);
+
+        let stepped = RangeHints {
+            default: None,
+            step: Some(2),
+        };
+        assert_eq!(stepped.snap((1, 5), 200), 5);
+    }
+
+    #[test]
+    fn lays_out_virtual_zones_in_led_order() {
+        let mut config = parse(&Config::zoned_fixture());
+        let layout = config.zone_layout();
+        assert_eq!(
+            layout,
+            vec![("Left", 0..2), ("Middle", 2..4), ("Right", 4..6)]
+        );
+
+        config.zones.remove(1);
+        let names: Vec<_> = config.zone_layout().into_iter().map(|x| x.0).collect();
+        assert_eq!(names, ["Left", "Other", "Right"]);
+    }
+
+    #[test]
+    fn rejects_invalid_zones() {
+        let mut json = Config::zoned_fixture();
+        json["zones"][0]["leds"] = serde_json::json!([4, 6]);
+        assert!(Config::from_str(&json.to_string()).is_err());
+
+        json["zones"][0]["leds"] = serde_json::json!([3, 5]);
+        assert!(Config::from_str(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn labels_custom_keycodes() {
+        let mut json = Config::fixture_json("keyboard");
+        json["keycodeLabels"] = serde_json::json!({ "0x7E00": "Macro 1", "4": "Alpha" });
+        let config = Config::from_str(&json.to_string()).unwrap();
+        assert_eq!(config.key_label(0x7E00), "Macro 1");
+        assert_eq!(config.key_label(4), "Alpha");
+        assert_eq!(config.key_label(5), "B");
+
+        json["keycodeLabels"] = serde_json::json!({ "KC_A": "Alpha" });
+        assert!(Config::from_str(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn places_segments_within_their_zone() {
+        let mut json = Config::zoned_fixture();
+        json["segments"] = serde_json::json!([{ "name": "Tip", "leds": [5, 5] }]);
+        let config = Config::from_str(&json.to_string()).unwrap();
+        let layout = config.zone_layout();
+        assert!(config.zone_segments(&layout[0].1).is_empty());
+        assert_eq!(config.zone_segments(&layout[2].1), vec![("Tip", 1..2)]);
+
+        json["segments"][0]["leds"] = serde_json::json!([3, 4]);
+        assert!(Config::from_str(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn counts_the_colors_of_each_effect() {
+        let config = Config::fixture("gradient");
+        assert_eq!(config.mode_colors.len(), 2);
+        assert_eq!(
+            (
+                config.mode_colors[1].channel,
+                config.mode_colors[1].value_id
+            ),
+            (3, 6)
+        );
+
+        assert!(config.effect_colors(1).is_empty());
+        assert_eq!(config.effect_colors(2), [0]);
+        assert_eq!(config.effect_colors(3), [0, 1]);
+        let flags = config.effects[3].2;
+        assert_ne!(flags & MODE_FLAG_HAS_MODE_SPECIFIC_COLOR, 0);
+        assert_eq!(flags & MODE_FLAG_HAS_RANDOM_COLOR, 0);
+    }
+
+    #[test]
+    fn advertises_direction_where_active() {
+        let mut json = Config::fixture_json("keyboard");
+        let controls = json["menus"][0]["content"][0]["content"]
+            .as_array_mut()
+            .unwrap();
+        controls.push(json!({
+            "showIf": "{id_qmk_rgb_matrix_effect} == 2",
+            "label": "Direction",
+            "type": "dropdown",
+            "content": ["id_qmk_rgb_matrix_direction", 3, 5],
+            "options": [["Left", 0], ["Right", 1], ["Up", 2], ["Down", 3]],
+        }));
+        let config = parse(&json);
+        let flags = |id| config.effects.iter().find(|x| x.1 == id).unwrap().2;
+        let direction = MODE_FLAG_HAS_DIRECTION_LR | MODE_FLAG_HAS_DIRECTION_UD;
+
+        assert_eq!(flags(2) & direction, direction);
+        assert_eq!(flags(2) & MODE_FLAG_HAS_DIRECTION_HV, 0);
+        assert_ne!(flags(2) & MODE_FLAG_MANUAL_SAVE, 0);
+        assert_eq!(flags(1) & direction, 0);
+
+        let control = config.direction.expect("Direction control is missing!");
+        assert_eq!((control.channel, control.value_id), (3, 5));
+        assert_eq!(control.firmware_value(3), Some(3));
+        assert_eq!(control.firmware_value(4), None);
+    }
+
+    #[test]
+    fn defaults_to_identity_correction() {
+        let config = Config::fixture("keyboard");
+        let color = Rgb::new(0.2, 0.4, 0.6);
+
+        assert!(config.corrections.is_empty());
+        assert_eq!(config.correct_color(0, color), color);
+        assert_eq!(config.firmware_led(4), 4);
+        assert!(!config.direct_only);
+        assert!(config.writes_brightness(true));
+    }
+}
@@ -8,6 +8,7 @@ pub enum Request {
     GetProtocolVersion = 40,
     SetClientName = 50,
     DeviceListUpdated = 100,
+    RescanDevices = 140,
     GetProfileList = 150,
     SaveProfile = 151,
     LoadProfile = 152,
@@ -19,9 +20,14 @@ pub enum Request {
     SetCustomMode = 1100,
     UpdateMode = 1101,
     SaveMode = 1102,
+    // ColorHoster extensions
+    UpdateLedsPrecise = 2050,
+    GetServerInfo = 2051,
+    Authenticate = 2052,
+    SetBrightnessWrites = 2053,
 }
 
-pub const OPENRGB_PROTOCOL_VERSION: u32 = 0x3;
+pub const OPENRGB_PROTOCOL_VERSION: u32 = 0x4;
 
 pub const QMK_USAGE_PAGE: u16 = 0xFF60;
 pub const QMK_USAGE_ID: u16 = 0x61;
@@ -31,9 +37,18 @@ pub const QMK_CUSTOM_GET_COMMAND: u8 = 0x08;
 pub const QMK_CUSTOM_SAVE_COMMAND: u8 = 0x09;
 pub const QMK_KEYMAP_GET_COMMAND: u8 = 0x12;
 
+/// Milliseconds to wait for the keyboard to answer a request
+pub const DEFAULT_REPORT_TIMEOUT: u64 = 1000;
+/// Requests awaiting an answer from the keyboards at once, as more make some devices drop them
+pub const DEFAULT_MAX_REQUESTS: usize = 8;
+pub const QMK_REQUEST_ATTEMPTS: usize = 3;
+pub const QMK_WRITE_ATTEMPTS: usize = 3;
+
 pub const QMK_CUSTOM_CHANNEL: u8 = 0x0;
 pub const QMK_COMMAND_MATRIX_CHROMA: u8 = 0x1;
 pub const QMK_COMMAND_MATRIX_BRIGHTNESS: u8 = 0x2;
+/// Matrix reports address LEDs with single byte offsets
+pub const QMK_MAX_LEDS: u32 = 256;
 
 pub const QMK_RGB_MATRIX_CHANNEL: u8 = 0x3;
 pub const QMK_COMMAND_BRIGHTNESS: u8 = 0x1;
@@ -44,15 +59,27 @@ pub const QMK_COMMAND_COLOR: u8 = 0x4;
 pub const DEVICE_TYPE_KEYBOARD: i32 = 5;
 
 pub const MODE_FLAG_HAS_SPEED: u32 = 1 << 0;
+pub const MODE_FLAG_HAS_DIRECTION_LR: u32 = 1 << 1;
+pub const MODE_FLAG_HAS_DIRECTION_UD: u32 = 1 << 2;
+pub const MODE_FLAG_HAS_DIRECTION_HV: u32 = 1 << 3;
 pub const MODE_FLAG_HAS_BRIGHTNESS: u32 = 1 << 4;
 pub const MODE_FLAG_HAS_PER_LED_COLOR: u32 = 1 << 5;
 pub const MODE_FLAG_HAS_MODE_SPECIFIC_COLOR: u32 = 1 << 6;
 pub const MODE_FLAG_HAS_RANDOM_COLOR: u32 = 1 << 7;
 pub const MODE_FLAG_MANUAL_SAVE: u32 = 1 << 8;
+/// Name of the mode clients look for to drive the LEDs frame by frame
+pub const DIRECT_MODE: &str = "Direct";
 
+pub const ZONE_TYPE_LINEAR: i32 = 1;
 pub const ZONE_TYPE_MATRIX: i32 = 2;
 
-pub const OPENRGB_SDK_DEFAULT_PORT: u32 = 6742;
+pub const OPENRGB_SDK_DEFAULT_PORT: u16 = 6742;
+pub const DEFAULT_MAX_REQUEST_LENGTH: u32 = 1 << 20;
+pub const DEFAULT_MAX_CLIENTS: usize = 32;
+
+/// Megabytes a log file may grow to before it is rotated
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10;
+pub const DEFAULT_LOG_FILES: usize = 3;
 
 pub fn openrgb_keycode(keycode: u16) -> &'static str {
     match keycode {
@@ -155,10 +182,31 @@ pub fn openrgb_keycode(keycode: u16) -> &'static str {
         99 => "Number Pad .",
         100 => "\\ (ISO)",
         101 => "Menu",
+        103 => "Number Pad =",
         104 => "F13",
         105 => "F14",
         106 => "F15",
         107 => "F16",
+        108 => "F17",
+        109 => "F18",
+        110 => "F19",
+        111 => "F20",
+        112 => "F21",
+        113 => "F22",
+        114 => "F23",
+        115 => "F24",
+        127 => "Media Mute",
+        128 => "Media Volume +",
+        129 => "Media Volume -",
+        133 => "Number Pad ,",
+        // International keys of JIS and Korean layouts
+        135 => "_",
+        136 => "かな",
+        137 => "¥",
+        138 => "変換",
+        139 => "無変換",
+        144 => "한/영",
+        145 => "한자",
         168 => "Media Mute",
         169 => "Media Volume +",
         170 => "Media Volume -",
@@ -192,3 +240,25 @@ pub fn openrgb_keycode(keycode: u16) -> &'static str {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_keys_of_every_layout() {
+        assert_eq!(openrgb_keycode(4), "A");
+        assert_eq!(openrgb_keycode(49), "\\ (ANSI)");
+        assert_eq!(openrgb_keycode(100), "\\ (ISO)");
+        assert_eq!(openrgb_keycode(101), "Menu");
+        assert_eq!(openrgb_keycode(115), "F24");
+        assert_eq!(openrgb_keycode(135), "_");
+        assert_eq!(openrgb_keycode(137), "¥");
+        assert_eq!(openrgb_keycode(139), "無変換");
+        assert_eq!(openrgb_keycode(225), "Left Shift");
+        assert_eq!(openrgb_keycode(229), "Right Shift");
+        // Layer keys are reported as Fn keys
+        assert_eq!(openrgb_keycode(0x5221), "Right Fn");
+        assert_eq!(openrgb_keycode(0), "Unknown");
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::Result;
+use colored::Colorize;
+use indexmap::IndexMap;
+use itertools::Itertools;
+use log::{debug, error, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{cli::CLI, config::Config, error::Error, keyboards::Keyboards, utils::collect_changes};
+
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Reads the keyboard definitions from the config directory and the extra `.json` paths.
+/// Invalid files are reported and skipped, so they do not take the other keyboards down.
+pub fn read(args: &CLI) -> Result<IndexMap<(u16, u16), Config>> {
+    let paths = directory(args)
+        .read_dir()?
+        .filter_map(|path| {
+            let path = path.as_ref().ok()?.path();
+            if is_definition(&path) {
+                Some(path)
+            } else {
+                None
+            }
+        })
+        // Definitions set the order of devices, so it must not depend on the file system
+        .sorted()
+        .chain(args.json.iter().cloned());
+
+    let mut contents = HashSet::new();
+    let mut configs = IndexMap::new();
+    for path in paths {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(error) => {
+                error!("Failed to read {path:?}: {error}");
+                continue;
+            }
+        };
+        if !contents.insert(data.clone()) {
+            continue;
+        }
+        match parse(&path, &data) {
+            Ok(parsed) => configs.extend(
+                parsed
+                    .into_iter()
+                    .map(|config| apply_overrides(config, args))
+                    .map(|config| ((config.vendor_id, config.product_id), config)),
+            ),
+            Err(error) => error!("Skipped {path:?}: {error}"),
+        }
+    }
+    Ok(configs)
+}
+
+/// Parses the definitions of a file, warning about the ones that are likely to be mistaken.
+/// Besides VIA `.json` files, devices of OpenRGB `.orp` profiles are understood as well.
+fn parse(path: &Path, data: &[u8]) -> Result<Vec<Config>> {
+    let configs = if is_profile(path) {
+        Config::all_from_profile(data)?
+    } else {
+        let json = std::str::from_utf8(data).map_err(|x| Error::Config(x.to_string()))?;
+        Config::all_from_str(json)?
+    };
+    for warning in configs.iter().flat_map(Config::warnings) {
+        warn!("{warning} ({path:?})");
+    }
+    Ok(configs)
+}
+
+pub fn apply_overrides(mut config: Config, args: &CLI) -> Config {
+    config.direct_only |= args.direct_only;
+    config.clear_custom_colors |= args.clear_custom_colors;
+    config.remember_effect_settings |= args.remember_effect_settings;
+    config.min_brightness = config.min_brightness.max(args.min_brightness);
+    config.read_only |= args.read_only;
+    config.include_encoders |= args.include_encoders;
+    if let Some(timeout) = args.report_timeout {
+        config.report_timeout = Duration::from_millis(timeout);
+    }
+    if let Some(fps) = args.max_fps {
+        config.max_fps = Some(fps);
+    }
+    if let Some(gamma) = args.gamma {
+        config.gamma = gamma;
+    }
+    config
+}
+
+/// Reloads definitions once their files change, so edits apply without restarting the server
+pub fn watch(args: CLI, keyboards: Keyboards, interrupt: CancellationToken) -> Result<()> {
+    let directory = fs::canonicalize(directory(&args))?;
+    // Editors tend to replace files instead of writing to them, so their folders are watched
+    let files: HashSet<_> = args
+        .json
+        .iter()
+        .filter_map(|x| fs::canonicalize(x).ok())
+        .collect();
+    let folders: HashSet<_> = files
+        .iter()
+        .filter_map(|x| x.parent())
+        .chain([directory.as_path()])
+        .map(Path::to_path_buf)
+        .collect();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                return;
+            }
+            event
+                .paths
+                .into_iter()
+                .filter(|x| {
+                    files.contains(x) || (is_definition(x) && x.parent() == Some(&directory))
+                })
+                .for_each(|x| _ = sender.send(x));
+        }
+    })?;
+    for folder in &folders {
+        watcher.watch(folder, RecursiveMode::NonRecursive)?;
+    }
+
+    tokio::spawn(async move {
+        // The watcher stops as soon as it is dropped, so it has to live in the task
+        let _watcher = watcher;
+
+        loop {
+            let changed = tokio::select! {
+                changed = collect_changes(&mut receiver, DEBOUNCE) => changed,
+                _ = interrupt.cancelled() => return,
+            };
+            if changed.is_empty() {
+                return;
+            }
+
+            for path in changed {
+                let Ok(data) = tokio::fs::read(&path).await else {
+                    continue;
+                };
+                match parse(&path, &data) {
+                    Err(error) => warn!("Skipped reloading {path:?}: {error}"),
+                    Ok(configs) => {
+                        for config in &configs {
+                            debug!("Reloading the definition of {}...", config.name.bold());
+                        }
+                        let configs = configs.into_iter().map(|x| apply_overrides(x, &args));
+                        keyboards.update_configs(configs);
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn directory(args: &CLI) -> PathBuf {
+    args.directory.clone().unwrap_or(CLI::current_dir())
+}
+
+fn is_definition(path: &Path) -> bool {
+    path.extension().and_then(|x| x.to_str()) == Some("json") || is_profile(path)
+}
+
+fn is_profile(path: &Path) -> bool {
+    path.extension().and_then(|x| x.to_str()) == Some("orp")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_only_flag_overrides_configs() {
+        let config = Config::fixture("keyboard");
+        let args = CLI {
+            direct_only: true,
+            ..CLI::default()
+        };
+
+        assert!(!apply_overrides(config.clone(), &CLI::default()).direct_only);
+        assert!(apply_overrides(config, &args).direct_only);
+    }
+}
@@ -0,0 +1,119 @@
+use thiserror::Error as ThisError;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    #[error("{0}")]
+    Protocol(String),
+    #[error("{0}")]
+    Device(String),
+    #[error(transparent)]
+    Hid(#[from] async_hid::HidError),
+    /// The device is gone, so retrying the failed operation makes no sense
+    #[error("{0}")]
+    Removed(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Bind(String),
+    #[error("{0}")]
+    NoDevices(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Error::Io(error) => is_io_disconnect(error),
+            _ => false,
+        }
+    }
+
+    /// Exit code of the process when it stops due to this error, so service managers can
+    /// tell whether restarting makes sense. These codes are stable:
+    /// - `1` - unexpected failure (I/O and anything not classified below)
+    /// - `2` - invalid CLI options or keyboard definitions, restarting will not help
+    /// - `3` - failed to listen on the port, most likely it is taken by another process
+    /// - `4` - some required keyboards are not connected
+    /// - `5` - failed to communicate with a keyboard
+    /// - `6` - a client violated the protocol
+    pub fn exit_code(&self) -> u32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Config(_) => 2,
+            Error::Bind(_) => 3,
+            Error::NoDevices(_) => 4,
+            Error::Device(_) | Error::Hid(_) | Error::Removed(_) => 5,
+            Error::Protocol(_) => 6,
+        }
+    }
+
+    pub fn class(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "I/O",
+            Error::Config(_) => "configuration",
+            Error::Bind(_) => "network",
+            Error::NoDevices(_) => "missing device",
+            Error::Device(_) | Error::Hid(_) | Error::Removed(_) => "device",
+            Error::Protocol(_) => "protocol",
+        }
+    }
+}
+
+/// Peers closing the connection, or silently going away (caught by TCP keepalive or by the
+/// timeout of stalled requests)
+pub fn is_io_disconnect(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::UnexpectedEof
+        || error.kind() == std::io::ErrorKind::ConnectionReset
+        || error.kind() == std::io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, utils::ErrorExt};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn classifies_io_disconnects() {
+        let eof = anyhow::Error::from(std::io::Error::from(ErrorKind::UnexpectedEof));
+        let reset = anyhow::Error::from(Error::from(std::io::Error::from(
+            ErrorKind::ConnectionReset,
+        )));
+        let denied = anyhow::Error::from(std::io::Error::from(ErrorKind::PermissionDenied));
+        let keepalive = anyhow::Error::from(std::io::Error::from(ErrorKind::TimedOut));
+
+        assert!(eof.is_disconnect());
+        assert!(reset.is_disconnect());
+        assert!(keepalive.is_disconnect());
+        assert!(!denied.is_disconnect());
+    }
+
+    #[test]
+    fn classifies_protocol_errors() {
+        let error = anyhow::Error::from(Error::Protocol("Unknown device!".into()));
+        assert!(!error.is_disconnect());
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn classifies_config_errors() {
+        let error = Config::from_str("{}").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Config(_))
+        ));
+        assert_eq!(error.exit_code(), 2);
+    }
+
+    #[test]
+    fn exits_with_a_generic_code_for_unknown_errors() {
+        let error = anyhow::anyhow!("Something went wrong!");
+        assert_eq!(error.exit_code(), 1);
+        let error = anyhow::Error::from(Error::Bind("Address in use".into()));
+        assert_eq!(error.exit_code(), 3);
+    }
+}
@@ -0,0 +1,444 @@
+use anyhow::Result;
+use palette::{encoding::Srgb, rgb::Rgb};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    error::Error,
+    keyboard::{Keyboard, KeyboardSnapshot},
+    orp::{self, SavedController},
+};
+
+/// Keyboards presented to clients as one device, by their names or `vvvv:pppp[:<serial>]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupConfig {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+/// A group member is either a connected keyboard or the definition of a missing one
+#[derive(Clone)]
+pub enum Member {
+    Online(Keyboard),
+    Missing(Box<Config>),
+}
+
+/// Several physical keyboards placed side by side in one combined matrix
+#[derive(Clone)]
+pub struct Group {
+    name: String,
+    members: Vec<Member>,
+    /// LED counts of the members, so routing a frame does not touch the keyboards
+    sizes: Vec<usize>,
+}
+
+/// A device as seen by the SDK clients
+#[derive(Clone)]
+pub enum Controller {
+    Keyboard(Keyboard),
+    Group(Group),
+}
+
+impl Group {
+    pub fn new(name: String, members: Vec<Member>) -> Self {
+        let sizes = members
+            .iter()
+            .map(|member| match member {
+                Member::Online(keyboard) => keyboard.config().count_leds() as usize,
+                Member::Missing(config) => config.count_leds() as usize,
+            })
+            .collect();
+        Group {
+            name,
+            members,
+            sizes,
+        }
+    }
+
+    fn keyboards(&self) -> impl Iterator<Item = &Keyboard> {
+        self.members.iter().filter_map(|member| match member {
+            Member::Online(keyboard) => Some(keyboard),
+            Member::Missing(_) => None,
+        })
+    }
+
+    async fn snapshots(&self) -> Vec<KeyboardSnapshot> {
+        let mut snapshots = Vec::new();
+        for member in &self.members {
+            snapshots.push(match member {
+                Member::Online(keyboard) => keyboard.snapshot().await,
+                Member::Missing(config) => missing_snapshot(*config.clone()),
+            });
+        }
+        snapshots
+    }
+
+    pub async fn snapshot(&self) -> KeyboardSnapshot {
+        compose(&self.name, self.snapshots().await)
+    }
+
+    pub fn update_colors(&self, colors: Vec<Option<Rgb>>, offset: usize, brightness: bool) {
+        for (index, colors, offset) in split_colors(&self.sizes, colors, offset) {
+            if let Member::Online(keyboard) = &self.members[index] {
+                keyboard.update_colors(colors, offset, brightness);
+            }
+        }
+    }
+
+    pub async fn save_state(&self) -> Result<String> {
+        let mut states = Vec::new();
+        for member in &self.members {
+            match member {
+                Member::Online(keyboard) => states.push(keyboard.save_state().await?),
+                Member::Missing(config) => Err(Error::Device(format!(
+                    "Cannot save the state of {}, since {} is not connected!",
+                    self.name, config.name
+                )))?,
+            }
+        }
+        serde_json::to_string(&states).map_err(|x| x.into())
+    }
+
+    /// Splits the colors of a saved group between its members, which share the saved mode
+    pub async fn import_profile(&self, saved: &SavedController) -> Result<String> {
+        let mut states = Vec::new();
+        let mut colors = saved.colors.iter().copied();
+        for (member, &leds) in self.members.iter().zip(&self.sizes) {
+            let Member::Online(keyboard) = member else {
+                return Err(Error::Device(format!(
+                    "Cannot load a profile of {}, since a member is not connected!",
+                    self.name
+                ))
+                .into());
+            };
+            let saved = SavedController {
+                colors: colors.by_ref().take(leds).collect(),
+                ..saved.clone()
+            };
+            states.push(keyboard.import_profile(&saved).await?);
+        }
+        serde_json::to_string(&states).map_err(|x| x.into())
+    }
+
+    pub fn load_state(&self, state: String, with_brightness: bool) -> Result<()> {
+        let states: Vec<String> = serde_json::from_str(&state)?;
+        if states.len() != self.members.len() {
+            return Err(Error::Protocol(format!(
+                "State has {} keyboards, but {} has {}!",
+                states.len(),
+                self.name,
+                self.members.len()
+            ))
+            .into());
+        }
+
+        for (member, state) in self.members.iter().zip(states) {
+            if let Member::Online(keyboard) = member {
+                keyboard.load_state(state, with_brightness);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Controller {
+    pub async fn snapshot(&self) -> KeyboardSnapshot {
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.snapshot().await,
+            Controller::Group(group) => group.snapshot().await,
+        }
+    }
+
+    pub async fn config(&self) -> Arc<Config> {
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.config(),
+            Controller::Group(group) => Arc::new(group.snapshot().await.config),
+        }
+    }
+
+    pub fn update_colors(&self, colors: Vec<Option<Rgb>>, offset: usize, brightness: bool) {
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.update_colors(colors, offset, brightness),
+            Controller::Group(group) => group.update_colors(colors, offset, brightness),
+        }
+    }
+
+    fn for_each(&self, action: impl Fn(&Keyboard)) {
+        match self {
+            Controller::Keyboard(keyboard) => action(keyboard),
+            Controller::Group(group) => group.keyboards().for_each(action),
+        }
+    }
+
+    pub fn update_color(&self, color: Rgb<Srgb, u8>) {
+        self.for_each(|x| x.update_color(color));
+    }
+
+    pub fn update_mode_colors(&self, colors: Vec<Option<Rgb<Srgb, u8>>>) {
+        self.for_each(|x| x.update_mode_colors(colors.clone()));
+    }
+
+    pub fn update_effect(&self, effect: u8) {
+        self.for_each(|x| x.update_effect(effect));
+    }
+
+    pub fn update_speed(&self, speed: u8) {
+        self.for_each(|x| x.update_speed(speed));
+    }
+
+    pub fn update_direction(&self, direction: u8) {
+        self.for_each(|x| x.update_direction(direction));
+    }
+
+    pub fn update_brightness(&self, brightness: u8) {
+        self.for_each(|x| x.update_brightness(brightness));
+    }
+
+    pub async fn flush(&self) {
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.flush().await,
+            Controller::Group(group) => {
+                for keyboard in group.keyboards() {
+                    keyboard.flush().await;
+                }
+            }
+        }
+    }
+
+    pub fn persist_state(&self) {
+        self.for_each(|x| x.persist_state());
+    }
+
+    pub async fn save_state(&self) -> Result<String> {
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.save_state().await,
+            Controller::Group(group) => group.save_state().await,
+        }
+    }
+
+    pub fn load_state(&self, state: String, with_brightness: bool) -> Result<()> {
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.load_state(state, with_brightness),
+            Controller::Group(group) => group.load_state(state, with_brightness)?,
+        }
+        Ok(())
+    }
+
+    /// Converts an OpenRGB profile into a state for `load_state`
+    pub async fn import_profile(&self, data: &[u8]) -> Result<String> {
+        let controllers = orp::parse(data)?;
+        let saved = orp::find(&controllers, &*self.config().await)?;
+        match self {
+            Controller::Keyboard(keyboard) => keyboard.import_profile(saved).await,
+            Controller::Group(group) => group.import_profile(saved).await,
+        }
+    }
+
+    pub fn set_profile(&self, profile: Option<String>) {
+        // Group profiles hold several states, so they are not reapplied to single keyboards
+        if let Controller::Keyboard(keyboard) = self {
+            keyboard.set_profile(profile);
+        }
+    }
+}
+
+fn missing_snapshot(config: Config) -> KeyboardSnapshot {
+    let (speed, brightness) = (
+        config.speed_hints.default_value(),
+        config.brightness_hints.default_value(),
+    );
+    let leds = config.count_leds() as usize;
+    let keys = (config.matrix.0 * config.matrix.1) as usize;
+    KeyboardSnapshot {
+        config,
+        keymap: vec![0; keys],
+        colors: vec![Rgb::new(0, 0, 0); leds],
+        color: Rgb::new(0, 0, 0),
+        mode_colors: Vec::new(),
+        effect: 0,
+        speed,
+        direction: 0,
+        brightness,
+        offline: true,
+        degraded: false,
+    }
+}
+
+/// Places the members next to each other, numbering their LEDs one after another.
+/// The modes and their values are taken from the first member.
+fn compose(name: &str, members: Vec<KeyboardSnapshot>) -> KeyboardSnapshot {
+    let width: u32 = members.iter().map(|x| x.config.matrix.0).sum();
+    let height = members.iter().map(|x| x.config.matrix.1).max().unwrap_or(0);
+
+    let mut leds = Vec::new();
+    let mut encoders = Vec::new();
+    let mut keymap = vec![0; (width * height) as usize];
+    let mut colors = Vec::new();
+    let (mut led_offset, mut column_offset) = (0u32, 0u32);
+
+    for member in &members {
+        let config = &member.config;
+        leds.extend(config.leds.iter().filter_map(|&(led, (row, col))| {
+            let led = u8::try_from(led as u32 + led_offset).ok()?;
+            let col = u8::try_from(col as u32 + column_offset).ok()?;
+            Some((led, (row, col)))
+        }));
+        encoders.extend(
+            config
+                .encoder_leds()
+                .iter()
+                .filter_map(|&led| u8::try_from(led as u32 + led_offset).ok()),
+        );
+
+        for row in 0..config.matrix.1 {
+            for col in 0..config.matrix.0 {
+                // Keymaps read from a device may be shorter than the matrix
+                let key = member.keymap.get((row * config.matrix.0 + col) as usize);
+                keymap[(row * width + column_offset + col) as usize] = key.copied().unwrap_or(0);
+            }
+        }
+
+        colors.extend_from_slice(&member.colors);
+        led_offset += config.count_leds();
+        column_offset += config.matrix.0;
+    }
+
+    let offline = members.iter().all(|x| x.offline);
+    let degraded = !offline && members.iter().any(|x| x.offline);
+    let first = members
+        .into_iter()
+        .next()
+        .expect("Groups always have members!");
+
+    KeyboardSnapshot {
+        config: Config {
+            name: name.to_string(),
+            serial: None,
+            display_name: None,
+            leds,
+            matrix: (width, height),
+            corrections: Vec::new(),
+            remap: Vec::new(),
+            zones: Vec::new(),
+            segments: Vec::new(),
+            encoders,
+            include_encoders: true,
+            ..first.config
+        },
+        keymap,
+        colors,
+        color: first.color,
+        mode_colors: first.mode_colors,
+        effect: first.effect,
+        speed: first.speed,
+        direction: first.direction,
+        brightness: first.brightness,
+        offline,
+        degraded,
+    }
+}
+
+/// Splits a color update into the parts that belong to each member, with member local offsets
+fn split_colors(
+    sizes: &[usize],
+    colors: Vec<Option<Rgb>>,
+    offset: usize,
+) -> Vec<(usize, Vec<Option<Rgb>>, usize)> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+
+    for (index, &size) in sizes.iter().enumerate() {
+        let end = start + size;
+        let from = offset.max(start);
+        let to = (offset + colors.len()).min(end);
+        if from < to {
+            parts.push((
+                index,
+                colors[from - offset..to - offset].to_vec(),
+                from - start,
+            ));
+        }
+        start = end;
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(offline: bool) -> KeyboardSnapshot {
+        let config = Config::fixture("keyboard");
+        KeyboardSnapshot {
+            offline,
+            ..missing_snapshot(config)
+        }
+    }
+
+    #[test]
+    fn routes_colors_to_the_second_member() {
+        let color = Some(Rgb::new(1.0, 0.0, 0.0));
+        let parts = split_colors(&[6, 6], vec![color], 7);
+        assert_eq!(parts, vec![(1, vec![color], 1)]);
+
+        let parts = split_colors(&[6, 6], vec![color; 4], 4);
+        assert_eq!(parts, vec![(0, vec![color; 2], 4), (1, vec![color; 2], 0)]);
+    }
+
+    #[test]
+    fn composes_members_side_by_side() {
+        let group = compose("Desk", vec![snapshot(false), snapshot(false)]);
+        assert_eq!(group.config.name, "Desk");
+        assert_eq!(group.config.matrix, (6, 2));
+        assert_eq!(group.config.count_leds(), 12);
+        assert_eq!(group.colors.len(), 12);
+        assert!(group.config.leds.contains(&(6, (0, 3))));
+        assert!(!group.degraded);
+    }
+
+    #[tokio::test]
+    async fn updates_the_colors_of_every_member() {
+        use crate::keyboards::{Filter, Keyboards};
+        use indexmap::IndexMap;
+
+        let mut numpad = Config::fixture_json("keyboard");
+        numpad["name"] = "Test Numpad".into();
+        numpad["productId"] = "0x0211".into();
+        let numpad = Config::from_str(&numpad.to_string()).unwrap();
+        let configs =
+            [Config::fixture("keyboard"), numpad].map(|x| ((x.vendor_id, x.product_id), x));
+        let group = GroupConfig {
+            name: "Desk".into(),
+            members: vec!["Test Keyboard".into(), "Test Numpad".into()],
+        };
+        let keyboards = Keyboards::simulate(
+            IndexMap::from(configs),
+            vec![group],
+            vec![],
+            vec![],
+            Filter::default(),
+            8,
+        )
+        .await
+        .unwrap();
+
+        let controller = keyboards.controller(0).await.unwrap();
+        let mut expected = controller.snapshot().await.colors;
+        let red = Some(Rgb::new(1.0, 0.0, 0.0));
+        controller.update_colors(vec![red; 2], 7, true);
+        controller.flush().await;
+
+        // LEDs 7 and 8 are the second and the third ones of the numpad
+        expected[7..9].fill(Rgb::new(255, 0, 0));
+        assert_eq!(controller.snapshot().await.colors, expected);
+    }
+
+    #[test]
+    fn reports_missing_members_as_degraded() {
+        let group = compose("Desk", vec![snapshot(false), snapshot(true)]);
+        assert!(group.degraded);
+        assert!(!group.offline);
+    }
+}
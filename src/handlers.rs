@@ -1,174 +1,269 @@
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use colored::Colorize;
-use log::debug;
+use itertools::Itertools;
+use log::{debug, warn};
 use palette::{encoding::Srgb, rgb::Rgb};
-use std::path::PathBuf;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::io::AsyncReadExt;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    config::Config,
     consts::{
         DEVICE_TYPE_KEYBOARD, MODE_FLAG_HAS_MODE_SPECIFIC_COLOR, MODE_FLAG_HAS_PER_LED_COLOR,
-        MODE_FLAG_HAS_RANDOM_COLOR, OPENRGB_PROTOCOL_VERSION, Request, ZONE_TYPE_MATRIX,
-        openrgb_keycode,
+        MODE_FLAG_HAS_RANDOM_COLOR, OPENRGB_PROTOCOL_VERSION, Request, ZONE_TYPE_LINEAR,
+        ZONE_TYPE_MATRIX,
     },
+    error::Error,
+    keyboard::KeyboardSnapshot,
     keyboards::Keyboards,
-    utils::{BufferExt, StreamExt},
+    orp,
+    profiles::{self, ProfileData, ProfileFormat, ProfileLimits},
+    recorder::Recorder,
+    utils::{BufferExt, Stream, StreamExt},
 };
 
 pub struct HandlerContext {
+    /// Tells the changes made by this connection from the ones of other clients
+    pub id: usize,
     pub keyboards: Keyboards,
     pub client: Option<String>,
+    pub protocol_version: u32,
+    pub requested_version: Option<u32>,
     pub with_brightness: bool,
     pub profiles_dir: PathBuf,
+    pub profile_format: ProfileFormat,
+    /// Secret clients have to present before changing anything
+    pub auth_token: Option<String>,
+    pub authenticated: bool,
+    pub profile_limits: ProfileLimits,
+    pub profile_retention: Option<Duration>,
+    pub max_request_length: u32,
+    pub client_timeout: Option<Duration>,
+    pub recorder: Option<Recorder>,
+    pub rate_limit: Option<RateLimit>,
+    pub rate: ClientRate,
     pub interrupt: CancellationToken,
 }
 
+/// How many requests per second a single client may send before it is reported
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub throttle: bool,
+}
+
+/// Request accounting of a single connection over one second windows
+#[derive(Debug, Default)]
+pub struct ClientRate {
+    pub total: u64,
+    window: Option<Instant>,
+    count: u32,
+    warned: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Pace {
+    Normal,
+    /// The client is over the limit, `first` is set once until it calms down for a whole window
+    Exceeded {
+        first: bool,
+        wait: Duration,
+    },
+}
+
+impl ClientRate {
+    pub fn tick(&mut self, now: Instant, limit: u32) -> Pace {
+        let window = Duration::from_secs(1);
+        let start = match self.window {
+            Some(start) if now.duration_since(start) < window => start,
+            _ => {
+                if self.count <= limit {
+                    self.warned = false;
+                }
+                self.count = 0;
+                *self.window.insert(now)
+            }
+        };
+
+        self.count += 1;
+        self.total += 1;
+        if self.count <= limit {
+            return Pace::Normal;
+        }
+
+        let first = !self.warned;
+        self.warned = true;
+        Pace::Exceeded {
+            first,
+            wait: (start + window).saturating_duration_since(now),
+        }
+    }
+}
+
+/// Accounts a request, warning about clients over the limit and optionally holding them off
+pub async fn pace(ctx: &mut HandlerContext) {
+    let Some(limit) = ctx.rate_limit else {
+        return;
+    };
+
+    if let Pace::Exceeded { first, wait } = ctx.rate.tick(Instant::now(), limit.requests) {
+        if first {
+            warn!(
+                "Client {} sends over {} requests per second{} ({} requests so far)",
+                ctx.client.as_deref().unwrap_or("unknown").bold(),
+                limit.requests,
+                if limit.throttle {
+                    ", throttling it"
+                } else {
+                    ""
+                },
+                ctx.rate.total,
+            );
+        }
+        if limit.throttle {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
 pub async fn handle(
     request: u32,
     device: u32,
-    stream: &mut TcpStream,
+    stream: &mut impl Stream,
     ctx: &mut HandlerContext,
 ) -> Result<()> {
-    let length = stream.read_u32_le().await?;
-    let keyboards = ctx.keyboards.items().await;
-
+    let length = check_length(stream.read_u32_le().await?, ctx.max_request_length)?;
+    if !ctx.authenticated && is_mutating(request) {
+        return Err(
+            Error::Protocol(format!("Unauthenticated client sent request {request}!")).into(),
+        );
+    }
+    if Request::try_from(request).is_err() {
+        // Requests of newer clients are skipped, so the connection stays usable
+        debug!("Skipped unknown request id {request} ({length} bytes).");
+        return skip(stream, length).await;
+    }
     match Request::try_from(request).ok() {
         Some(Request::GetProtocolVersion) => {
-            let _client_version = stream.read_u32_le().await?;
+            let client_version = stream.read_u32_le().await?;
+            ctx.protocol_version = negotiate_protocol(client_version);
+            ctx.requested_version = Some(client_version);
+            log_protocol(ctx);
             let version = OPENRGB_PROTOCOL_VERSION.to_le_bytes();
             stream.write_response(request, &version).await?;
             return Ok(());
         }
+        Some(Request::GetServerInfo) => {
+            let mut features = vec!["precise-colors", "brightness-toggle"];
+            if ctx.with_brightness {
+                features.push("brightness");
+            }
+            if ctx.profile_limits.count.is_some() || ctx.profile_limits.bytes.is_some() {
+                features.push("profile-limits");
+            }
+            if ctx.auth_token.is_some() {
+                features.push("auth");
+            }
+
+            stream
+                .write_response(request, &server_info(&features))
+                .await?;
+            return Ok(());
+        }
         Some(Request::GetControllerCount) => {
-            let count: u32 = keyboards.len() as u32;
+            let count = ctx.keyboards.count().await as u32;
             stream.write_response(request, &count.to_le_bytes()).await?;
             return Ok(());
         }
+        Some(Request::RescanDevices) => {
+            match ctx.keyboards.rescan().await {
+                Ok(added) => debug!("Rescan found {added} new keyboard(s)."),
+                Err(error) => warn!("Failed to rescan for keyboards: {error}"),
+            }
+            return Ok(());
+        }
+        Some(Request::Authenticate) => {
+            let token = stream.read_str(length).await?;
+            authenticate(ctx, &token);
+            let status = (!ctx.authenticated as u32).to_le_bytes();
+            stream.write_response(request, &status).await?;
+            if !ctx.authenticated {
+                return Err(Error::Protocol("Invalid authentication token!".into()).into());
+            }
+            return Ok(());
+        }
+        Some(Request::SetBrightnessWrites) => {
+            // Only this connection is affected, the keyboards keep whatever was written last
+            ctx.with_brightness = stream.read_u32_le().await? != 0;
+            debug!(
+                "Client {} turned brightness writes {}.",
+                ctx.client.as_deref().unwrap_or("unknown").bold(),
+                if ctx.with_brightness { "on" } else { "off" }
+            );
+            return Ok(());
+        }
         Some(Request::SetClientName) => {
-            let mut name: Vec<u8> = vec![0; length as usize];
+            let mut name: Vec<u8> = vec![0; length];
             stream.read_exact(&mut name).await?;
+            let mut name = String::from_utf8_lossy(&name).to_string();
+
+            // Stock OpenRGB clients can only pass the token along with their name
+            if ctx.auth_token.is_some() && !ctx.authenticated {
+                if let Some((client, token)) = name.trim_end_matches('\0').rsplit_once('@') {
+                    authenticate(ctx, token);
+                    if !ctx.authenticated {
+                        return Err(Error::Protocol("Invalid authentication token!".into()).into());
+                    }
+                    name = client.to_string();
+                }
+            }
 
             let first_time = ctx.client.is_none();
-            ctx.client = Some(String::from_utf8_lossy(&name).to_string());
+            ctx.client = Some(name);
             if first_time {
                 debug!("Client {} connected.", ctx.client.clone().unwrap().bold());
+                log_protocol(ctx);
             }
             return Ok(());
         }
         _ => {}
     }
 
-    let keyboard = keyboards
-        .values()
-        .nth(device as usize)
-        .ok_or(anyhow!("Unknown device!"))?;
+    let keyboard = (ctx.keyboards.controller(device as usize).await)
+        .ok_or(Error::Protocol("Unknown device!".into()))?;
 
     match Request::try_from(request).ok() {
         Some(Request::GetControllerData) => {
-            if length > 0 {
-                stream.read_u32_le().await?;
-            }
-
-            let config = keyboard.config().await;
-            let id = format!("{:04x}:{:04x}", config.vendor_id, config.product_id);
-
-            let mut buffer = Vec::new();
-            buffer.extend_from_slice(&0u32.to_le_bytes()); // Data size (will update later)
-
-            buffer.extend_from_slice(&DEVICE_TYPE_KEYBOARD.to_le_bytes());
-            buffer.extend_from_str(&config.name);
-            buffer.extend_from_str("Unknown");
-            buffer.extend_from_str(&format!("{} via ColorHoster", &config.name));
-            buffer.extend_from_str(env!("CARGO_PKG_VERSION"));
-            buffer.extend_from_str(&id);
-            buffer.extend_from_str(&format!("HID: {}", id));
-
-            buffer.extend_from_slice(&(config.effects.len() as u16).to_le_bytes());
-            buffer.extend_from_slice(&(keyboard.effect().await as i32).to_le_bytes());
-
-            for (name, id, flags) in &config.effects {
-                buffer.extend_from_str(name);
-
-                buffer.extend_from_slice(&id.to_le_bytes());
-                buffer.extend_from_slice(&flags.to_le_bytes());
-                buffer.extend_from_slice(&config.speed.0.to_le_bytes());
-                buffer.extend_from_slice(&config.speed.1.to_le_bytes());
-                buffer.extend_from_slice(&config.brightness.0.to_le_bytes());
-                buffer.extend_from_slice(&config.brightness.1.to_le_bytes());
-
-                let mode_colors: u32 = 1;
-                buffer.extend_from_slice(&mode_colors.to_le_bytes());
-                buffer.extend_from_slice(&mode_colors.to_le_bytes());
-                buffer.extend_from_slice(&(keyboard.speed().await as u32).to_le_bytes());
-                buffer.extend_from_slice(&(keyboard.brightness().await as u32).to_le_bytes());
-                buffer.extend_from_slice(&(0u32).to_le_bytes()); // Direction is constant
-
-                let color_mode = if flags & MODE_FLAG_HAS_PER_LED_COLOR != 0 {
-                    1u32
-                } else if flags & MODE_FLAG_HAS_MODE_SPECIFIC_COLOR != 0 {
-                    2u32
-                } else if flags & MODE_FLAG_HAS_RANDOM_COLOR != 0 {
-                    3u32
-                } else {
-                    0u32
-                };
-                buffer.extend_from_slice(&color_mode.to_le_bytes());
-
-                buffer.extend_from_slice(&(mode_colors as u16).to_le_bytes());
-                buffer.extend_from_color(&keyboard.color().await);
-            }
-
-            buffer.extend_from_slice(&(1u16).to_le_bytes());
-
-            let leds_count = config.count_leds();
-            buffer.extend_from_str("Keyboard");
-            buffer.extend_from_slice(&ZONE_TYPE_MATRIX.to_le_bytes());
-            buffer.extend_from_slice(&leds_count.to_le_bytes());
-            buffer.extend_from_slice(&leds_count.to_le_bytes());
-            buffer.extend_from_slice(&leds_count.to_le_bytes());
-
-            let matrix_data_size = (config.matrix.0 * config.matrix.1 * 4) + 8;
-            buffer.extend_from_slice(&(matrix_data_size as u16).to_le_bytes());
-            buffer.extend_from_slice(&config.matrix.1.to_le_bytes());
-            buffer.extend_from_slice(&config.matrix.0.to_le_bytes());
-
-            let mut led_matrix = vec![0xFFFFFFFF; (config.matrix.0 * config.matrix.1) as usize];
-            for &(led, (row, col)) in config.leds.iter() {
-                led_matrix[row as usize * config.matrix.0 as usize + col as usize] = led as u32;
-            }
-            buffer.extend_from_u32s(&led_matrix);
-
-            buffer.extend_from_slice(&(leds_count as u16).to_le_bytes());
-            let keymap = keyboard.keymap().await;
-            for &(led, (row, col)) in config.leds.iter() {
-                let scancode = keymap[row as usize * config.matrix.0 as usize + col as usize];
-                buffer.extend_from_str(&format!("Key: {}", openrgb_keycode(scancode)));
-                buffer.extend_from_slice(&(led as u32).to_le_bytes());
-            }
-
-            buffer.extend_from_slice(&(leds_count as u16).to_le_bytes());
-            for color in keyboard.colors().await {
-                buffer.extend_from_color(&color);
-            }
-
-            let buffer_length = buffer.len() as u32;
-            buffer[0..4].copy_from_slice(&buffer_length.to_le_bytes());
+            let protocol = if length > 0 {
+                negotiate_protocol(stream.read_u32_le().await?)
+            } else {
+                ctx.protocol_version
+            };
 
+            let buffer = controller_data(&keyboard.snapshot().await, protocol);
             stream.write_response(request, &buffer).await?;
         }
         Some(Request::UpdateSingleLed) => {
             let led_index = stream.read_u32_le().await? as usize;
             let rgb = stream.read_rgb().await?;
 
+            if let Some(recorder) = &ctx.recorder {
+                recorder.record(device as usize, led_index, &[Some(rgb)]);
+            }
             keyboard.update_colors(vec![Some(rgb)], led_index, ctx.with_brightness);
         }
         Some(Request::UpdateLeds) | Some(Request::UpdateZoneLeds) => {
             let _data_length = stream.read_u32_le().await?;
 
-            if request == Request::UpdateZoneLeds as u32 {
-                let _zone = stream.read_u32_le().await?;
-            }
+            let zone = if request == Request::UpdateZoneLeds as u32 {
+                Some(stream.read_u32_le().await?)
+            } else {
+                None
+            };
 
             let led_count = stream.read_u16_le().await?;
             let mut colors: Vec<Option<Rgb<Srgb, f32>>> = Vec::new();
@@ -176,73 +271,130 @@ pub async fn handle(
                 colors.push(Some(stream.read_rgb().await?));
             }
 
+            let (colors, offset) = match zone {
+                Some(zone) => zone_colors(&*keyboard.config().await, zone, colors)?,
+                None => (colors, 0),
+            };
+            if let Some(recorder) = &ctx.recorder {
+                recorder.record(device as usize, offset, &colors);
+            }
+            keyboard.update_colors(colors, offset, ctx.with_brightness);
+        }
+        Some(Request::UpdateLedsPrecise) => {
+            // Same as `UpdateLeds`, but with colors as 3 floats, so they are quantized only once
+            let _data_length = stream.read_u32_le().await?;
+            let led_count = stream.read_u16_le().await?;
+            let mut colors: Vec<Option<Rgb<Srgb, f32>>> = Vec::new();
+            for _ in 0..led_count {
+                colors.push(Some(stream.read_rgb_f32().await?));
+            }
+
+            if let Some(recorder) = &ctx.recorder {
+                recorder.record(device as usize, 0, &colors);
+            }
             keyboard.update_colors(colors, 0, ctx.with_brightness);
         }
         Some(Request::UpdateMode) | Some(Request::SaveMode) => {
-            let data_length = stream.read_u32_le().await?;
-            let effect = stream.read_i32_le().await? as u8;
-            keyboard.update_effect(effect);
-
+            let data_length = check_length(stream.read_u32_le().await?, ctx.max_request_length)?;
+            let effect = stream.read_i32_le().await?;
             let name_length = stream.read_u16_le().await? as usize;
-            let mut buffer = vec![0; data_length as usize - 10];
+            let mut buffer = vec![0; data_length.saturating_sub(10)];
             stream.read_exact(&mut buffer).await?;
 
+            // Everything is read before applying, so a truncated mode changes nothing
             let speed = buffer.read_u32_le(name_length + 32)?;
-            keyboard.update_speed(speed as u8);
-
             let brightness = buffer.read_u32_le(name_length + 36)?;
-            keyboard.update_brightness(brightness as u8);
+            let direction = buffer.read_u32_le(name_length + 40)?;
+            let colors = buffer.read_u16_le(name_length + 48)? as usize;
+            let color = match colors {
+                0 => None,
+                _ => Some(buffer.read_rgb(name_length + 50)?),
+            };
+            let config = keyboard.config().await;
+            // The whole mode is read by now, so the connection stays usable without it
+            let Some(effect) = config.find_effect(effect) else {
+                warn!(
+                    "Skipped unknown effect {effect} for {}!",
+                    config.name.bold()
+                );
+                return Ok(());
+            };
+            let mut mode_colors = vec![None; config.mode_colors.len()];
+            let slots = config.effect_colors(effect as i32);
+            for (slot, index) in slots.into_iter().enumerate().take(colors.saturating_sub(1)) {
+                mode_colors[index] = Some(buffer.read_rgb(name_length + 54 + slot * 4)?);
+            }
 
-            if buffer.read_u16_le(name_length + 48)? > 0 {
-                let color = buffer.read_rgb(name_length + 50)?;
+            keyboard.update_effect(effect);
+            // Keyboards clamp these to the ranges of their definitions
+            keyboard.update_speed(speed.min(u8::MAX as u32) as u8);
+            keyboard.update_brightness(brightness.min(u8::MAX as u32) as u8);
+            keyboard.update_direction(direction as u8);
+            if let Some(color) = color {
                 keyboard.update_color(color);
             }
+            if colors > 1 {
+                keyboard.update_mode_colors(mode_colors);
+            }
 
             if request == Request::SaveMode as u32 {
                 keyboard.persist_state();
             }
         }
         Some(Request::SetCustomMode) => {
-            if let Some(effect) = keyboard
-                .config()
-                .await
-                .effects
-                .iter()
-                .find(|x| x.2 & MODE_FLAG_HAS_PER_LED_COLOR != 0)
-                .map(|x| x.1 as u8)
-            {
+            if let Some(effect) = keyboard.config().await.custom_effect() {
                 keyboard.update_effect(effect);
             }
         }
         Some(Request::SaveProfile) => {
-            let profile = stream.read_str(length as usize).await?;
-            let path = ctx.profiles_dir.join(format!("{profile}.json"));
+            let profile = stream.read_str(length).await?;
 
-            let data = keyboard.save_state().await?;
-            tokio::fs::write(&path, data).await?;
+            let result = async {
+                profiles::check_name(&profile)?;
+                let data = match ctx.profile_format {
+                    ProfileFormat::Orp => {
+                        let snapshot = keyboard.snapshot().await;
+                        orp::serialize(&[controller_data(&snapshot, OPENRGB_PROTOCOL_VERSION)])
+                    }
+                    ProfileFormat::Json => keyboard.save_state().await?.into_bytes(),
+                };
+                ctx.profile_limits
+                    .check(&ctx.profiles_dir, &profile, data.len())?;
+                profiles::write(&ctx.profiles_dir, &profile, ctx.profile_format, &data).await?;
+                keyboard.set_profile(Some(profile.clone()));
+                Ok(())
+            }
+            .await;
+            let status = profile_status("save", &profile, result);
+            stream.write_response(request, &status).await?;
         }
         Some(Request::LoadProfile) => {
-            let profile = stream.read_str(length as usize).await?;
-            let path = ctx.profiles_dir.join(format!("{profile}.json"));
+            let profile = stream.read_str(length).await?;
 
-            let data = tokio::fs::read_to_string(&path).await?;
-            keyboard.load_state(data, ctx.with_brightness);
+            let result = async {
+                profiles::check_name(&profile)?;
+                let state = match profiles::read_profile(&ctx.profiles_dir, &profile).await {
+                    Some(ProfileData::Json(state)) => state,
+                    Some(ProfileData::Orp(data)) => keyboard.import_profile(&data).await?,
+                    None => Err(std::io::Error::from(std::io::ErrorKind::NotFound))?,
+                };
+                keyboard.load_state(state, ctx.with_brightness)?;
+                keyboard.set_profile(Some(profile.clone()));
+                Ok(())
+            }
+            .await;
+            let status = profile_status("load", &profile, result);
+            stream.write_response(request, &status).await?;
         }
         Some(Request::DeleteProfile) => {
-            let profile = stream.read_str(length as usize).await?;
-            let path = ctx.profiles_dir.join(format!("{profile}.json"));
-            tokio::fs::remove_file(&path).await?;
+            let profile = stream.read_str(length).await?;
+            let result = profiles::check_name(&profile)
+                .and_then(|_| profiles::delete(&ctx.profiles_dir, &profile, ctx.profile_retention));
+            let status = profile_status("delete", &profile, result);
+            stream.write_response(request, &status).await?;
         }
         Some(Request::GetProfileList) => {
-            let profiles: Vec<_> = ctx
-                .profiles_dir
-                .read_dir()?
-                .filter_map(|x| x.ok())
-                .filter_map(|x| {
-                    let name = x.file_name().to_string_lossy().into_owned();
-                    Some(name.strip_suffix(".json")?.to_string())
-                })
-                .collect();
+            let profiles = profiles::list(&ctx.profiles_dir)?;
 
             let mut buffer: Vec<u8> = Vec::new();
             buffer.extend_from_slice(&0u32.to_le_bytes()); // Data size (will update later)
@@ -260,9 +412,786 @@ pub async fn handle(
             let _zone = stream.read_i32_le().await?;
             let _size = stream.read_i32_le().await?;
         }
-        Some(_) => Err(anyhow!("Unknown request id {}!", request))?,
-        None => Err(anyhow!("Unknown request id {}!", request))?,
+        Some(_) | None => {
+            debug!("Skipped unsupported request id {request} ({length} bytes).");
+            skip(stream, length).await?;
+        }
     };
 
+    if changes_settings(request) {
+        ctx.keyboards.notify_change(Some(ctx.id));
+    }
+    Ok(())
+}
+
+/// Discards the data of a request that is not handled
+async fn skip(stream: &mut impl Stream, length: usize) -> Result<()> {
+    let mut data = (&mut *stream).take(length as u64);
+    let skipped = tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+    if skipped < length as u64 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
     Ok(())
 }
+
+/// Whether a request changes anything, so it needs an authenticated client
+fn is_mutating(request: u32) -> bool {
+    matches!(
+        Request::try_from(request),
+        Ok(Request::UpdateLeds
+            | Request::UpdateZoneLeds
+            | Request::UpdateSingleLed
+            | Request::UpdateLedsPrecise
+            | Request::UpdateMode
+            | Request::SaveMode
+            | Request::SetCustomMode
+            | Request::SaveProfile
+            | Request::LoadProfile
+            | Request::DeleteProfile
+            | Request::ResizeZone
+            | Request::RescanDevices)
+    )
+}
+
+fn authenticate(ctx: &mut HandlerContext, token: &str) {
+    ctx.authenticated = ctx
+        .auth_token
+        .as_deref()
+        .is_none_or(|secret| tokens_match(secret, token));
+}
+
+/// Compares tokens in a time that does not depend on where they differ
+pub fn tokens_match(secret: &str, token: &str) -> bool {
+    let diff = secret
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    secret.len() == token.len() && diff == 0
+}
+
+/// Whether a request changes what other clients see in `GetControllerData`. Per-LED updates
+/// are left out, as they are streamed far too often to re-announce the devices for each of them
+fn changes_settings(request: u32) -> bool {
+    matches!(
+        Request::try_from(request),
+        Ok(Request::UpdateMode | Request::SaveMode | Request::SetCustomMode | Request::LoadProfile)
+    )
+}
+
+/// Rejects declared lengths over the limit before anything is allocated for them
+pub fn check_length(length: u32, limit: u32) -> Result<usize> {
+    if length > limit {
+        return Err(Error::Protocol(format!(
+            "Declared length of {length} bytes exceeds the limit of {limit}!"
+        ))
+        .into());
+    }
+    Ok(length as usize)
+}
+
+/// Encodes the acknowledgment of a profile operation: a `u32` status (0 on success), followed by
+/// the reason on failure. Only protocol errors are passed as is, so no local paths leak to clients
+fn profile_status(operation: &str, profile: &str, result: Result<()>) -> Vec<u8> {
+    let Err(error) = result else {
+        return 0u32.to_le_bytes().to_vec();
+    };
+
+    warn!(
+        "Failed to {operation} profile {}\x1B[33m: {error}",
+        profile.bold()
+    );
+    let reason = match error.downcast_ref() {
+        Some(Error::Protocol(reason)) => reason.clone(),
+        _ if error
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|x| x.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            format!("Profile {profile} does not exist!")
+        }
+        _ => format!("Failed to {operation} profile {profile}!"),
+    };
+
+    let mut buffer = 1u32.to_le_bytes().to_vec();
+    buffer.extend_from_str(&reason);
+    buffer
+}
+
+/// Limits the colors of a zone update to the zone, returning them with the offset of its first LED
+fn zone_colors(
+    config: &Config,
+    zone: u32,
+    mut colors: Vec<Option<Rgb>>,
+) -> Result<(Vec<Option<Rgb>>, usize)> {
+    let layout = config.zone_layout();
+    let (_, range) = layout
+        .get(zone as usize)
+        .ok_or(Error::Protocol("Unknown zone!".into()))?;
+    colors.truncate(range.len());
+    Ok((colors, range.start))
+}
+
+pub fn negotiate_protocol(client_version: u32) -> u32 {
+    client_version.min(OPENRGB_PROTOCOL_VERSION)
+}
+
+fn log_protocol(ctx: &HandlerContext) {
+    if let (Some(client), Some(requested)) = (&ctx.client, ctx.requested_version) {
+        debug!(
+            "{}",
+            protocol_message(client, requested, ctx.protocol_version)
+        );
+    }
+}
+
+fn protocol_message(client: &str, requested: u32, negotiated: u32) -> String {
+    format!(
+        "Client {client} requested protocol version {requested}, negotiated {negotiated} (server supports {OPENRGB_PROTOCOL_VERSION})."
+    )
+}
+
+fn server_info(features: &[&str]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&0u32.to_le_bytes()); // Data size (will update later)
+    buffer.extend_from_str(env!("CARGO_PKG_VERSION"));
+    buffer.extend_from_slice(&OPENRGB_PROTOCOL_VERSION.to_le_bytes());
+    buffer.extend_from_slice(&(features.len() as u16).to_le_bytes());
+    for feature in features {
+        buffer.extend_from_str(feature);
+    }
+
+    let buffer_length = buffer.len() as u32;
+    buffer[0..4].copy_from_slice(&buffer_length.to_le_bytes());
+    buffer
+}
+
+pub fn controller_data(keyboard: &KeyboardSnapshot, protocol: u32) -> Vec<u8> {
+    let config = &keyboard.config;
+    let id = config.hardware_id();
+
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(&0u32.to_le_bytes()); // Data size (will update later)
+
+    buffer.extend_from_slice(&DEVICE_TYPE_KEYBOARD.to_le_bytes());
+    let name = config.display_name();
+    buffer.extend_from_str(name);
+    if protocol >= 1 {
+        buffer.extend_from_str("Unknown");
+    }
+    if keyboard.offline {
+        buffer.extend_from_str(&format!("{name} via ColorHoster (Offline)"));
+    } else if keyboard.degraded {
+        buffer.extend_from_str(&format!("{name} via ColorHoster (Degraded)"));
+    } else {
+        buffer.extend_from_str(&format!("{name} via ColorHoster"));
+    }
+    buffer.extend_from_str(env!("CARGO_PKG_VERSION"));
+    buffer.extend_from_str(&id);
+    buffer.extend_from_str(&format!("HID: {}", id));
+
+    buffer.extend_from_slice(&(config.effects.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(&(keyboard.effect as i32).to_le_bytes());
+
+    for (name, id, flags) in &config.effects {
+        buffer.extend_from_str(name);
+
+        buffer.extend_from_slice(&id.to_le_bytes());
+        buffer.extend_from_slice(&flags.to_le_bytes());
+        buffer.extend_from_slice(&config.speed.0.to_le_bytes());
+        buffer.extend_from_slice(&config.speed.1.to_le_bytes());
+        if protocol >= 3 {
+            buffer.extend_from_slice(&config.brightness.0.to_le_bytes());
+            buffer.extend_from_slice(&config.brightness.1.to_le_bytes());
+        }
+
+        let extra_colors = config.effect_colors(*id);
+        let mode_colors = 1 + extra_colors.len() as u32;
+        buffer.extend_from_slice(&mode_colors.to_le_bytes());
+        buffer.extend_from_slice(&mode_colors.to_le_bytes());
+        buffer.extend_from_slice(&(keyboard.speed as u32).to_le_bytes());
+        if protocol >= 3 {
+            buffer.extend_from_slice(&(keyboard.brightness as u32).to_le_bytes());
+        }
+        buffer.extend_from_slice(&(keyboard.direction as u32).to_le_bytes());
+
+        let color_mode = if flags & MODE_FLAG_HAS_PER_LED_COLOR != 0 {
+            1u32
+        } else if flags & MODE_FLAG_HAS_MODE_SPECIFIC_COLOR != 0 {
+            2u32
+        } else if flags & MODE_FLAG_HAS_RANDOM_COLOR != 0 {
+            3u32
+        } else {
+            0u32
+        };
+        buffer.extend_from_slice(&color_mode.to_le_bytes());
+
+        buffer.extend_from_slice(&(mode_colors as u16).to_le_bytes());
+        buffer.extend_from_color(&keyboard.color);
+        for index in extra_colors {
+            let color = keyboard.mode_colors.get(index);
+            buffer.extend_from_color(color.unwrap_or(&Rgb::new(0, 0, 0)));
+        }
+    }
+
+    let layout = config.zone_layout();
+    buffer.extend_from_slice(&(layout.len() as u16).to_le_bytes());
+
+    for (name, range) in &layout {
+        let zone_leds = range.len() as u32;
+        buffer.extend_from_str(name);
+        buffer.extend_from_slice(&ZONE_TYPE_MATRIX.to_le_bytes());
+        buffer.extend_from_slice(&zone_leds.to_le_bytes());
+        buffer.extend_from_slice(&zone_leds.to_le_bytes());
+        buffer.extend_from_slice(&zone_leds.to_le_bytes());
+
+        // Encoders get a row of their own below the keys
+        let encoders: Vec<_> = config
+            .encoder_leds()
+            .iter()
+            .filter(|&&x| range.contains(&(x as usize)))
+            .collect();
+        let width = config.matrix.0.max(encoders.len() as u32);
+        let height = config.matrix.1 + !encoders.is_empty() as u32;
+
+        let matrix_data_size = (width * height * 4) + 8;
+        buffer.extend_from_slice(&(matrix_data_size as u16).to_le_bytes());
+        buffer.extend_from_slice(&height.to_le_bytes());
+        buffer.extend_from_slice(&width.to_le_bytes());
+
+        // Matrices refer to the LEDs by their index within the zone
+        let mut led_matrix = vec![0xFFFFFFFF; (width * height) as usize];
+        for &(led, (row, col)) in config.leds.iter() {
+            if range.contains(&(led as usize)) {
+                led_matrix[row as usize * width as usize + col as usize] =
+                    (led as usize - range.start) as u32;
+            }
+        }
+        for (col, &&led) in encoders.iter().enumerate() {
+            led_matrix[(height as usize - 1) * width as usize + col] =
+                (led as usize - range.start) as u32;
+        }
+        buffer.extend_from_u32s(&led_matrix);
+
+        if protocol >= 4 {
+            let segments = config.zone_segments(range);
+            buffer.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+            for (name, leds) in segments {
+                buffer.extend_from_str(name);
+                buffer.extend_from_slice(&ZONE_TYPE_LINEAR.to_le_bytes());
+                buffer.extend_from_slice(&(leds.start as u32).to_le_bytes());
+                buffer.extend_from_slice(&(leds.len() as u32).to_le_bytes());
+            }
+        }
+    }
+
+    let leds_count = config.count_leds();
+    buffer.extend_from_slice(&(leds_count as u16).to_le_bytes());
+    let keys = config.leds.iter().map(|&(led, (row, col))| {
+        let scancode = keyboard.keymap[row as usize * config.matrix.0 as usize + col as usize];
+        (led, format!("Key: {}", config.key_label(scancode)))
+    });
+    let encoders = (config.encoder_leds().iter().enumerate())
+        .map(|(index, &led)| (led, format!("Encoder {}", index + 1)));
+    for (led, name) in keys.chain(encoders).sorted_by_key(|x| x.0) {
+        buffer.extend_from_str(&name);
+        buffer.extend_from_slice(&(led as u32).to_le_bytes());
+    }
+
+    buffer.extend_from_slice(&(leds_count as u16).to_le_bytes());
+    for color in &keyboard.colors {
+        buffer.extend_from_color(color);
+    }
+
+    let buffer_length = buffer.len() as u32;
+    buffer[0..4].copy_from_slice(&buffer_length.to_le_bytes());
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::DEFAULT_MAX_REQUEST_LENGTH, utils::ErrorExt};
+
+    fn snapshot() -> KeyboardSnapshot {
+        KeyboardSnapshot::for_test(Config::fixture("keyboard"))
+    }
+
+    /// A 16x16 board with an LED under every key
+    fn large_definition() -> String {
+        let mut json = Config::fixture_json("keyboard");
+        json["matrix"] = serde_json::json!({ "rows": 16, "cols": 16 });
+        json["layouts"]["keymap"] = (0..16)
+            .map(|row| {
+                (0..16)
+                    .map(|col| format!("{row},{col}\nl{}", row * 16 + col))
+                    .collect()
+            })
+            .collect::<Vec<Vec<String>>>()
+            .into();
+        json.to_string()
+    }
+
+    fn large_snapshot() -> KeyboardSnapshot {
+        let config = Config::from_str(&large_definition()).expect("Failed to parse definition!");
+        KeyboardSnapshot::for_test(config)
+    }
+
+    #[test]
+    fn serializes_large_boards() {
+        let keyboard = large_snapshot();
+        assert_eq!(keyboard.config.count_leds(), 256);
+
+        let data = controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION);
+        assert_eq!(data.read_u32_le(0).unwrap() as usize, data.len());
+        let names = data.windows(b"Key: A".len()).filter(|x| x == b"Key: A");
+        assert_eq!(names.count(), 256);
+    }
+
+    #[cfg(feature = "bench")]
+    mod benches {
+        extern crate test;
+
+        use super::*;
+        use test::Bencher;
+
+        #[bench]
+        fn parses_large_boards(bencher: &mut Bencher) {
+            let definition = large_definition();
+            bencher.iter(|| Config::from_str(test::black_box(&definition)).unwrap());
+        }
+
+        #[bench]
+        fn serializes_large_boards(bencher: &mut Bencher) {
+            let keyboard = large_snapshot();
+            bencher.iter(|| controller_data(test::black_box(&keyboard), OPENRGB_PROTOCOL_VERSION));
+        }
+    }
+
+    #[test]
+    fn negotiates_lowest_common_version() {
+        assert_eq!(negotiate_protocol(0), 0);
+        assert_eq!(negotiate_protocol(2), 2);
+        assert_eq!(negotiate_protocol(99), OPENRGB_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn rejects_absurd_declared_lengths() {
+        assert_eq!(check_length(64, DEFAULT_MAX_REQUEST_LENGTH).unwrap(), 64);
+        let error = check_length(u32::MAX, DEFAULT_MAX_REQUEST_LENGTH).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn describes_negotiated_version() {
+        assert_eq!(
+            protocol_message("OpenRGB", 5, negotiate_protocol(5)),
+            "Client OpenRGB requested protocol version 5, negotiated 4 (server supports 4)."
+        );
+    }
+
+    #[test]
+    fn throttles_only_clients_over_the_rate() {
+        let start = Instant::now();
+        let mut noisy = ClientRate::default();
+        let mut quiet = ClientRate::default();
+
+        for i in 0..3 {
+            let now = start + Duration::from_millis(i * 10);
+            assert_eq!(noisy.tick(now, 3), Pace::Normal);
+            assert_eq!(quiet.tick(now, 3), Pace::Normal);
+        }
+
+        let now = start + Duration::from_millis(400);
+        assert_eq!(
+            noisy.tick(now, 3),
+            Pace::Exceeded {
+                first: true,
+                wait: Duration::from_millis(600)
+            }
+        );
+        assert!(matches!(
+            noisy.tick(now, 3),
+            Pace::Exceeded { first: false, .. }
+        ));
+        assert_eq!(noisy.total, 5);
+
+        // A quiet window lets the client be reported again
+        let later = start + Duration::from_secs(1);
+        assert_eq!(noisy.tick(later, 3), Pace::Normal);
+        assert_eq!(noisy.tick(later + Duration::from_secs(1), 3), Pace::Normal);
+        assert_eq!(quiet.tick(later, 3), Pace::Normal);
+        assert!(!noisy.warned && !quiet.warned);
+    }
+
+    #[test]
+    fn reports_crate_version() {
+        let info = server_info(&["brightness"]);
+        let version = env!("CARGO_PKG_VERSION");
+
+        assert_eq!(info.read_u32_le(0).unwrap() as usize, info.len());
+        assert_eq!(info.read_u16_le(4).unwrap() as usize, version.len() + 1);
+        assert_eq!(&info[6..6 + version.len()], version.as_bytes());
+
+        let protocol = 6 + version.len() + 1;
+        assert_eq!(
+            info.read_u32_le(protocol).unwrap(),
+            OPENRGB_PROTOCOL_VERSION
+        );
+        assert_eq!(info.read_u16_le(protocol + 4).unwrap(), 1);
+    }
+
+    #[test]
+    fn serializes_per_connection_version() {
+        let keyboard = snapshot();
+        let v2_client = controller_data(&keyboard, negotiate_protocol(2));
+        let v4_client = controller_data(&keyboard, negotiate_protocol(4));
+
+        // Protocol 3 adds brightness min, max and value to each of the 4 modes,
+        // and protocol 4 a segment count to the only zone
+        assert_eq!(v4_client.len(), v2_client.len() + 4 * 12 + 2);
+        assert_eq!(v2_client.read_u32_le(0).unwrap() as usize, v2_client.len());
+        assert_eq!(v4_client.read_u32_le(0).unwrap() as usize, v4_client.len());
+    }
+
+    #[test]
+    fn lays_out_controller_data_for_the_negotiated_version() {
+        let mut json = Config::zoned_fixture();
+        json["segments"] = serde_json::json!([{ "name": "Tip", "leds": [5, 5] }]);
+        let keyboard = KeyboardSnapshot::for_test(Config::from_str(&json.to_string()).unwrap());
+        let v0_client = controller_data(&keyboard, negotiate_protocol(0));
+        let v1_client = controller_data(&keyboard, negotiate_protocol(1));
+        let v4_client = controller_data(&keyboard, negotiate_protocol(4));
+
+        // Protocol 1 adds the vendor string right after the name
+        let name = 8 + 2 + "Test Keyboard\0".len();
+        assert_eq!(&v1_client[name + 2..name + 10], b"Unknown\0");
+        assert_eq!(v1_client.len(), v0_client.len() + 2 + "Unknown\0".len());
+
+        // Protocol 3 adds brightness to each of the 4 modes, and protocol 4 a segment count
+        // to each of the 3 zones, followed by the name, type, start and size of the segment
+        let segment = 2 + "Tip\0".len() + 3 * 4;
+        assert_eq!(v4_client.len(), v1_client.len() + 4 * 12 + 3 * 2 + segment);
+        let tip = v4_client
+            .windows(6)
+            .position(|x| x == b"\x04\0Tip\0")
+            .unwrap();
+        assert_eq!(
+            v4_client.read_u32_le(tip + 6).unwrap(),
+            ZONE_TYPE_LINEAR as u32
+        );
+        assert_eq!(v4_client.read_u32_le(tip + 10).unwrap(), 1);
+        assert_eq!(v4_client.read_u32_le(tip + 14).unwrap(), 1);
+        assert!(!v1_client.windows(4).any(|x| x == b"Tip\0"));
+    }
+
+    #[test]
+    fn describes_every_color_of_a_mode() {
+        let config = Config::fixture("gradient");
+        let keyboard = KeyboardSnapshot {
+            config,
+            mode_colors: vec![Rgb::new(0, 255, 0), Rgb::new(255, 255, 0)],
+            ..snapshot()
+        };
+        let data = controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION);
+
+        let mode = data
+            .windows(20)
+            .position(|x| x == b"\x12\0Reactive Gradient\0")
+            .unwrap();
+        // The name is followed by the value, flags, speed and brightness ranges
+        let colors = mode + 20 + 6 * 4;
+        assert_eq!(data.read_u32_le(colors).unwrap(), 3);
+        assert_eq!(data.read_u32_le(colors + 4).unwrap(), 3);
+        assert_eq!(data.read_u16_le(colors + 4 * 6).unwrap(), 3);
+        assert_eq!(
+            data[colors + 4 * 6 + 2..][..12],
+            [0, 0, 255, 0, 0, 255, 0, 0, 255, 255, 0, 0]
+        );
+    }
+
+    #[test]
+    fn routes_zone_updates_to_their_range() {
+        let config = Config::from_str(&Config::zoned_fixture().to_string()).unwrap();
+        let red = Some(Rgb::new(1.0, 0.0, 0.0));
+        let (colors, offset) = zone_colors(&config, 1, vec![red; 6]).unwrap();
+
+        let mut leds = vec![None; config.count_leds() as usize];
+        leds[offset..offset + colors.len()].copy_from_slice(&colors);
+        assert_eq!(leds, [None, None, red, red, None, None]);
+        assert!(zone_colors(&config, 3, vec![red]).is_err());
+
+        let data = controller_data(
+            &KeyboardSnapshot::for_test(config),
+            OPENRGB_PROTOCOL_VERSION,
+        );
+        let zone = b"Middle\0";
+        assert!(data.windows(zone.len()).any(|x| x == zone));
+    }
+
+    #[test]
+    fn acknowledges_profile_deletion() {
+        let directory = std::env::temp_dir().join("colorhoster-acknowledge");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("a.json"), "{}").unwrap();
+
+        let delete = || profile_status("delete", "a", profiles::delete(&directory, "a", None));
+        assert_eq!(delete(), 0u32.to_le_bytes());
+        assert_eq!(delete()[..4], 1u32.to_le_bytes());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn acknowledges_missing_profile_loads() {
+        let path = std::env::temp_dir().join("colorhoster-missing-profile.json");
+        let result = tokio::fs::read_to_string(&path).await.map(|_| ());
+        let status = profile_status("load", "missing", result.map_err(Into::into));
+
+        let mut expected = 1u32.to_le_bytes().to_vec();
+        expected.extend_from_str("Profile missing does not exist!");
+        assert_eq!(status, expected);
+
+        let refused = Error::Protocol("The limit of 2 profiles is reached!".into());
+        let status = profile_status("save", "third", Err(refused.into()));
+        assert!(status.ends_with(b"The limit of 2 profiles is reached!\0"));
+        assert_eq!(profile_status("save", "fine", Ok(())), 0u32.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn skips_the_data_of_unknown_requests() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0xAA; 5]).await.unwrap();
+        client.write_all(&42u32.to_le_bytes()).await.unwrap();
+
+        skip(&mut server, 5).await.unwrap();
+        assert_eq!(server.read_u32_le().await.unwrap(), 42);
+
+        drop(client);
+        assert!(skip(&mut server, 1).await.unwrap_err().is_disconnect());
+    }
+
+    fn context(id: usize, keyboards: Keyboards) -> HandlerContext {
+        HandlerContext {
+            id,
+            keyboards,
+            client: None,
+            protocol_version: 0,
+            requested_version: None,
+            with_brightness: true,
+            profiles_dir: std::env::temp_dir().join("colorhoster-handlers"),
+            profile_format: ProfileFormat::default(),
+            auth_token: None,
+            authenticated: true,
+            profile_limits: ProfileLimits::default(),
+            profile_retention: None,
+            max_request_length: DEFAULT_MAX_REQUEST_LENGTH,
+            client_timeout: None,
+            recorder: None,
+            rate_limit: None,
+            rate: ClientRate::default(),
+            interrupt: CancellationToken::new(),
+        }
+    }
+
+    /// Sends a request to the handler and reads the data of its response
+    async fn request(ctx: &mut HandlerContext, kind: Request, data: &[u8]) -> Vec<u8> {
+        use tokio::io::AsyncWriteExt;
+
+        let kind = kind as u32;
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_u32_le(data.len() as u32).await.unwrap();
+        client.write_all(data).await.unwrap();
+        handle(kind, 0, &mut server, ctx).await.unwrap();
+
+        let mut header = [0; 16];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header.to_vec().read_u32_le(8).unwrap(), kind);
+        let mut response = vec![0; header.to_vec().read_u32_le(12).unwrap() as usize];
+        client.read_exact(&mut response).await.unwrap();
+        response
+    }
+
+    /// Sends a request without a response and checks that the stream is left right after it
+    async fn send(ctx: &mut HandlerContext, kind: Request, data: &[u8]) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_u32_le(data.len() as u32).await.unwrap();
+        client.write_all(data).await.unwrap();
+        client.write_u32_le(0xC0FFEE).await.unwrap();
+        handle(kind as u32, 0, &mut server, ctx).await?;
+        assert_eq!(server.read_u32_le().await.unwrap(), 0xC0FFEE);
+        Ok(())
+    }
+
+    async fn simulated() -> Keyboards {
+        use crate::keyboards::Filter;
+
+        let config = Config::fixture("keyboard");
+        let configs = [((config.vendor_id, config.product_id), config)].into();
+        Keyboards::simulate(configs, vec![], vec![], vec![], Filter::default(), 8)
+            .await
+            .unwrap()
+    }
+
+    /// Lays out a mode the way OpenRGB sends it in `UpdateMode`, without a name or colors
+    fn mode(effect: i32, speed: u32) -> Vec<u8> {
+        let mut data = 62u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&effect.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        let mut fields = vec![0; 52];
+        fields[32..36].copy_from_slice(&speed.to_le_bytes());
+        fields[36..40].copy_from_slice(&255u32.to_le_bytes());
+        data.extend_from_slice(&fields);
+        data
+    }
+
+    #[tokio::test]
+    async fn skips_unknown_effects() {
+        let keyboards = simulated().await;
+        let keyboard = keyboards.controller(0).await.unwrap();
+        let before = keyboard.snapshot().await;
+        let mut ctx = context(1, keyboards.clone());
+
+        send(&mut ctx, Request::UpdateMode, &mode(9999, 10))
+            .await
+            .unwrap();
+        let data = request(&mut ctx, Request::GetControllerData, &[]).await;
+        assert_eq!(data, controller_data(&before, ctx.protocol_version));
+
+        let effect = before.config.effects[0].1;
+        send(&mut ctx, Request::UpdateMode, &mode(effect, 10))
+            .await
+            .unwrap();
+        keyboard.flush().await;
+        assert_eq!(keyboard.snapshot().await.speed, 10);
+    }
+
+    #[tokio::test]
+    async fn ignores_changes_to_offline_keyboards() {
+        let keyboards = simulated().await;
+        let keyboard = keyboards.controller(0).await.unwrap();
+        (keyboards.items().await.values()).for_each(|x| x.set_offline());
+        let before = keyboard.snapshot().await;
+        let mut ctx = context(1, keyboards.clone());
+
+        let leds = before.colors.len();
+        let mut colors = (4 + 2 + leds as u32 * 4).to_le_bytes().to_vec();
+        colors.extend_from_slice(&(leds as u16).to_le_bytes());
+        colors.extend_from_slice(&[255, 0, 0, 0].repeat(leds));
+        send(&mut ctx, Request::UpdateLeds, &colors).await.unwrap();
+        let effect = before.config.effects[0].1;
+        send(&mut ctx, Request::UpdateMode, &mode(effect, 10))
+            .await
+            .unwrap();
+
+        keyboard.flush().await;
+        let snapshot = keyboard.snapshot().await;
+        assert!(snapshot.offline);
+        assert_eq!(snapshot.colors, before.colors);
+        assert_eq!(snapshot.speed, before.speed);
+        let data = request(&mut ctx, Request::GetControllerData, &[]).await;
+        assert_eq!(data, controller_data(&before, ctx.protocol_version));
+    }
+
+    #[tokio::test]
+    async fn rejects_profiles_outside_the_directory() {
+        let mut ctx = context(1, simulated().await);
+        let outside = ctx.profiles_dir.parent().unwrap().join("escaped.orp");
+        for name in [
+            "../escaped",
+            "nested/../../escaped",
+            "..\\escaped",
+            ".hidden",
+            "",
+        ] {
+            let data = format!("{name}\0");
+            for kind in [
+                Request::SaveProfile,
+                Request::LoadProfile,
+                Request::DeleteProfile,
+            ] {
+                let status = request(&mut ctx, kind, data.as_bytes()).await;
+                assert_eq!(status[..4], 1u32.to_le_bytes());
+                assert!(status.ends_with(format!("Invalid profile name {name}!\0").as_bytes()));
+            }
+        }
+        assert!(!outside.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_protocol_of_each_connection() {
+        let keyboards = simulated().await;
+        let mut old_client = context(1, keyboards.clone());
+        let mut new_client = context(2, keyboards.clone());
+
+        request(
+            &mut old_client,
+            Request::GetProtocolVersion,
+            &2u32.to_le_bytes(),
+        )
+        .await;
+        request(
+            &mut new_client,
+            Request::GetProtocolVersion,
+            &4u32.to_le_bytes(),
+        )
+        .await;
+        assert_eq!(old_client.protocol_version, 2);
+        assert_eq!(new_client.protocol_version, 4);
+
+        let snapshot = keyboards.controller(0).await.unwrap().snapshot().await;
+        let old_data = request(&mut old_client, Request::GetControllerData, &[]).await;
+        let new_data = request(&mut new_client, Request::GetControllerData, &[]).await;
+        assert_eq!(old_data, controller_data(&snapshot, 2));
+        assert_eq!(new_data, controller_data(&snapshot, 4));
+        assert_ne!(old_data.len(), new_data.len());
+    }
+
+    #[test]
+    fn gates_only_mutating_requests() {
+        assert!(is_mutating(Request::UpdateLeds as u32));
+        assert!(is_mutating(Request::DeleteProfile as u32));
+        assert!(!is_mutating(Request::GetProtocolVersion as u32));
+        assert!(!is_mutating(Request::GetControllerData as u32));
+        assert!(!is_mutating(Request::Authenticate as u32));
+        assert!(!is_mutating(Request::SetBrightnessWrites as u32));
+
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "secreT"));
+        assert!(!tokens_match("secret", "secret2"));
+    }
+
+    #[test]
+    fn announces_only_settings_changes() {
+        assert!(changes_settings(Request::UpdateMode as u32));
+        assert!(changes_settings(Request::LoadProfile as u32));
+        assert!(!changes_settings(Request::UpdateLeds as u32));
+        assert!(!changes_settings(Request::GetControllerData as u32));
+        assert!(!changes_settings(9999));
+    }
+
+    #[test]
+    fn reports_offline_keyboards() {
+        let mut keyboard = snapshot();
+        let online = controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION);
+        keyboard.offline = true;
+        let offline = controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION);
+
+        let description = b"Test Keyboard via ColorHoster (Offline)\0";
+        assert!(offline.windows(description.len()).any(|x| x == description));
+        assert_eq!(offline.len(), online.len() + " (Offline)".len());
+    }
+
+    #[test]
+    fn omits_vendor_before_version_one() {
+        let keyboard = snapshot();
+        let v0_client = controller_data(&keyboard, 0);
+        let v1_client = controller_data(&keyboard, 1);
+
+        assert_eq!(v1_client.len(), v0_client.len() + "Unknown".len() + 3);
+    }
+}
@@ -0,0 +1,291 @@
+use anyhow::Result;
+use axum::{
+    Json, Router,
+    extract::{Path, Request, State},
+    http::{StatusCode, header::AUTHORIZATION},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use log::{debug, warn};
+use palette::{encoding::Srgb, rgb::Rgb};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    config::Config, error::Error, group::Controller, handlers::tokens_match,
+    keyboard::KeyboardSnapshot, keyboards::Keyboards,
+};
+
+#[derive(Clone)]
+struct Api {
+    keyboards: Keyboards,
+    with_brightness: bool,
+    auth_token: Option<String>,
+}
+
+/// A device as listed by `GET /devices`, indexed the same way as for OpenRGB clients
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct DeviceInfo {
+    index: usize,
+    name: String,
+    id: String,
+    leds: usize,
+    effects: Vec<String>,
+    effect: Option<String>,
+    speed: u8,
+    brightness: u8,
+    offline: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ColorsUpdate {
+    /// Colors as `#rrggbb`, where `null` leaves an LED as it is
+    colors: Vec<Option<String>>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct EffectUpdate {
+    effect: EffectId,
+    speed: Option<u8>,
+    brightness: Option<u8>,
+}
+
+/// Effects are picked either by their value or by their name
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EffectId {
+    Value(i32),
+    Name(String),
+}
+
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.1 });
+        (self.0, Json(body)).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+/// Serves the JSON API until the interrupt, sharing the keyboards with OpenRGB clients
+pub async fn serve(
+    address: SocketAddr,
+    keyboards: Keyboards,
+    with_brightness: bool,
+    auth_token: Option<String>,
+    interrupt: CancellationToken,
+) -> Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .map_err(|x| Error::Bind(format!("Failed to serve HTTP on {address}: {x}")))?;
+    debug!("Started HTTP server at {address}!");
+
+    let api = Api {
+        keyboards,
+        with_brightness,
+        auth_token,
+    };
+    let router = Router::new()
+        .route("/devices", get(devices))
+        .route("/devices/{index}/colors", get(colors).post(update_colors))
+        .route("/devices/{index}/effect", post(update_effect))
+        .layer(middleware::from_fn_with_state(api.clone(), authorize))
+        .with_state(api);
+
+    tokio::spawn(async move {
+        let server =
+            axum::serve(listener, router).with_graceful_shutdown(interrupt.cancelled_owned());
+        if let Err(error) = server.await {
+            warn!("HTTP server stopped: {error}");
+        }
+    });
+    Ok(())
+}
+
+/// Requires the `Authorization: Bearer <token>` header when the server has a token
+async fn authorize(State(api): State<Api>, request: Request, next: Next) -> Response {
+    let Some(secret) = &api.auth_token else {
+        return next.run(request).await;
+    };
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "));
+    if token.is_some_and(|x| tokens_match(secret, x)) {
+        next.run(request).await
+    } else {
+        let message = "Invalid authentication token!".to_string();
+        ApiError(StatusCode::UNAUTHORIZED, message).into_response()
+    }
+}
+
+async fn devices(State(api): State<Api>) -> Json<Vec<DeviceInfo>> {
+    let mut devices = Vec::new();
+    for (index, controller) in api.keyboards.controllers().await.iter().enumerate() {
+        devices.push(device_info(index, &controller.snapshot().await));
+    }
+    Json(devices)
+}
+
+async fn colors(State(api): State<Api>, Path(index): Path<usize>) -> ApiResult<Json<Vec<String>>> {
+    let snapshot = controller(&api, index).await?.snapshot().await;
+    Ok(Json(snapshot.colors.iter().map(to_hex).collect()))
+}
+
+async fn update_colors(
+    State(api): State<Api>,
+    Path(index): Path<usize>,
+    Json(update): Json<ColorsUpdate>,
+) -> ApiResult<StatusCode> {
+    let controller = controller(&api, index).await?;
+    let count = controller.config().await.count_leds() as usize;
+    if update.offset + update.colors.len() > count {
+        return Err(bad_request(format!("The device has only {count} LEDs!")));
+    }
+
+    let colors = update
+        .colors
+        .iter()
+        .map(|x| match x {
+            Some(hex) => parse_hex(hex)
+                .map(|x| Some(x.into_format()))
+                .ok_or_else(|| bad_request(format!("Invalid color {hex}, expected #rrggbb!"))),
+            None => Ok(None),
+        })
+        .collect::<ApiResult<_>>()?;
+    controller.update_colors(colors, update.offset, api.with_brightness);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn update_effect(
+    State(api): State<Api>,
+    Path(index): Path<usize>,
+    Json(update): Json<EffectUpdate>,
+) -> ApiResult<StatusCode> {
+    let controller = controller(&api, index).await?;
+    let config = controller.config().await;
+    let effect = find_effect(&config, &update.effect)
+        .ok_or_else(|| bad_request(format!("Unknown effect {:?}!", update.effect)))?;
+
+    controller.update_effect(effect);
+    if let Some(speed) = update.speed {
+        controller.update_speed(speed);
+    }
+    if let Some(brightness) = update.brightness {
+        controller.update_brightness(brightness);
+    }
+    api.keyboards.notify_change(None);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn controller(api: &Api, index: usize) -> ApiResult<Controller> {
+    api.keyboards
+        .controller(index)
+        .await
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("Unknown device {index}!")))
+}
+
+fn bad_request(message: String) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, message)
+}
+
+fn device_info(index: usize, snapshot: &KeyboardSnapshot) -> DeviceInfo {
+    let config = &snapshot.config;
+    DeviceInfo {
+        index,
+        name: config.display_name().to_string(),
+        id: config.hardware_id(),
+        leds: snapshot.colors.len(),
+        effects: config.effects.iter().map(|x| x.0.clone()).collect(),
+        effect: config
+            .effects
+            .iter()
+            .find(|x| x.1 == snapshot.effect as i32)
+            .map(|x| x.0.clone()),
+        speed: snapshot.speed,
+        brightness: snapshot.brightness,
+        offline: snapshot.offline,
+    }
+}
+
+fn find_effect(config: &Config, effect: &EffectId) -> Option<u8> {
+    let found = config.effects.iter().find(|x| match effect {
+        EffectId::Value(value) => x.1 == *value,
+        EffectId::Name(name) => x.0.eq_ignore_ascii_case(name),
+    });
+    found.map(|x| x.1 as u8)
+}
+
+fn to_hex(color: &Rgb<Srgb, u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.red, color.green, color.blue)
+}
+
+pub fn parse_hex(hex: &str) -> Option<Rgb<Srgb, u8>> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.chars().all(|x| x.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(Rgb::new(
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> KeyboardSnapshot {
+        let config = Config::fixture("gradient");
+        let leds = config.count_leds() as usize;
+        KeyboardSnapshot {
+            colors: vec![Rgb::new(255, 0, 16); leds],
+            effect: 2,
+            ..KeyboardSnapshot::for_test(config)
+        }
+    }
+
+    #[test]
+    fn describes_devices() {
+        let snapshot = snapshot();
+        let device = device_info(1, &snapshot);
+        assert_eq!(device.id, "362d:0210");
+        assert_eq!(device.leds, snapshot.colors.len());
+        assert_eq!(device.effect.as_deref(), Some("Gradient"));
+        assert_eq!(to_hex(&snapshot.colors[0]), "#ff0010");
+
+        let json = serde_json::to_value(&device).unwrap();
+        assert_eq!(json["index"], 1);
+        assert_eq!(json["effects"][3], "Reactive Gradient");
+    }
+
+    #[test]
+    fn picks_effects_by_value_or_name() {
+        let config = snapshot().config;
+        let effect = |json| find_effect(&config, &serde_json::from_str(json).unwrap());
+        assert_eq!(effect("3"), Some(3));
+        assert_eq!(effect("\"solid color\""), Some(1));
+        assert_eq!(effect("42"), None);
+        assert_eq!(effect("\"Rainbow\""), None);
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_hex("#ff0010"), Some(Rgb::new(255, 0, 16)));
+        assert_eq!(parse_hex("00FF00"), Some(Rgb::new(0, 255, 0)));
+        assert_eq!(parse_hex("#fff"), None);
+        assert_eq!(parse_hex("+fffff"), None);
+    }
+}
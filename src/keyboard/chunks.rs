@@ -1,3 +1,7 @@
+use anyhow::Result;
+
+use crate::error::Error;
+
 pub struct ChunksChanges<'a, T: PartialEq> {
     v: &'a [T],
     offset: usize,
@@ -37,25 +41,29 @@ impl<'a, T: PartialEq> Iterator for ChunksChanges<'a, T> {
 pub trait ChunkChanged<'a, T: PartialEq + 'a> {
     type Iter: Iterator<Item = (usize, &'a [T])>;
 
-    fn chunk_changed(&'a self, chunk_size: usize, reference: &'a [T]) -> Self::Iter;
+    fn chunk_changed(&'a self, chunk_size: usize, reference: &'a [T]) -> Result<Self::Iter>;
 }
 
 impl<'a, T: PartialEq + 'a> ChunkChanged<'a, T> for [T] {
     type Iter = ChunksChanges<'a, T>;
 
-    fn chunk_changed(&'a self, chunk_size: usize, reference: &'a [T]) -> Self::Iter {
+    fn chunk_changed(&'a self, chunk_size: usize, reference: &'a [T]) -> Result<Self::Iter> {
         assert!(chunk_size != 0, "chunk size must be non-zero");
-        assert!(
-            self.len() <= reference.len(),
-            "reference must be at least as long as self"
-        );
+        if self.len() > reference.len() {
+            return Err(Error::Protocol(format!(
+                "Cannot compare {} values against a reference of {}!",
+                self.len(),
+                reference.len()
+            ))
+            .into());
+        }
 
-        ChunksChanges {
+        Ok(ChunksChanges {
             v: self,
             offset: 0,
             chunk_size,
             reference,
-        }
+        })
     }
 }
 
@@ -72,6 +80,7 @@ mod tests {
 
         let chunks: Vec<_> = modified
             .chunk_changed(chunk_size, &original[offset..])
+            .unwrap()
             .collect();
         assert_eq!(chunks, vec![(2, &[7][..])]);
     }
@@ -82,7 +91,10 @@ mod tests {
         let modified = vec![1, -1, 4, -2, 6, -3, 8];
         let chunk_size = 3;
 
-        let chunks: Vec<_> = modified.chunk_changed(chunk_size, &original).collect();
+        let chunks: Vec<_> = modified
+            .chunk_changed(chunk_size, &original)
+            .unwrap()
+            .collect();
         assert_eq!(chunks, vec![(1, &[-1, 4, -2][..]), (5, &[-3][..])]);
     }
 
@@ -90,7 +102,7 @@ mod tests {
     fn all_elements_differ() {
         let original = vec![1, 2, 3];
         let modified = vec![4, 5, 6];
-        let chunks: Vec<_> = modified.chunk_changed(2, &original).collect();
+        let chunks: Vec<_> = modified.chunk_changed(2, &original).unwrap().collect();
         assert_eq!(chunks, vec![(0, &[4, 5][..]), (2, &[6][..])]);
     }
 
@@ -98,7 +110,7 @@ mod tests {
     fn no_differences() {
         let original = vec![1, 2, 3];
         let modified = vec![1, 2, 3];
-        let chunks: Vec<_> = modified.chunk_changed(2, &original).collect();
+        let chunks: Vec<_> = modified.chunk_changed(2, &original).unwrap().collect();
         assert!(chunks.is_empty());
     }
 
@@ -106,7 +118,7 @@ mod tests {
     fn exact_chunk_boundaries() {
         let original = vec![0, 0, 0, 0, 0];
         let modified = vec![1, 0, 2, 0, 3];
-        let chunks: Vec<_> = modified.chunk_changed(2, &original).collect();
+        let chunks: Vec<_> = modified.chunk_changed(2, &original).unwrap().collect();
         assert_eq!(chunks, vec![(0, &[1][..]), (2, &[2][..]), (4, &[3][..])]);
     }
 
@@ -114,7 +126,27 @@ mod tests {
     fn large_chunk_size() {
         let original = vec![1, 2, 3];
         let modified = vec![4, 5, 6];
-        let chunks: Vec<_> = modified.chunk_changed(5, &original).collect();
+        let chunks: Vec<_> = modified.chunk_changed(5, &original).unwrap().collect();
         assert_eq!(chunks, vec![(0, &[4, 5, 6][..])]);
     }
+
+    #[test]
+    fn merges_changes_separated_by_gaps_within_a_chunk() {
+        let original = vec![0; 10];
+        // Reports have a fixed size, so unchanged values between changes cost nothing
+        let modified = vec![1, 0, 0, 2, 0, 0, 0, 0, 0, 3];
+        let chunks: Vec<_> = modified.chunk_changed(4, &original).unwrap().collect();
+        assert_eq!(chunks, vec![(0, &[1, 0, 0, 2][..]), (9, &[3][..])]);
+
+        // A gap reaching past the chunk still needs another report
+        let chunks: Vec<_> = modified.chunk_changed(3, &original).unwrap().collect();
+        assert_eq!(chunks, vec![(0, &[1][..]), (3, &[2][..]), (9, &[3][..])]);
+    }
+
+    #[test]
+    fn short_reference_is_an_error() {
+        let original = vec![1, 2];
+        let modified = vec![1, 2, 3];
+        assert!(modified.chunk_changed(2, &original).is_err());
+    }
 }
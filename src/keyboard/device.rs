@@ -1,22 +1,71 @@
 use anyhow::Result;
-use async_hid::{AsyncHidRead, AsyncHidWrite, Device, DeviceId, DeviceWriter};
-use std::sync::Arc;
+use async_hid::{AsyncHidRead, AsyncHidWrite, Device, DeviceReader, DeviceWriter};
+use futures::future::BoxFuture;
+use log::debug;
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::sync::{
-    Mutex as AsyncMutex,
+    Mutex as AsyncMutex, Semaphore,
     mpsc::{self, Sender},
     oneshot,
 };
 use tokio_util::sync::CancellationToken;
 
-use crate::report::{FutureReport, FutureReportState, Report};
+use crate::{
+    consts::{QMK_REQUEST_ATTEMPTS, QMK_WRITE_ATTEMPTS},
+    error::Error,
+    report::{FutureReport, FutureReportState, Report},
+};
+
+/// Delay before the first retry of a failed write, doubled with every next one
+const WRITE_BACKOFF: Duration = Duration::from_millis(10);
 
 type ReportRequest<const N: usize> = (Vec<u8>, FutureReportState<N>, oneshot::Sender<()>);
 
+/// Receiving half of an opened device, so the reports can come from something else than HID
+pub trait ReportReader: Send + 'static {
+    fn read_report<'a>(&'a mut self, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<usize>>;
+}
+
+/// Sending half of an opened device, failing with `Error::Removed` once the device is gone
+pub trait ReportWriter: Send + 'static {
+    fn write_report<'a>(&'a mut self, report: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+}
+
+impl ReportReader for DeviceReader {
+    fn read_report<'a>(&'a mut self, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let read = self.read_input_report(buffer).await;
+            Ok(read.map_err(Error::Hid)?)
+        })
+    }
+}
+
+impl ReportWriter for DeviceWriter {
+    fn write_report<'a>(&'a mut self, report: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let written = self.write_output_report(report).await;
+            written.map_err(|x| {
+                if is_removed(&x) {
+                    Error::Removed(x.into()).into()
+                } else {
+                    Error::Hid(x).into()
+                }
+            })
+        })
+    }
+}
+
 pub struct KeyboardDevice<const N: usize> {
-    writer: Arc<AsyncMutex<DeviceWriter>>,
+    writer: Arc<AsyncMutex<Box<dyn ReportWriter>>>,
     listener: CancellationToken,
     reporter: Sender<ReportRequest<N>>,
-    pub id: DeviceId,
+    timeout: Duration,
+    /// Shared by every keyboard, so their requests do not flood the HID stack together
+    requests: Arc<Semaphore>,
 }
 
 impl<const N: usize> KeyboardDevice<N> {
@@ -24,9 +73,23 @@ impl<const N: usize> KeyboardDevice<N> {
         Report::<N>::new()
     }
 
-    pub async fn from_device(device: Device) -> Result<Self> {
-        let (mut reader, writer) = device.open().await?;
+    pub async fn from_device(
+        device: Device,
+        timeout: Duration,
+        requests: Arc<Semaphore>,
+    ) -> Result<Self> {
+        let (reader, writer) = device.open().await.map_err(Error::Hid)?;
+
+        Ok(Self::from_parts(reader, writer, timeout, requests))
+    }
 
+    /// Starts listening to the answers of an opened device
+    pub fn from_parts(
+        mut reader: impl ReportReader,
+        writer: impl ReportWriter,
+        timeout: Duration,
+        requests: Arc<Semaphore>,
+    ) -> Self {
         let listener = CancellationToken::new();
         let signal = listener.clone();
 
@@ -41,11 +104,13 @@ impl<const N: usize> KeyboardDevice<N> {
                     _ = signal.cancelled() => { return; }
 
                     Some(request) = receiver.recv() => {
+                        // Requests that timed out are no longer awaited by anyone
+                        requests.retain(|x| Arc::strong_count(&x.1) > 1);
                         requests.push((request.0, request.1));
                         _ = request.2.send(());
                     }
 
-                    _ = reader.read_input_report(&mut buffer) => {
+                    _ = reader.read_report(&mut buffer) => {
                         requests.retain(|x| {
                             if buffer.starts_with(&x.0) {
                                 let mut state = x.1.lock().unwrap();
@@ -63,36 +128,86 @@ impl<const N: usize> KeyboardDevice<N> {
             }
         });
 
-        Ok(KeyboardDevice {
-            writer: Arc::new(tokio::sync::Mutex::new(writer)),
-            id: device.id.clone(),
+        KeyboardDevice {
+            writer: Arc::new(tokio::sync::Mutex::new(Box::new(writer))),
             reporter,
             listener,
-        })
+            timeout,
+            requests,
+        }
     }
 
+    /// Writes a report, retrying transient failures (e.g. a busy device while resuming from
+    /// sleep). Errors of a removed device are returned right away for hotplug to handle them
     pub async fn send_report(&self, report: Report<N>) -> Result<()> {
-        self.writer
-            .lock()
-            .await
-            .write_output_report(&report.into_inner())
-            .await
-            .map_err(|err| anyhow::Error::from(err))?;
+        let report = report.into_inner();
+        let mut backoff = WRITE_BACKOFF;
+        for attempt in 1.. {
+            let result = self.writer.lock().await.write_report(&report).await;
+            let error = match result {
+                Ok(()) => break,
+                Err(error) => error,
+            };
+
+            let removed = matches!(error.downcast_ref(), Some(Error::Removed(_)));
+            if attempt >= QMK_WRITE_ATTEMPTS || removed {
+                return Err(error);
+            }
+            debug!("Retrying a failed write to the keyboard: {error}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
 
         Ok(())
     }
 
+    /// Sends a report and waits for the answer starting with the same `ref_bytes`, resending
+    /// it a few times before giving up on a keyboard that stays silent
     pub async fn request_report(&self, report: Report<N>, ref_bytes: usize) -> Result<[u8; N]> {
-        let prefix = report[..ref_bytes].to_vec();
-        let state = FutureReport::new_state();
+        for _ in 0..QMK_REQUEST_ATTEMPTS {
+            // Held until the answer arrives or the attempt times out
+            let _permit = self.requests.acquire().await?;
+            let prefix = report[..ref_bytes].to_vec();
+            let state = FutureReport::new_state();
+
+            let (ack_tx, ack_rx) = oneshot::channel();
+            self.reporter.send((prefix, state.clone(), ack_tx)).await?;
+            ack_rx.await?;
+
+            self.send_report(report.clone()).await?;
+            let response = FutureReport::from_state(state);
+            if let Ok(data) = tokio::time::timeout(self.timeout, response).await {
+                return Ok(data);
+            }
+        }
+
+        Err(Error::Device(format!(
+            "Keyboard did not answer a request within {:?} in {QMK_REQUEST_ATTEMPTS} attempts!",
+            self.timeout
+        ))
+        .into())
+    }
+}
 
-        let (ack_tx, ack_rx) = oneshot::channel();
-        self.reporter.send((prefix, state.clone(), ack_tx)).await?;
-        ack_rx.await?;
+/// Whether a write failed because the device is gone, as opposed to being busy for a moment.
+/// The HID backends report these as OS errors somewhere down the source chain.
+fn is_removed(error: &(dyn std::error::Error + 'static)) -> bool {
+    const ENXIO: i32 = 6;
+    const ENODEV: i32 = 19;
+    const ERROR_DEVICE_NOT_CONNECTED: i32 = 1167;
 
-        self.send_report(report).await?;
-        Ok(FutureReport::from_state(state).await)
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<io::Error>() {
+            return matches!(error.kind(), ErrorKind::NotFound | ErrorKind::NotConnected)
+                || matches!(
+                    error.raw_os_error(),
+                    Some(ENXIO | ENODEV | ERROR_DEVICE_NOT_CONNECTED)
+                );
+        }
+        source = error.source();
     }
+    false
 }
 
 impl<const N: usize> Drop for KeyboardDevice<N> {
@@ -100,3 +215,44 @@ impl<const N: usize> Drop for KeyboardDevice<N> {
         self.listener.cancel();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::script::scripted_device;
+    use std::sync::Mutex;
+
+    #[test]
+    fn tells_removed_devices_from_busy_ones() {
+        let removed = |error: io::Error| is_removed(&error);
+        assert!(removed(io::Error::from(ErrorKind::NotConnected)));
+        assert!(removed(io::Error::from_raw_os_error(1167)));
+        assert!(removed(io::Error::from_raw_os_error(19)));
+        assert!(!removed(io::Error::from_raw_os_error(16)));
+        assert!(!removed(io::Error::from(ErrorKind::BrokenPipe)));
+
+        let wrapped = Error::Removed(io::Error::from(ErrorKind::NotFound).into());
+        assert!(is_removed(&wrapped));
+    }
+
+    #[tokio::test]
+    async fn limits_requests_in_flight() {
+        let written = Arc::new(Mutex::new(0));
+        let counter = written.clone();
+        let script = move |_: &[u8]| {
+            *counter.lock().unwrap() += 1;
+            None
+        };
+        let requests = Arc::new(Semaphore::new(2));
+        let device = scripted_device::<33>(script, Duration::from_secs(1), requests.clone());
+        let device = Arc::new(device);
+
+        for _ in 0..5 {
+            let device = device.clone();
+            tokio::spawn(async move { device.request_report(device.create_report(), 1).await });
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*written.lock().unwrap(), 2);
+        assert_eq!(requests.available_permits(), 0);
+    }
+}
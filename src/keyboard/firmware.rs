@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use crate::{
+    config::Config,
+    consts::{
+        QMK_COMMAND_BRIGHTNESS, QMK_COMMAND_COLOR, QMK_COMMAND_EFFECT,
+        QMK_COMMAND_MATRIX_BRIGHTNESS, QMK_COMMAND_MATRIX_CHROMA, QMK_COMMAND_SPEED,
+        QMK_CUSTOM_CHANNEL, QMK_CUSTOM_GET_COMMAND, QMK_CUSTOM_SET_COMMAND, QMK_KEYMAP_GET_COMMAND,
+        QMK_MAX_LEDS, QMK_RGB_MATRIX_CHANNEL,
+    },
+};
+
+/// Firmware of a keyboard which exists only in memory, answering VIA requests the way
+/// a real one would. It knows nothing about keycodes, so every key reads as `KC_NO`.
+pub struct VirtualFirmware {
+    chroma: Vec<u8>,
+    brightness: Vec<u8>,
+    keymap: Vec<u8>,
+    /// Values set by channel and value id, e.g. the effect or the mode colors
+    values: HashMap<(u8, u8), Vec<u8>>,
+}
+
+impl VirtualFirmware {
+    pub fn new(config: &Config) -> Self {
+        let effect = config.effects.first().map_or(0, |x| x.1 as u8);
+        let values = [
+            (
+                QMK_COMMAND_BRIGHTNESS,
+                config.brightness_hints.default_value(),
+            ),
+            (QMK_COMMAND_SPEED, config.speed_hints.default_value()),
+            (QMK_COMMAND_EFFECT, effect),
+        ];
+        let mut values: HashMap<_, _> = values
+            .into_iter()
+            .map(|(id, value)| ((QMK_RGB_MATRIX_CHANNEL, id), vec![value]))
+            .collect();
+        values.insert((QMK_RGB_MATRIX_CHANNEL, QMK_COMMAND_COLOR), vec![0, 255]);
+
+        let leds = QMK_MAX_LEDS as usize;
+        VirtualFirmware {
+            chroma: vec![0; leds * 2],
+            brightness: vec![255; leds],
+            keymap: vec![0; (config.matrix.0 * config.matrix.1) as usize * 2],
+            values,
+        }
+    }
+
+    /// Applies a report, returning the answer to it if it requests anything
+    pub fn answer(&mut self, report: &[u8]) -> Option<Vec<u8>> {
+        let mut answer = report.to_vec();
+        match *report {
+            [
+                QMK_CUSTOM_SET_COMMAND,
+                QMK_CUSTOM_CHANNEL,
+                command,
+                offset,
+                count,
+                ..,
+            ] => {
+                let (matrix, range) = self.matrix(command, offset, count)?;
+                let data = report.get(5..5 + range.len())?;
+                matrix.get_mut(range)?.copy_from_slice(data);
+                None
+            }
+            [
+                QMK_CUSTOM_GET_COMMAND,
+                QMK_CUSTOM_CHANNEL,
+                command,
+                offset,
+                count,
+                ..,
+            ] => {
+                let (matrix, range) = self.matrix(command, offset, count)?;
+                let data = matrix.get(range)?;
+                answer.get_mut(5..5 + data.len())?.copy_from_slice(data);
+                Some(answer)
+            }
+            [QMK_CUSTOM_SET_COMMAND, channel, value_id, ..] => {
+                self.values
+                    .insert((channel, value_id), report[3..].to_vec());
+                None
+            }
+            [QMK_CUSTOM_GET_COMMAND, channel, value_id, ..] => {
+                let value = self.values.get(&(channel, value_id));
+                for (byte, value) in answer[3..].iter_mut().zip(value.into_iter().flatten()) {
+                    *byte = *value;
+                }
+                Some(answer)
+            }
+            [QMK_KEYMAP_GET_COMMAND, high, low, size, ..] => {
+                let offset = u16::from_be_bytes([high, low]) as usize;
+                let data = self.keymap.get(offset..offset + size as usize)?;
+                answer.get_mut(4..4 + data.len())?.copy_from_slice(data);
+                Some(answer)
+            }
+            _ => None,
+        }
+    }
+
+    /// Bytes of a per-LED matrix along with the range covering the LEDs of a report
+    fn matrix(
+        &mut self,
+        command: u8,
+        offset: u8,
+        count: u8,
+    ) -> Option<(&mut Vec<u8>, std::ops::Range<usize>)> {
+        let (offset, count) = (offset as usize, count as usize);
+        match command {
+            QMK_COMMAND_MATRIX_CHROMA => Some((&mut self.chroma, offset * 2..(offset + count) * 2)),
+            QMK_COMMAND_MATRIX_BRIGHTNESS => Some((&mut self.brightness, offset..offset + count)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_with_what_was_written() {
+        let config = Config::fixture("keyboard");
+        let mut firmware = VirtualFirmware::new(&config);
+        let report = |header: &[u8], data: &[u8]| {
+            let mut report = vec![0; 32];
+            report[..header.len()].copy_from_slice(header);
+            report[header.len()..header.len() + data.len()].copy_from_slice(data);
+            report
+        };
+
+        let set = [QMK_CUSTOM_SET_COMMAND, QMK_CUSTOM_CHANNEL];
+        let chroma = report(
+            &[&set[..], &[QMK_COMMAND_MATRIX_CHROMA, 2, 2]].concat(),
+            &[1, 2, 3, 4],
+        );
+        assert_eq!(firmware.answer(&chroma), None);
+        let get = [
+            QMK_CUSTOM_GET_COMMAND,
+            QMK_CUSTOM_CHANNEL,
+            QMK_COMMAND_MATRIX_CHROMA,
+            1,
+            3,
+        ];
+        let answer = firmware.answer(&report(&get, &[])).unwrap();
+        assert_eq!(answer[..11], [&get[..], &[0, 0, 1, 2, 3, 4]].concat());
+
+        let effect = [QMK_RGB_MATRIX_CHANNEL, QMK_COMMAND_EFFECT];
+        let answer = firmware
+            .answer(&report(&[QMK_CUSTOM_GET_COMMAND], &effect))
+            .unwrap();
+        assert_eq!(answer[3], config.effects[0].1 as u8);
+        firmware.answer(&report(
+            &[&[QMK_CUSTOM_SET_COMMAND], &effect[..]].concat(),
+            &[7],
+        ));
+        let answer = firmware
+            .answer(&report(&[QMK_CUSTOM_GET_COMMAND], &effect))
+            .unwrap();
+        assert_eq!(answer[3], 7);
+
+        // Requests out of range are left unanswered, like a keyboard would
+        let outside = [
+            QMK_CUSTOM_GET_COMMAND,
+            QMK_CUSTOM_CHANNEL,
+            QMK_COMMAND_MATRIX_CHROMA,
+            255,
+            13,
+        ];
+        assert_eq!(firmware.answer(&report(&outside, &[])), None);
+    }
+}
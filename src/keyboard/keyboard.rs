@@ -1,26 +1,32 @@
-use anyhow::{Result, anyhow};
-use async_hid::{Device, DeviceId};
+use anyhow::Result;
+use async_hid::Device;
 use futures::future::{self};
 use palette::{Hsv, IntoColor, encoding::Srgb, rgb::Rgb};
 use serde::{Deserialize, Serialize};
-use std::{borrow::Borrow, fmt};
+use std::{borrow::Borrow, collections::HashMap, fmt, mem, sync::Arc};
+use tokio::sync::Semaphore;
 
 use crate::{
-    config::Config,
+    config::{Config, ModeColor},
     consts::{
         QMK_COMMAND_BRIGHTNESS, QMK_COMMAND_COLOR, QMK_COMMAND_EFFECT,
         QMK_COMMAND_MATRIX_BRIGHTNESS, QMK_COMMAND_MATRIX_CHROMA, QMK_COMMAND_SPEED,
         QMK_CUSTOM_CHANNEL, QMK_CUSTOM_GET_COMMAND, QMK_CUSTOM_SAVE_COMMAND,
         QMK_CUSTOM_SET_COMMAND, QMK_KEYMAP_GET_COMMAND, QMK_RGB_MATRIX_CHANNEL,
     },
+    error::Error,
     keyboard::chunks::ChunkChanged,
     keyboard::device::KeyboardDevice,
+    keyboard::{KeyboardId, firmware::VirtualFirmware, script::scripted_device},
+    orp::SavedController,
+    report::Report,
 };
 
 pub struct KeyboardController {
     config: Config,
     keymap: Vec<u16>,
     device: KeyboardDevice<33>, // TODO: make this configurable
+    id: KeyboardId,
     state: KeyboardState,
 }
 
@@ -31,32 +37,315 @@ struct KeyboardState {
     brightness: u8,
     effect: u8,
     speed: u8,
+    #[serde(default)]
+    direction: u8,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    effects: HashMap<u8, EffectSettings>,
+    /// Hue and saturation of the additional mode colors declared by the definition
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mode_colors: Vec<(u8, u8)>,
+    /// Colors as clients sent them, since 8-bit HSV cannot represent every RGB value
+    #[serde(skip)]
+    requested: Vec<Option<Rgb<Srgb, u8>>>,
+}
+
+/// Slider values last used with an effect
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct EffectSettings {
+    color: (u8, u8),
+    brightness: u8,
+    speed: u8,
+}
+
+/// Raw per-LED state as stored by the server, for debugging color issues
+#[derive(Serialize, Debug)]
+pub struct StateDump {
+    pub name: String,
+    pub chroma: Vec<(u8, u8)>,
+    pub brightness: Vec<u8>,
+    pub rgb: Vec<(u8, u8, u8)>,
+}
+
+impl KeyboardState {
+    fn check_compatible(&self, state: &str) -> Result<()> {
+        let state: KeyboardState = serde_json::from_str(state)?;
+        if state.colors.0.len() != self.colors.0.len() {
+            return Err(Error::Protocol(format!(
+                "State has {} leds, but the keyboard has {}!",
+                state.colors.0.len(),
+                self.colors.0.len()
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, offset: usize, count: usize) -> Result<()> {
+        if offset + count > self.colors.0.len() {
+            return Err(Error::Protocol("Trying to update more leds than possible!".into()).into());
+        }
+        Ok(())
+    }
+
+    fn encode_colors(
+        &self,
+        config: &Config,
+        colors: Vec<Option<Rgb>>,
+        offset: usize,
+    ) -> (Vec<(u8, u8)>, Vec<u8>) {
+        let hsv_colors = colors.into_iter().enumerate().map(|(index, rgb)| {
+            let led = offset + index;
+            if let Some(rgb) = rgb {
+                let mut hsv: Hsv = config.correct_color(led, rgb).into_color();
+                hsv.value = hsv.value.powf(config.gamma);
+                return hsv.into_format::<u8>();
+            } else {
+                let (hue, saturation) = self.colors.0[led];
+                let brightness = self.colors.1[led];
+                return Hsv::from_components((hue, saturation, brightness));
+            }
+        });
+
+        // Converting every color once matters for large boards updated at a high frame rate
+        hsv_colors
+            .map(|x| ((x.hue.into(), x.saturation), x.value))
+            .unzip()
+    }
+
+    fn apply_colors(
+        &mut self,
+        offset: usize,
+        chroma: &[(u8, u8)],
+        brightness: &[u8],
+        with_brightness: bool,
+    ) {
+        self.colors.0[offset..offset + chroma.len()].copy_from_slice(chroma);
+        if with_brightness {
+            self.colors.1[offset..offset + brightness.len()].copy_from_slice(brightness);
+        }
+    }
+
+    /// Builds the reports for the chroma and brightness values that differ from the state
+    fn color_reports<const N: usize>(
+        &self,
+        template: Report<N>,
+        offset: usize,
+        chroma: &[(u8, u8)],
+        brightness: &[u8],
+        with_brightness: bool,
+        min_brightness: u8,
+    ) -> Result<Vec<Report<N>>> {
+        let chroma_reports = chroma
+            .chunk_changed((template.len() - 5) / 2, &self.colors.0[offset..])?
+            .map(|(local_offset, chunk)| {
+                let mut chroma_report = template.clone();
+                chroma_report[2] = QMK_COMMAND_MATRIX_CHROMA;
+                chroma_report[3] = led_offset(local_offset + offset);
+                chroma_report[4] = chunk.len() as u8;
+                chroma_report[5..(5 + chunk.len() * 2)].copy_from_slice(chunk.as_bytes());
+                return chroma_report;
+            });
+
+        let brightness_reports = brightness
+            .chunk_changed(template.len() - 5, &self.colors.1[offset..])?
+            .map(|(local_offset, chunk)| {
+                let mut brightness_report = template.clone();
+                brightness_report[2] = QMK_COMMAND_MATRIX_BRIGHTNESS;
+                brightness_report[3] = led_offset(local_offset + offset);
+                brightness_report[4] = chunk.len() as u8;
+                for (value, &requested) in brightness_report[5..].iter_mut().zip(chunk) {
+                    *value = requested.max(min_brightness);
+                }
+                return brightness_report;
+            });
+
+        let maybe_brightness_reports = with_brightness
+            .then_some(brightness_reports)
+            .into_iter()
+            .flatten();
+
+        Ok(chroma_reports.chain(maybe_brightness_reports).collect())
+    }
+
+    /// Builds the reports re-applying only the fields of a cached state that differ from this one
+    fn restore_reports<const N: usize>(
+        &self,
+        cached: &KeyboardState,
+        template: Report<N>,
+        with_brightness: bool,
+        min_brightness: u8,
+    ) -> Result<Vec<Report<N>>> {
+        let setting = |command: u8, values: &[u8]| {
+            let mut report = template.clone();
+            report[0] = QMK_CUSTOM_SET_COMMAND;
+            report[1] = QMK_RGB_MATRIX_CHANNEL;
+            report[2] = command;
+            report[3..3 + values.len()].copy_from_slice(values);
+            report
+        };
+
+        // The effect goes first, like when loading a state
+        let mut reports = Vec::new();
+        if cached.effect != self.effect {
+            reports.push(setting(QMK_COMMAND_EFFECT, &[cached.effect]));
+        }
+
+        let mut colors_template = template.clone();
+        colors_template[0] = QMK_CUSTOM_SET_COMMAND;
+        colors_template[1] = QMK_CUSTOM_CHANNEL;
+        reports.extend(self.color_reports(
+            colors_template,
+            0,
+            &cached.colors.0,
+            &cached.colors.1,
+            with_brightness,
+            min_brightness,
+        )?);
+
+        if cached.color != self.color {
+            reports.push(setting(
+                QMK_COMMAND_COLOR,
+                &[cached.color.0, cached.color.1],
+            ));
+        }
+        if cached.speed != self.speed {
+            reports.push(setting(QMK_COMMAND_SPEED, &[cached.speed]));
+        }
+        if cached.brightness != self.brightness {
+            let brightness = cached.brightness.max(min_brightness);
+            reports.push(setting(QMK_COMMAND_BRIGHTNESS, &[brightness]));
+        }
+
+        Ok(reports)
+    }
+
+    fn remember_effect(&mut self) {
+        let settings = EffectSettings {
+            color: self.color,
+            brightness: self.brightness,
+            speed: self.speed,
+        };
+        self.effects.insert(self.effect, settings);
+    }
+
+    /// Remembers the settings of the current effect and returns the ones last used with the new one
+    fn switch_effect(&mut self, effect: u8) -> Option<EffectSettings> {
+        self.remember_effect();
+        self.effect = effect;
+        self.effects.get(&effect).copied()
+    }
+
+    fn colors(&self) -> Vec<Rgb<Srgb, u8>> {
+        let colors = self.colors.0.iter().zip(&self.colors.1).map(|((h, s), v)| {
+            let rgb: Rgb = Hsv::new(*h, *s, *v).into_format().into_color();
+            return rgb.into_format();
+        });
+
+        return colors.collect();
+    }
+
+    fn remember_requested(&mut self, offset: usize, colors: &[Option<Rgb<Srgb, u8>>]) {
+        if self.requested.len() < offset + colors.len() {
+            self.requested.resize(offset + colors.len(), None);
+        }
+        for (cached, color) in self.requested[offset..].iter_mut().zip(colors) {
+            if color.is_some() {
+                *cached = *color;
+            }
+        }
+    }
+
+    /// The colors last sent by clients, falling back to the firmware state for the other LEDs
+    fn requested_colors(&self) -> Vec<Rgb<Srgb, u8>> {
+        let mut colors = self.colors();
+        for (color, requested) in colors.iter_mut().zip(&self.requested) {
+            if let Some(requested) = requested {
+                *color = *requested;
+            }
+        }
+        colors
+    }
+
+    fn dump(&self, name: &str) -> StateDump {
+        StateDump {
+            name: name.to_string(),
+            chroma: self.colors.0.clone(),
+            brightness: self.colors.1.clone(),
+            rgb: self
+                .colors()
+                .into_iter()
+                .map(|x| (x.red, x.green, x.blue))
+                .collect(),
+        }
+    }
 }
 
 impl KeyboardController {
-    pub async fn from_config(config: Config, device: Device) -> Result<KeyboardController> {
-        let device = KeyboardDevice::from_device(device).await?;
+    pub async fn from_config(
+        mut config: Config,
+        device: Device,
+        requests: Arc<Semaphore>,
+    ) -> Result<KeyboardController> {
+        config.serial = device.serial_number.clone();
+        let id = KeyboardId::Device(device.id.clone());
+        let device = KeyboardDevice::from_device(device, config.report_timeout, requests).await?;
+        KeyboardController::load(config, device, id).await
+    }
+
+    /// Simulates the keyboard of a definition with a firmware kept in memory
+    pub async fn from_virtual(
+        config: Config,
+        index: usize,
+        requests: Arc<Semaphore>,
+    ) -> Result<KeyboardController> {
+        let mut firmware = VirtualFirmware::new(&config);
+        let script = move |report: &[u8]| firmware.answer(report);
+        let device = scripted_device(script, config.report_timeout, requests);
+        KeyboardController::load(config, device, KeyboardId::Virtual(index)).await
+    }
+
+    pub(super) async fn load(
+        config: Config,
+        device: KeyboardDevice<33>,
+        id: KeyboardId,
+    ) -> Result<KeyboardController> {
         let leds = config.count_leds() as usize;
 
-        let (keymap, colors, color, effect, speed, brightness) = tokio::try_join!(
+        let has_brightness = config.has_brightness();
+        let (keymap, colors, (color, mut effect, mut speed, mut brightness), mode_colors) = tokio::try_join!(
             KeyboardController::load_keymap(&device, (config.matrix.0 * config.matrix.1) as usize),
-            KeyboardController::load_colors(&device, leds),
-            KeyboardController::load_color(&device),
-            KeyboardController::load_effect(&device),
-            KeyboardController::load_speed(&device),
-            KeyboardController::load_brightness(&device),
+            KeyboardController::load_colors(&device, leds, has_brightness),
+            KeyboardController::load_effect_state(&device, config.direct_only, has_brightness),
+            KeyboardController::load_mode_colors(&device, &config.mode_colors, config.direct_only),
         )?;
 
+        // Nothing is read from direct only boards, so the defaults are the best guess
+        if config.direct_only {
+            speed = config.speed_hints.default_value();
+            brightness = config.brightness_hints.default_value();
+        }
+        if !has_brightness {
+            brightness = u8::MAX;
+        }
+        if config.synthetic_direct {
+            effect = 0;
+        }
+
         Ok(KeyboardController {
             config,
             keymap,
             device,
+            id,
             state: KeyboardState {
                 colors,
                 color,
                 brightness,
                 effect,
                 speed,
+                direction: 0,
+                effects: HashMap::new(),
+                mode_colors,
+                requested: Vec::new(),
             },
         })
     }
@@ -74,10 +363,10 @@ impl KeyboardController {
         report_template[2] = QMK_COMMAND_MATRIX_BRIGHTNESS;
 
         let handles: Vec<_> = vec![255u8; self.state.colors.1.len()]
-            .chunk_changed(report_template.len() - 5, &self.state.colors.1)
+            .chunk_changed(report_template.len() - 5, &self.state.colors.1)?
             .map(|(local_offset, chunk)| {
                 let mut report = report_template.clone();
-                report[3] = local_offset as u8;
+                report[3] = led_offset(local_offset);
                 report[4] = chunk.len() as u8;
                 report[5..(5 + chunk.len())].copy_from_slice(chunk);
                 return report;
@@ -86,6 +375,8 @@ impl KeyboardController {
             .collect();
 
         future::join_all(handles).await;
+        self.state.colors.1.fill(255);
+        self.state.requested.clear();
         Ok(())
     }
 
@@ -95,121 +386,152 @@ impl KeyboardController {
         offset: usize,
         with_brightness: bool,
     ) -> Result<()> {
-        if offset + colors.len() > self.state.colors.0.len() {
-            return Err(anyhow!("Trying to update more leds than possible!"));
-        }
+        self.state.check_range(offset, colors.len())?;
 
-        let hsv_colors = colors.into_iter().map(|rgb| {
-            if let Some(rgb) = rgb {
-                let hsv: Hsv = rgb.into_color();
-                return hsv.into_format::<u8>();
-            } else {
-                let (hue, saturation) = self.state.colors.0[offset];
-                let brightness = self.state.colors.1[offset];
-                return Hsv::from_components((hue, saturation, brightness));
-            }
-        });
+        let (colors, offset) = self.config.remap_colors(colors, offset);
+        self.write_colors(colors, offset, with_brightness).await
+    }
 
-        let brightness: Vec<_> = hsv_colors.clone().map(|x| x.value).collect();
-        let chroma: Vec<_> = hsv_colors.map(|x| (x.hue.into(), x.saturation)).collect();
+    /// Writes colors addressed by firmware LED indices
+    async fn write_colors(
+        &mut self,
+        colors: Vec<Option<Rgb>>,
+        offset: usize,
+        with_brightness: bool,
+    ) -> Result<()> {
+        self.state.check_range(offset, colors.len())?;
+        let requested: Vec<_> = colors.iter().map(|x| x.map(|x| x.into_format())).collect();
+        let (chroma, brightness) = self.state.encode_colors(&self.config, colors, offset);
+
+        let with_brightness = self.config.writes_brightness(with_brightness);
 
         let mut report_template = self.device.create_report();
         report_template[0] = QMK_CUSTOM_SET_COMMAND;
         report_template[1] = QMK_CUSTOM_CHANNEL;
 
-        let chroma_reports = chroma
-            .chunk_changed(
-                (report_template.len() - 5) / 2,
-                &self.state.colors.0[offset..],
-            )
-            .map(|(local_offset, chunk)| {
-                let mut chroma_report = report_template.clone();
-                chroma_report[2] = QMK_COMMAND_MATRIX_CHROMA;
-                chroma_report[3] = (local_offset + offset) as u8;
-                chroma_report[4] = chunk.len() as u8;
-                chroma_report[5..(5 + chunk.len() * 2)].copy_from_slice(chunk.as_bytes());
-                return chroma_report;
-            });
-
-        let brightness_reports = brightness
-            .chunk_changed(report_template.len() - 5, &self.state.colors.1[offset..])
-            .map(|(local_offset, chunk)| {
-                let mut brightness_report = report_template.clone();
-                brightness_report[2] = QMK_COMMAND_MATRIX_BRIGHTNESS;
-                brightness_report[3] = (local_offset + offset) as u8;
-                brightness_report[4] = chunk.len() as u8;
-                brightness_report[5..(5 + chunk.len())].copy_from_slice(chunk);
-                return brightness_report;
-            });
-
-        let maybe_brightness_reports = with_brightness
-            .then_some(brightness_reports)
-            .into_iter()
-            .flatten();
+        let reports = self.state.color_reports(
+            report_template,
+            offset,
+            &chroma,
+            &brightness,
+            with_brightness,
+            self.config.min_brightness,
+        )?;
 
         let device = &self.device;
-        let handles: Vec<_> = chroma_reports
-            .chain(maybe_brightness_reports)
+        let handles: Vec<_> = reports
+            .into_iter()
             .map(|report| async move { device.send_report(report).await })
             .collect();
 
-        self.state.colors.0[offset..offset + chroma.len()].copy_from_slice(&chroma);
-        if with_brightness {
-            self.state.colors.1[offset..offset + brightness.len()].copy_from_slice(&brightness);
-        }
+        self.state
+            .apply_colors(offset, &chroma, &brightness, with_brightness);
+        self.state.remember_requested(offset, &requested);
 
         future::try_join_all(handles).await?;
         Ok(())
     }
 
     pub fn colors(&self) -> Vec<Rgb<Srgb, u8>> {
-        let colors = self
-            .state
-            .colors
-            .0
-            .iter()
-            .zip(&self.state.colors.1)
-            .map(|((h, s), v)| {
-                let rgb: Rgb = Hsv::new(*h, *s, *v).into_format().into_color();
-                return rgb.into_format();
-            });
+        self.config.unmap_colors(&self.state.requested_colors())
+    }
 
-        return colors.collect();
+    pub fn dump_state(&self) -> StateDump {
+        self.state.dump(&self.config.name)
     }
 
     pub async fn update_color(&mut self, color: Rgb<Srgb, u8>) -> Result<()> {
-        let hsv: Hsv = color.into_format().into_color();
-        let hsv = hsv.into_format::<u8>();
+        self.write_color(to_chroma(color)).await
+    }
 
-        if hsv.hue != self.state.color.0 || hsv.saturation != self.state.color.1 {
-            self.state.color = (hsv.hue.into(), hsv.saturation);
+    async fn write_color(&mut self, color: (u8, u8)) -> Result<()> {
+        if color != self.state.color {
+            self.state.color = color;
             let mut report = self.device.create_report();
             report[0] = QMK_CUSTOM_SET_COMMAND;
             report[1] = QMK_RGB_MATRIX_CHANNEL;
             report[2] = QMK_COMMAND_COLOR;
-            report[3] = hsv.hue.into();
-            report[4] = hsv.saturation;
+            report[3] = color.0;
+            report[4] = color.1;
             self.device.send_report(report).await?;
+            self.remember_effect();
         }
         Ok(())
     }
 
     pub fn color(&self) -> Rgb<Srgb, u8> {
-        let rgb: Rgb = Hsv::new(self.state.color.0, self.state.color.1, 255)
-            .into_format()
-            .into_color();
-        return rgb.into_format();
+        from_chroma(self.state.color)
+    }
+
+    /// Sets the additional mode colors by their index in the definition, `None` keeps a color
+    pub async fn update_mode_colors(&mut self, colors: Vec<Option<Rgb<Srgb, u8>>>) -> Result<()> {
+        let controls = self.config.mode_colors.iter();
+        for (index, (control, color)) in controls.zip(colors).enumerate() {
+            let Some(color) = color.map(to_chroma) else {
+                continue;
+            };
+            if self.state.mode_colors.get(index) == Some(&color) {
+                continue;
+            }
+
+            let mut report = self.device.create_report();
+            report[0] = QMK_CUSTOM_SET_COMMAND;
+            report[1] = control.channel;
+            report[2] = control.value_id;
+            report[3] = color.0;
+            report[4] = color.1;
+            self.device.send_report(report).await?;
+
+            if self.state.mode_colors.len() <= index {
+                self.state.mode_colors.resize(index + 1, (0, 0));
+            }
+            self.state.mode_colors[index] = color;
+        }
+        Ok(())
+    }
+
+    pub fn mode_colors(&self) -> Vec<Rgb<Srgb, u8>> {
+        let mut colors: Vec<_> = self
+            .state
+            .mode_colors
+            .iter()
+            .copied()
+            .map(from_chroma)
+            .collect();
+        colors.resize(self.config.mode_colors.len(), from_chroma((0, 0)));
+        colors
     }
 
     pub async fn update_effect(&mut self, effect: u8) -> Result<()> {
+        // A made up direct mode is the only one, so there is nothing to switch to
+        if self.config.synthetic_direct {
+            return Ok(());
+        }
         if effect != self.state.effect {
-            self.state.effect = effect;
+            let clear = self.config.clears_colors(self.state.effect, effect);
+            let settings = if self.config.remember_effect_settings {
+                self.state.switch_effect(effect)
+            } else {
+                self.state.effect = effect;
+                None
+            };
             let mut report = self.device.create_report();
             report[0] = QMK_CUSTOM_SET_COMMAND;
             report[1] = QMK_RGB_MATRIX_CHANNEL;
             report[2] = QMK_COMMAND_EFFECT;
             report[3] = effect;
             self.device.send_report(report).await?;
+
+            if clear {
+                let black = vec![Some(Rgb::new(0.0, 0.0, 0.0)); self.state.colors.0.len()];
+                self.write_colors(black, 0, true).await?;
+            }
+
+            if let Some(settings) = settings {
+                self.write_color(settings.color).await?;
+                self.update_speed(settings.speed).await?;
+                self.update_brightness(settings.brightness).await?;
+            }
         }
         Ok(())
     }
@@ -219,6 +541,7 @@ impl KeyboardController {
     }
 
     pub async fn update_speed(&mut self, speed: u8) -> Result<()> {
+        let speed = self.config.speed_hints.snap(self.config.speed, speed);
         if speed != self.state.speed {
             self.state.speed = speed;
             let mut report = self.device.create_report();
@@ -227,6 +550,7 @@ impl KeyboardController {
             report[2] = QMK_COMMAND_SPEED;
             report[3] = speed;
             self.device.send_report(report).await?;
+            self.remember_effect();
         }
         Ok(())
     }
@@ -235,15 +559,48 @@ impl KeyboardController {
         self.state.speed
     }
 
+    /// Sets an OpenRGB direction, if the definition maps it to a firmware value
+    pub async fn update_direction(&mut self, direction: u8) -> Result<()> {
+        let Some(control) = &self.config.direction else {
+            return Ok(());
+        };
+        let Some(value) = control.firmware_value(direction) else {
+            return Ok(());
+        };
+
+        if direction != self.state.direction {
+            self.state.direction = direction;
+            let mut report = self.device.create_report();
+            report[0] = QMK_CUSTOM_SET_COMMAND;
+            report[1] = control.channel;
+            report[2] = control.value_id;
+            report[3] = value;
+            self.device.send_report(report).await?;
+        }
+        Ok(())
+    }
+
+    pub fn direction(&self) -> u8 {
+        self.state.direction
+    }
+
     pub async fn update_brightness(&mut self, brightness: u8) -> Result<()> {
+        if !self.config.has_brightness() {
+            return Ok(());
+        }
+        let brightness = self
+            .config
+            .brightness_hints
+            .snap(self.config.brightness, brightness);
         if brightness != self.state.brightness {
             self.state.brightness = brightness;
             let mut report = self.device.create_report();
             report[0] = QMK_CUSTOM_SET_COMMAND;
             report[1] = QMK_RGB_MATRIX_CHANNEL;
             report[2] = QMK_COMMAND_BRIGHTNESS;
-            report[3] = brightness;
+            report[3] = brightness.max(self.config.min_brightness);
             self.device.send_report(report).await?;
+            self.remember_effect();
         }
         Ok(())
     }
@@ -252,6 +609,12 @@ impl KeyboardController {
         self.state.brightness
     }
 
+    fn remember_effect(&mut self) {
+        if self.config.remember_effect_settings {
+            self.state.remember_effect();
+        }
+    }
+
     pub fn config(&self) -> &Config {
         &self.config
     }
@@ -260,6 +623,57 @@ impl KeyboardController {
         serde_json::to_string(&self.state).map_err(|x| x.into())
     }
 
+    /// Converts a device saved in an OpenRGB profile into a state for `load_state`
+    pub fn import_profile(&self, saved: &SavedController) -> Result<String> {
+        let leds = self.state.colors.0.len();
+        if saved.colors.len() != leds {
+            return Err(Error::Protocol(format!(
+                "Profile has {} leds, but the keyboard has {leds}!",
+                saved.colors.len()
+            ))
+            .into());
+        }
+
+        let mode = saved.active_mode()?;
+        let colors = saved.colors.iter().map(|x| Some(x.into_format())).collect();
+        // States are in firmware order, with the gamma that `load_state` undoes
+        let (colors, _) = self.config.remap_colors(colors, 0);
+        let colors = colors.into_iter().flatten().map(|rgb| {
+            let mut hsv: Hsv = rgb.into_color();
+            hsv.value = hsv.value.powf(self.config.gamma);
+            let hsv = hsv.into_format::<u8>();
+            ((hsv.hue.into(), hsv.saturation), hsv.value)
+        });
+
+        let mut mode_colors = self.state.mode_colors.clone();
+        let extra = self.config.effect_colors(mode.value);
+        for (index, color) in extra.into_iter().zip(mode.colors.iter().skip(1)) {
+            if let Some(saved) = mode_colors.get_mut(index) {
+                *saved = to_chroma(*color);
+            }
+        }
+
+        let state = KeyboardState {
+            colors: colors.unzip(),
+            color: mode
+                .colors
+                .first()
+                .map_or(self.state.color, |x| to_chroma(*x)),
+            brightness: mode.brightness.map_or(self.state.brightness, |x| x as u8),
+            effect: mode.value as u8,
+            speed: mode.speed as u8,
+            direction: mode.direction as u8,
+            effects: HashMap::new(),
+            mode_colors,
+            requested: Vec::new(),
+        };
+        serde_json::to_string(&state).map_err(|x| x.into())
+    }
+
+    pub fn check_state(&self, state: &str) -> Result<()> {
+        self.state.check_compatible(state)
+    }
+
     pub async fn load_state(&mut self, state: &str, with_brightness: bool) -> Result<()> {
         let state: KeyboardState = serde_json::from_str(state)?;
         let colors: Vec<Option<Rgb>> = state
@@ -268,26 +682,64 @@ impl KeyboardController {
             .iter()
             .zip(state.colors.1)
             .map(|(chroma, brightness)| {
-                return Some(
-                    Hsv::new(chroma.0, chroma.1, brightness)
-                        .into_format::<f32>()
-                        .into_color(),
-                );
+                let mut hsv = Hsv::new(chroma.0, chroma.1, brightness).into_format::<f32>();
+                // Saved states hold the brightness sent to the firmware, which is written again
+                hsv.value = hsv.value.powf(self.config.gamma.recip());
+                return Some(hsv.into_color());
             })
             .collect();
 
-        let color: Rgb = Hsv::new(state.color.0, state.color.1, 255)
-            .into_format()
-            .into_color();
-
-        self.update_colors(colors, 0, with_brightness).await?;
-        self.update_color(color.into_format()).await?;
+        // The effect goes first, so leaving the custom mode does not clear the loaded colors
         self.update_effect(state.effect).await?;
+        self.write_colors(colors, 0, with_brightness).await?;
+        self.write_color(state.color).await?;
+        let mode_colors = state.mode_colors.into_iter();
+        self.update_mode_colors(mode_colors.map(|x| Some(from_chroma(x))).collect())
+            .await?;
         self.update_speed(state.speed).await?;
+        self.update_direction(state.direction).await?;
         self.update_brightness(state.brightness).await?;
+        if self.config.remember_effect_settings {
+            self.state.effects.extend(state.effects);
+        }
         Ok(())
     }
 
+    /// Re-applies a state cached before the keyboard reconnected. Boards that kept their state
+    /// in EEPROM already match it, so only the fields the firmware lost are written
+    pub async fn restore_state(&mut self, state: &str) -> Result<()> {
+        self.state.check_compatible(state)?;
+        let cached: KeyboardState = serde_json::from_str(state)?;
+        let with_brightness = self.config.writes_brightness(true);
+
+        let reports = self.state.restore_reports(
+            &cached,
+            self.device.create_report(),
+            with_brightness,
+            self.config.min_brightness,
+        )?;
+        for report in reports {
+            self.device.send_report(report).await?;
+        }
+
+        let direction = cached.direction;
+        let mode_colors = cached.mode_colors.iter().map(|x| Some(from_chroma(*x)));
+        let mode_colors = mode_colors.collect();
+        let brightness = if with_brightness {
+            cached.colors.1
+        } else {
+            mem::take(&mut self.state.colors.1)
+        };
+        self.state = KeyboardState {
+            colors: (cached.colors.0, brightness),
+            direction: self.state.direction,
+            mode_colors: mem::take(&mut self.state.mode_colors),
+            ..cached
+        };
+        self.update_direction(direction).await?;
+        self.update_mode_colors(mode_colors).await
+    }
+
     pub async fn persist_state(&mut self) -> Result<()> {
         let mut report = self.device.create_report();
         report[0] = QMK_CUSTOM_SAVE_COMMAND;
@@ -296,17 +748,22 @@ impl KeyboardController {
         Ok(())
     }
 
-    pub fn device_id(&self) -> &DeviceId {
-        &self.device.id
+    pub fn device_id(&self) -> &KeyboardId {
+        &self.id
     }
 
-    pub fn into_config(self) -> Config {
-        self.config
+    /// Reads the keymap again, as it may be changed with VIA while the server runs
+    pub async fn reload_keymap(&mut self) -> Result<()> {
+        let count = (self.config.matrix.0 * self.config.matrix.1) as usize;
+        self.keymap = KeyboardController::load_keymap(&self.device, count).await?;
+        Ok(())
     }
 
+    /// Reads the per-LED colors, where LEDs of boards without brightness are at full brightness
     async fn load_colors<const N: usize>(
         device: &KeyboardDevice<N>,
         count: usize,
+        with_brightness: bool,
     ) -> Result<(Vec<(u8, u8)>, Vec<u8>)> {
         let mut colors = (vec![(0, 0); count], vec![255; count]);
 
@@ -319,17 +776,21 @@ impl KeyboardController {
         let chroma_reports = (0..chroma_chunks).map(|i| {
             let mut chroma_report = report_template.clone();
             chroma_report[2] = QMK_COMMAND_MATRIX_CHROMA;
-            chroma_report[3] = (i * chroma_chunk_size) as u8;
+            chroma_report[3] = led_offset(i * chroma_chunk_size);
             chroma_report[4] = chroma_chunk_size.min(count - i * chroma_chunk_size) as u8;
             return chroma_report;
         });
 
         let brightness_chunk_size: usize = report_template.len() - 5;
-        let brightness_chunks = (count as f32 / brightness_chunk_size as f32).ceil() as usize;
+        let brightness_chunks = if with_brightness {
+            (count as f32 / brightness_chunk_size as f32).ceil() as usize
+        } else {
+            0
+        };
         let brightness_reports = (0..brightness_chunks).map(|i| {
             let mut brightness_report = report_template.clone();
             brightness_report[2] = QMK_COMMAND_MATRIX_BRIGHTNESS;
-            brightness_report[3] = (i * brightness_chunk_size) as u8;
+            brightness_report[3] = led_offset(i * brightness_chunk_size);
             brightness_report[4] =
                 brightness_chunk_size.min(count - i * brightness_chunk_size) as u8;
             return brightness_report;
@@ -358,6 +819,51 @@ impl KeyboardController {
         Ok(colors)
     }
 
+    async fn load_effect_state<const N: usize>(
+        device: &KeyboardDevice<N>,
+        direct_only: bool,
+        has_brightness: bool,
+    ) -> Result<((u8, u8), u8, u8, u8)> {
+        // Boards with partial VIA support may not answer these at all
+        if direct_only {
+            return Ok(((0, 0), 0, 0, 0));
+        }
+
+        let brightness = async {
+            if has_brightness {
+                KeyboardController::load_brightness(device).await
+            } else {
+                Ok(u8::MAX)
+            }
+        };
+        tokio::try_join!(
+            KeyboardController::load_color(device),
+            KeyboardController::load_effect(device),
+            KeyboardController::load_speed(device),
+            brightness,
+        )
+    }
+
+    async fn load_mode_colors<const N: usize>(
+        device: &KeyboardDevice<N>,
+        controls: &[ModeColor],
+        direct_only: bool,
+    ) -> Result<Vec<(u8, u8)>> {
+        if direct_only {
+            return Ok(vec![(0, 0); controls.len()]);
+        }
+
+        future::try_join_all(controls.iter().map(|control| async move {
+            let mut report = device.create_report();
+            report[0] = QMK_CUSTOM_GET_COMMAND;
+            report[1] = control.channel;
+            report[2] = control.value_id;
+            let response = device.request_report(report, 3).await?;
+            Ok((response[3], response[4]))
+        }))
+        .await
+    }
+
     async fn load_color<const N: usize>(device: &KeyboardDevice<N>) -> Result<(u8, u8)> {
         let mut report = device.create_report();
         report[0] = QMK_CUSTOM_GET_COMMAND;
@@ -448,6 +954,23 @@ impl fmt::Debug for KeyboardController {
     }
 }
 
+/// Hue and saturation of a color, as the firmware stores effect colors
+fn to_chroma(color: Rgb<Srgb, u8>) -> (u8, u8) {
+    let hsv: Hsv = color.into_format().into_color();
+    let hsv = hsv.into_format::<u8>();
+    (hsv.hue.into(), hsv.saturation)
+}
+
+fn from_chroma(chroma: (u8, u8)) -> Rgb<Srgb, u8> {
+    let rgb: Rgb = Hsv::new(chroma.0, chroma.1, 255).into_format().into_color();
+    rgb.into_format()
+}
+
+/// Offsets of matrix reports are single bytes, definitions addressing more LEDs are rejected
+fn led_offset(offset: usize) -> u8 {
+    u8::try_from(offset).expect("LED offsets must fit into a byte!")
+}
+
 trait AsBytes {
     fn as_bytes(&self) -> &[u8];
     fn as_bytes_mut(&mut self) -> &mut [u8];
@@ -466,3 +989,441 @@ impl AsBytes for [(u8, u8)] {
         unsafe { std::slice::from_raw_parts_mut(ptr, len) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::QMK_REQUEST_ATTEMPTS, keyboard::mock::mock_device};
+
+    fn state(leds: usize) -> KeyboardState {
+        KeyboardState {
+            colors: (vec![(0, 0); leds], vec![255; leds]),
+            color: (0, 0),
+            brightness: 255,
+            effect: 0,
+            speed: 0,
+            direction: 0,
+            effects: HashMap::new(),
+            mode_colors: Vec::new(),
+            requested: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn accepts_states_with_matching_leds() {
+        let saved = serde_json::to_string(&state(6)).unwrap();
+        assert!(state(6).check_compatible(&saved).is_ok());
+        assert!(state(5).check_compatible(&saved).is_err());
+        assert!(state(6).check_compatible("{}").is_err());
+    }
+
+    #[test]
+    fn rejects_updates_past_the_end() {
+        let state = state(6);
+        assert!(state.check_range(0, 6).is_ok());
+        assert!(state.check_range(4, 2).is_ok());
+        assert!(state.check_range(5, 2).is_err());
+        assert!(state.check_range(7, 0).is_err());
+    }
+
+    #[test]
+    fn skips_brightness_reports_when_disabled() {
+        let mut config = Config::fixture("keyboard");
+        let state = state(config.count_leds() as usize);
+        let (chroma, brightness) =
+            state.encode_colors(&config, vec![Some(Rgb::new(0., 0.5, 0.)); 6], 0);
+        let brightness_reports = |with_brightness| {
+            let reports = state
+                .color_reports(
+                    Report::<33>::new(),
+                    0,
+                    &chroma,
+                    &brightness,
+                    with_brightness,
+                    0,
+                )
+                .unwrap();
+            reports
+                .iter()
+                .filter(|x| x[2] == QMK_COMMAND_MATRIX_BRIGHTNESS)
+                .count()
+        };
+
+        assert!(brightness_reports(config.writes_brightness(true)) > 0);
+        config.no_per_led_brightness = true;
+        assert_eq!(brightness_reports(config.writes_brightness(true)), 0);
+    }
+
+    #[test]
+    fn precise_colors_lose_less() {
+        let config = Config::fixture("keyboard");
+        let state = state(config.count_leds() as usize);
+        let gradient: Vec<Rgb> = (0..6)
+            .map(|i| Rgb::new(0.9 - i as f32 * 0.137, 0.3 + i as f32 * 0.091, 0.503))
+            .collect();
+
+        let loss = |colors: Vec<Rgb>| {
+            let (chroma, brightness) =
+                state.encode_colors(&config, colors.into_iter().map(Some).collect(), 0);
+            chroma
+                .into_iter()
+                .zip(brightness)
+                .zip(&gradient)
+                .map(|(((hue, saturation), value), expected)| {
+                    let rgb: Rgb = Hsv::new(hue, saturation, value)
+                        .into_format::<f32>()
+                        .into_color();
+                    (rgb.red - expected.red).abs()
+                        + (rgb.green - expected.green).abs()
+                        + (rgb.blue - expected.blue).abs()
+                })
+                .sum::<f32>()
+        };
+
+        // Standard requests carry 8-bit RGB, which gets quantized again as HSV
+        let standard = gradient
+            .iter()
+            .map(|x| x.into_format::<u8>().into_format())
+            .collect();
+        let precise = gradient.clone();
+
+        assert!(loss(precise) < loss(standard));
+    }
+
+    #[test]
+    fn clears_custom_colors_on_effect_switch() {
+        let mut config = Config::fixture("keyboard");
+        let mut state = state(config.count_leds() as usize);
+        let red = Some(Rgb::new(1., 0., 0.));
+        let (chroma, brightness) = state.encode_colors(&config, vec![red; 6], 0);
+        state.apply_colors(0, &chroma, &brightness, true);
+
+        // Effect 3 is the per-LED custom mode in the fixture
+        assert!(!config.clears_colors(3, 1));
+        config.clear_custom_colors = true;
+        assert!(config.clears_colors(3, 1));
+        assert!(!config.clears_colors(1, 3));
+
+        let black = Some(Rgb::new(0., 0., 0.));
+        let (chroma, brightness) = state.encode_colors(&config, vec![black; 6], 0);
+        state.apply_colors(0, &chroma, &brightness, true);
+        assert_eq!(state.colors(), vec![Rgb::new(0, 0, 0); 6]);
+    }
+
+    #[test]
+    fn keeps_brightness_above_the_floor() {
+        let config = Config::fixture("keyboard");
+        let mut state = state(config.count_leds() as usize);
+        let black = Some(Rgb::new(0., 0., 0.));
+        let (chroma, brightness) = state.encode_colors(&config, vec![black; 6], 0);
+
+        let reports = state
+            .color_reports(Report::<33>::new(), 0, &chroma, &brightness, true, 20)
+            .unwrap();
+        let report = reports
+            .iter()
+            .find(|x| x[2] == QMK_COMMAND_MATRIX_BRIGHTNESS)
+            .unwrap();
+        assert_eq!(report[5..11], [20; 6]);
+
+        state.apply_colors(0, &chroma, &brightness, true);
+        assert_eq!(state.dump(&config.name).brightness, [0; 6]);
+    }
+
+    #[test]
+    fn restores_settings_of_each_effect() {
+        let mut state = state(6);
+        state.effect = 1;
+        state.speed = 10;
+        state.brightness = 100;
+        state.remember_effect();
+        assert_eq!(state.switch_effect(2), None);
+
+        state.speed = 200;
+        state.color = (85, 255);
+        state.remember_effect();
+
+        let first = state.switch_effect(1).unwrap();
+        assert_eq!((first.speed, first.brightness), (10, 100));
+        assert_eq!(first.color, (0, 0));
+        let second = state.switch_effect(2).unwrap();
+        assert_eq!((second.speed, second.color), (200, (85, 255)));
+    }
+
+    #[test]
+    fn restores_only_what_the_firmware_lost() {
+        let mut fresh = state(6);
+        fresh.effect = 3;
+        fresh.colors.0[2] = (85, 255);
+        let cached: KeyboardState =
+            serde_json::from_str(&serde_json::to_string(&fresh).unwrap()).unwrap();
+
+        let reports = |fresh: &KeyboardState| {
+            fresh
+                .restore_reports(&cached, Report::<33>::new(), true, 0)
+                .unwrap()
+        };
+        assert!(reports(&fresh).is_empty());
+
+        // Firmware without EEPROM comes back with its defaults
+        let reset = state(6);
+        let reports = reports(&reset);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0][2..4], [QMK_COMMAND_EFFECT, 3]);
+        assert_eq!(reports[1][2..5], [QMK_COMMAND_MATRIX_CHROMA, 2, 1]);
+    }
+
+    #[test]
+    fn reads_back_requested_colors_exactly() {
+        let config = Config::fixture("keyboard");
+        let mut state = state(config.count_leds() as usize);
+        let pink: Rgb<Srgb, u8> = Rgb::new(250, 3, 120);
+        let (chroma, brightness) = state.encode_colors(&config, vec![Some(pink.into_format())], 4);
+        state.apply_colors(4, &chroma, &brightness, true);
+        assert_ne!(state.colors()[4], pink);
+
+        state.remember_requested(4, &[Some(pink)]);
+        state.remember_requested(0, &[None; 6]);
+        assert_eq!(state.requested_colors()[4], pink);
+        assert_eq!(state.requested_colors()[..4], state.colors()[..4]);
+    }
+
+    #[test]
+    fn corrects_only_the_brightness_with_gamma() {
+        let mut config = Config::fixture("keyboard");
+        let state = state(config.count_leds() as usize);
+        let dim = vec![Some(Rgb::new(0.5, 0.25, 0.0))];
+        let (chroma, brightness) = state.encode_colors(&config, dim.clone(), 0);
+        assert_eq!(brightness, [128]);
+
+        config.gamma = 2.0;
+        let corrected = state.encode_colors(&config, dim, 0);
+        assert_eq!(corrected, (chroma, vec![64]));
+    }
+
+    #[test]
+    fn dumps_written_hsv() {
+        let config = Config::fixture("keyboard");
+        let mut state = state(config.count_leds() as usize);
+
+        let red = Some(Rgb::new(1., 0., 0.));
+        let dim_green = Some(Rgb::new(0., 0.5, 0.));
+        let (chroma, brightness) = state.encode_colors(&config, vec![red, None, dim_green], 1);
+        state.apply_colors(1, &chroma, &brightness, true);
+
+        let dump = state.dump(&config.name);
+        assert_eq!(dump.name, "Test Keyboard");
+        assert_eq!(dump.chroma[1..4], chroma);
+        assert_eq!(dump.brightness[1..4], brightness);
+        assert_eq!(dump.chroma[1], (0, 255));
+        assert_eq!(dump.chroma[2], (0, 0));
+        assert_eq!(dump.brightness[3], 128);
+        assert_eq!(dump.rgb[1], (255, 0, 0));
+        assert_eq!(dump.rgb[2], (255, 255, 255));
+    }
+
+    /// Answers reads of the per-LED colors with the LED index as the hue and twice as the value
+    fn answer_colors(request: &[u8]) -> Option<Vec<u8>> {
+        if request[..2] != [QMK_CUSTOM_GET_COMMAND, QMK_CUSTOM_CHANNEL] {
+            return None;
+        }
+        let mut answer = request.to_vec();
+        let (offset, count) = (request[3], request[4]);
+        for (i, led) in (offset..offset + count).enumerate() {
+            if request[2] == QMK_COMMAND_MATRIX_BRIGHTNESS {
+                answer[5 + i] = led * 2;
+            } else {
+                answer[5 + i * 2..7 + i * 2].copy_from_slice(&[led, 255]);
+            }
+        }
+        Some(answer)
+    }
+
+    #[tokio::test]
+    async fn loads_colors_in_chunks() {
+        let (device, written) = mock_device::<33>(answer_colors);
+        let (chroma, brightness) = KeyboardController::load_colors(&device, 20, true)
+            .await
+            .unwrap();
+
+        assert_eq!(chroma, (0..20).map(|x| (x, 255)).collect::<Vec<_>>());
+        assert_eq!(brightness, (0..20).map(|x| x * 2).collect::<Vec<_>>());
+        let chunks: Vec<_> = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|x| x[2..5].to_vec())
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                [QMK_COMMAND_MATRIX_CHROMA, 0, 13],
+                [QMK_COMMAND_MATRIX_CHROMA, 13, 7],
+                [QMK_COMMAND_MATRIX_BRIGHTNESS, 0, 20],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn loads_keymap_in_chunks() {
+        let (device, written) = mock_device::<33>(|request| {
+            if request[0] != QMK_KEYMAP_GET_COMMAND {
+                return None;
+            }
+            let mut answer = request.to_vec();
+            let offset = u16::from_be_bytes([request[1], request[2]]) / 2;
+            for i in 0..request[3] as u16 / 2 {
+                let keycode = 0x0400 + offset + i;
+                answer[4 + i as usize * 2..][..2].copy_from_slice(&keycode.to_be_bytes());
+            }
+            Some(answer)
+        });
+        let keymap = KeyboardController::load_keymap(&device, 20).await.unwrap();
+
+        assert_eq!(keymap, (0x0400..0x0414).collect::<Vec<u16>>());
+        assert_eq!(written.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn writes_only_the_changed_chunks() {
+        let config = Config::fixture("keyboard");
+        let (device, written) = mock_device::<33>(|_| None);
+        let state = state(40);
+
+        let mut colors = vec![None; 40];
+        colors[0] = Some(Rgb::new(1., 0., 0.));
+        colors[30] = Some(Rgb::new(1., 0., 0.));
+        let (chroma, brightness) = state.encode_colors(&config, colors, 0);
+
+        let mut template = device.create_report();
+        template[0] = QMK_CUSTOM_SET_COMMAND;
+        template[1] = QMK_CUSTOM_CHANNEL;
+        let reports = state
+            .color_reports(template, 0, &chroma, &brightness, true, 0)
+            .unwrap();
+        for report in reports {
+            device.send_report(report).await.unwrap();
+        }
+
+        let chunks: Vec<_> = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|x| x[..5].to_vec())
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                [QMK_CUSTOM_SET_COMMAND, 0, QMK_COMMAND_MATRIX_CHROMA, 0, 1],
+                [QMK_CUSTOM_SET_COMMAND, 0, QMK_COMMAND_MATRIX_CHROMA, 30, 1],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulates_keyboards_of_definitions() {
+        let config = Config::fixture("keyboard");
+        let leds = config.count_leds() as usize;
+        let requests = Arc::new(Semaphore::new(1));
+        let mut keyboard = KeyboardController::from_virtual(config, 0, requests)
+            .await
+            .unwrap();
+        assert_eq!(keyboard.device_id(), &KeyboardId::Virtual(0));
+        assert_eq!(keyboard.colors().len(), leds);
+
+        let red = Some(Rgb::new(1., 0., 0.));
+        keyboard
+            .update_colors(vec![red; leds], 0, true)
+            .await
+            .unwrap();
+        let (chroma, _) = KeyboardController::load_colors(&keyboard.device, leds, true)
+            .await
+            .unwrap();
+        assert_eq!(chroma, vec![(0, 255); leds]);
+    }
+
+    #[tokio::test]
+    async fn initializes_boards_without_brightness() {
+        let mut config = Config::fixture("keyboard");
+        config.brightness = (0, 0);
+        let (device, written) = mock_device::<33>(|request| match request[..3] {
+            [
+                QMK_CUSTOM_GET_COMMAND,
+                QMK_CUSTOM_CHANNEL,
+                QMK_COMMAND_MATRIX_BRIGHTNESS,
+            ]
+            | [
+                QMK_CUSTOM_GET_COMMAND,
+                QMK_RGB_MATRIX_CHANNEL,
+                QMK_COMMAND_BRIGHTNESS,
+            ] => None,
+            _ => Some(request.to_vec()),
+        });
+
+        let id = KeyboardId::Virtual(0);
+        let mut keyboard = KeyboardController::load(config, device, id).await.unwrap();
+        assert_eq!(keyboard.brightness(), 255);
+        assert_eq!(keyboard.dump_state().brightness, vec![255; 6]);
+
+        written.lock().unwrap().clear();
+        keyboard.update_brightness(100).await.unwrap();
+        let red = Some(Rgb::new(1., 0., 0.));
+        keyboard.update_colors(vec![red; 6], 0, true).await.unwrap();
+        let written = written.lock().unwrap();
+        assert!(written.iter().all(|x| x[2] == QMK_COMMAND_MATRIX_CHROMA));
+    }
+
+    #[tokio::test]
+    async fn initializes_direct_only_boards_without_effect_reads() {
+        let mut config = Config::fixture("keyboard");
+        config.direct_only = true;
+        config.speed_hints.default = Some(96);
+        config.brightness_hints.default = Some(180);
+        let (device, written) = mock_device::<33>(|request| match request[..3] {
+            [
+                QMK_CUSTOM_GET_COMMAND,
+                QMK_RGB_MATRIX_CHANNEL,
+                QMK_COMMAND_EFFECT | QMK_COMMAND_SPEED | QMK_COMMAND_COLOR | QMK_COMMAND_BRIGHTNESS,
+            ] => None,
+            _ => Some(request.to_vec()),
+        });
+
+        let id = KeyboardId::Virtual(0);
+        let keyboard = KeyboardController::load(config, device, id).await.unwrap();
+        assert_eq!(keyboard.speed(), 96);
+        assert_eq!(keyboard.brightness(), 180);
+        let written = written.lock().unwrap();
+        assert!(written.iter().all(|x| x[1] != QMK_RGB_MATRIX_CHANNEL));
+    }
+
+    #[tokio::test]
+    async fn keeps_boards_without_effects_in_direct_mode() {
+        let mut json = Config::fixture_json("keyboard");
+        json["menus"] = serde_json::json!([]);
+        let config = Config::from_str(&json.to_string()).unwrap();
+        let (device, written) = mock_device::<33>(|request| match request[..3] {
+            [
+                QMK_CUSTOM_GET_COMMAND,
+                QMK_RGB_MATRIX_CHANNEL,
+                QMK_COMMAND_EFFECT,
+            ] => Some([&request[..3], &[5]].concat()),
+            _ => Some(request.to_vec()),
+        });
+
+        let id = KeyboardId::Virtual(0);
+        let mut keyboard = KeyboardController::load(config, device, id).await.unwrap();
+        assert_eq!(keyboard.effect(), 0);
+
+        written.lock().unwrap().clear();
+        keyboard.update_effect(5).await.unwrap();
+        assert!(written.lock().unwrap().is_empty());
+        assert_eq!(keyboard.effect(), 0);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_silent_keyboards() {
+        let (device, written) = mock_device::<33>(|_| None);
+        assert!(KeyboardController::load_effect(&device).await.is_err());
+        assert_eq!(written.lock().unwrap().len(), QMK_REQUEST_ATTEMPTS);
+    }
+}
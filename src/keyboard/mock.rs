@@ -0,0 +1,22 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+
+use crate::keyboard::{device::KeyboardDevice, script::scripted_device};
+
+/// A scripted keyboard which also records the written reports (without the report id)
+pub fn mock_device<const N: usize>(
+    mut script: impl FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+) -> (KeyboardDevice<N>, Arc<Mutex<Vec<Vec<u8>>>>) {
+    let written = Arc::new(Mutex::new(Vec::new()));
+    let recorded = written.clone();
+    let script = move |report: &[u8]| {
+        recorded.lock().unwrap().push(report.to_vec());
+        script(report)
+    };
+    let requests = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+    let device = scripted_device(script, Duration::from_millis(100), requests);
+    (device, written)
+}
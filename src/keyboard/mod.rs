@@ -1,107 +1,219 @@
 mod chunks;
 mod device;
+mod firmware;
 mod keyboard;
+#[cfg(test)]
+mod mock;
+mod script;
 
 use anyhow::Result;
 use async_hid::{Device, DeviceId};
 use colored::Colorize;
 use indexmap::IndexMap;
-use log::warn;
+use itertools::Itertools;
+use log::{debug, warn};
 use palette::{encoding::Srgb, rgb::Rgb};
 use std::{
     cmp::{max, min},
     mem::{self, Discriminant},
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        Mutex as AsyncMutex, Semaphore,
+        mpsc::{self, Receiver, Sender},
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    config::Config, keyboard::keyboard::KeyboardController, orp::SavedController,
+    supervisor::supervise,
 };
-use tokio::sync::{Mutex as AsyncMutex, Notify};
 
-use crate::{config::Config, keyboard::keyboard::KeyboardController};
+pub use keyboard::StateDump;
+
+/// A consistent view of the keyboard state taken under a single lock
+pub struct KeyboardSnapshot {
+    pub config: Config,
+    pub keymap: Vec<u16>,
+    pub colors: Vec<Rgb<Srgb, u8>>,
+    pub color: Rgb<Srgb, u8>,
+    pub mode_colors: Vec<Rgb<Srgb, u8>>,
+    pub effect: u8,
+    pub speed: u8,
+    pub direction: u8,
+    pub brightness: u8,
+    pub offline: bool,
+    pub degraded: bool,
+}
+
+impl KeyboardSnapshot {
+    /// Resolves the OpenRGB name of the key above an LED
+    pub fn key_name(&self, led: u8) -> Option<&str> {
+        let width = self.config.matrix.0 as usize;
+        let (_, (row, col)) = self.config.leds.iter().find(|x| x.0 == led)?;
+        let scancode = self.keymap.get(*row as usize * width + *col as usize)?;
+        Some(self.config.key_label(*scancode))
+    }
+
+    /// Finds every LED under a key with the given OpenRGB name (case-insensitive)
+    pub fn find_key(&self, name: &str) -> Vec<u8> {
+        let width = self.config.matrix.0 as usize;
+        self.config
+            .leds
+            .iter()
+            .filter(|(_, (row, col))| {
+                let scancode = self.keymap.get(*row as usize * width + *col as usize);
+                scancode.is_some_and(|&x| self.config.key_label(x).eq_ignore_ascii_case(name))
+            })
+            .map(|(led, _)| *led)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl KeyboardSnapshot {
+    /// A keyboard with "A" under every key, lit red in a non-default mode
+    pub fn for_test(config: Config) -> Self {
+        let leds = config.count_leds() as usize;
+        let keys = (config.matrix.0 * config.matrix.1) as usize;
+        KeyboardSnapshot {
+            config,
+            keymap: vec![4; keys],
+            colors: vec![Rgb::new(255, 0, 0); leds],
+            color: Rgb::new(0, 0, 255),
+            mode_colors: Vec::new(),
+            effect: 1,
+            speed: 128,
+            direction: 0,
+            brightness: 200,
+            offline: false,
+            degraded: false,
+        }
+    }
+}
+
+/// Vendor id, product id and serial number telling physical keyboards apart
+pub type Identity = (u16, u16, Option<String>);
+
+/// Tells connected keyboards apart, where simulated ones are numbered by their definition
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyboardId {
+    Device(DeviceId),
+    Virtual(usize),
+}
+
+/// How long background sources are held off after a user changes something
+const USER_GRACE: Duration = Duration::from_secs(2);
+
+type Actions = Arc<Mutex<IndexMap<Discriminant<KeyboardAction>, KeyboardAction>>>;
 
 #[derive(Clone)]
 pub struct Keyboard {
-    actions: Arc<Mutex<IndexMap<Discriminant<KeyboardAction>, KeyboardAction>>>,
+    actions: Actions,
     keyboard: Arc<AsyncMutex<KeyboardController>>,
     notifier: Notifier,
+    profile: Arc<Mutex<Option<String>>>,
+    offline: Arc<AtomicBool>,
+    arbiter: Arbiter,
+    identity: Arc<Identity>,
+    /// The definition never changes once a keyboard is loaded, so it is read without a lock
+    config: Arc<Config>,
 }
 
 impl Keyboard {
-    pub async fn from_config(config: Config, device: Device) -> Result<Keyboard> {
-        let keyboard = KeyboardController::from_config(config, device).await?;
-        let keyboard = Arc::new(AsyncMutex::new(keyboard));
-        let weak_keyboard = Arc::downgrade(&keyboard);
+    pub async fn from_config(
+        config: Config,
+        device: Device,
+        requests: Arc<Semaphore>,
+    ) -> Result<Keyboard> {
+        let identity = (
+            config.vendor_id,
+            config.product_id,
+            device.serial_number.clone(),
+        );
+        let keyboard = KeyboardController::from_config(config, device, requests).await?;
+        Ok(Self::from_controller(keyboard, identity))
+    }
 
-        let notifier = Notifier {
-            notify: Arc::new(Notify::new()),
-        };
+    /// Creates a keyboard which is only simulated, without touching any hardware
+    pub async fn from_virtual(
+        config: Config,
+        index: usize,
+        requests: Arc<Semaphore>,
+    ) -> Result<Keyboard> {
+        let identity = (config.vendor_id, config.product_id, None);
+        let keyboard = KeyboardController::from_virtual(config, index, requests).await?;
+        Ok(Self::from_controller(keyboard, identity))
+    }
 
-        let actions = Arc::new(Mutex::new(IndexMap::<
-            Discriminant<KeyboardAction>,
-            KeyboardAction,
-        >::new()));
+    fn from_controller(keyboard: KeyboardController, identity: Identity) -> Keyboard {
+        let config = Arc::new(keyboard.config().clone());
+        let keyboard = Arc::new(AsyncMutex::new(keyboard));
 
-        let handler_actions = actions.clone();
-        let handler_notify = notifier.clone();
-        tokio::spawn(async move {
-            'handle: loop {
-                handler_notify.notify.notified().await;
-
-                'drain: loop {
-                    let keyboard = match weak_keyboard.upgrade() {
-                        Some(keyboard) => keyboard,
-                        None => break 'handle,
-                    };
-
-                    let action = {
-                        let mut actions = handler_actions.lock().unwrap();
-                        match actions.shift_remove_index(0) {
-                            Some((_, action)) => action,
-                            None => break 'drain,
-                        }
-                    };
-
-                    let mut keyboard = keyboard.lock().await;
-                    let action_name = action.as_name();
-
-                    if let Err(error) = handle_action(action, &mut keyboard).await {
-                        warn!(
-                            "{}\x1B[33m failed to execute action {}\x1B[33m: {error}",
-                            keyboard.config().name.bold(),
-                            action_name.bold(),
-                        )
-                    }
-                }
-            }
-        });
+        let actions = Actions::default();
+        let (notifier, receiver) = Notifier::new();
+        spawn_handler(receiver, actions.clone(), keyboard.clone());
 
-        Ok(Keyboard {
+        Keyboard {
             keyboard,
             actions,
             notifier,
-        })
+            profile: Arc::default(),
+            offline: Arc::default(),
+            arbiter: Arbiter::default(),
+            identity: Arc::new(identity),
+            config,
+        }
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
     }
 
     fn perform_action(&self, action: KeyboardAction) {
-        let mut actions = self.actions.lock().unwrap();
-        let id = mem::discriminant(&action);
-
-        let action = match (actions.shift_remove(&id), action) {
-            (
-                Some(KeyboardAction::UpdateColors(colors_old, offset_old, _)),
-                KeyboardAction::UpdateColors(colors_new, offset_new, with_brightness),
-            ) => {
-                let (colors, offset) =
-                    merge_colors(colors_old, offset_old as i32, colors_new, offset_new as i32);
-                KeyboardAction::UpdateColors(colors, offset, with_brightness)
-            }
-            (_, action) => action,
-        };
+        self.enqueue(action, Priority::User);
+    }
+
+    fn perform_background_action(&self, action: KeyboardAction) {
+        self.enqueue(action, Priority::Background);
+    }
 
-        actions.insert(id, action);
-        self.notifier.notify.notify_one();
+    fn enqueue(&self, action: KeyboardAction, priority: Priority) {
+        if self.is_offline() {
+            return;
+        }
+        if let Err(wait) = self.arbiter.admit(priority, USER_GRACE) {
+            return self.defer(action, wait);
+        }
+
+        queue(&mut self.actions.lock().unwrap(), action);
+        self.notifier.notify();
     }
 
-    pub async fn keymap(&self) -> Vec<u16> {
-        self.keyboard.lock().await.keymap().clone()
+    /// Retries a held off background action once the user is done, where only the latest one
+    /// of them is kept
+    fn defer(&self, action: KeyboardAction, wait: Duration) {
+        debug!(
+            "Postponed a background change of {}.",
+            self.config.name.bold()
+        );
+        if !self.arbiter.defer(action) {
+            return;
+        }
+
+        let keyboard = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            if let Some(action) = keyboard.arbiter.take_deferred() {
+                keyboard.enqueue(action, Priority::Background);
+            }
+        });
     }
 
     pub fn reset_brightness(&self) {
@@ -116,44 +228,65 @@ impl Keyboard {
         ));
     }
 
-    pub async fn colors(&self) -> Vec<Rgb<Srgb, u8>> {
-        self.keyboard.lock().await.colors()
-    }
-
     pub fn update_color(&self, color: Rgb<Srgb, u8>) {
         self.perform_action(KeyboardAction::UpdateColor(color));
     }
 
-    pub async fn color(&self) -> Rgb<Srgb, u8> {
-        self.keyboard.lock().await.color()
+    pub fn update_mode_colors(&self, colors: Vec<Option<Rgb<Srgb, u8>>>) {
+        self.perform_action(KeyboardAction::UpdateModeColors(colors));
     }
 
     pub fn update_effect(&self, effect: u8) {
         self.perform_action(KeyboardAction::UpdateEffect(effect));
     }
 
-    pub async fn effect(&self) -> u8 {
-        self.keyboard.lock().await.effect()
-    }
-
     pub fn update_speed(&self, speed: u8) {
         self.perform_action(KeyboardAction::UpdateSpeed(speed));
     }
 
-    pub async fn speed(&self) -> u8 {
-        self.keyboard.lock().await.speed()
+    pub fn update_direction(&self, direction: u8) {
+        self.perform_action(KeyboardAction::UpdateDirection(direction));
     }
 
     pub fn update_brightness(&self, brightness: u8) {
         self.perform_action(KeyboardAction::UpdateBrightness(brightness));
     }
 
-    pub async fn brightness(&self) -> u8 {
-        self.keyboard.lock().await.brightness()
+    /// Waits until every queued action has been executed
+    pub async fn flush(&self) {
+        loop {
+            let _keyboard = self.keyboard.lock().await;
+            if self.actions.lock().unwrap().is_empty() {
+                return;
+            }
+            drop(_keyboard);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
     }
 
-    pub async fn config(&self) -> Config {
-        self.keyboard.lock().await.config().clone()
+    pub async fn snapshot(&self) -> KeyboardSnapshot {
+        let keyboard = self.keyboard.lock().await;
+        KeyboardSnapshot {
+            config: keyboard.config().clone(),
+            keymap: keyboard.keymap().clone(),
+            colors: keyboard.colors(),
+            color: keyboard.color(),
+            mode_colors: keyboard.mode_colors(),
+            effect: keyboard.effect(),
+            speed: keyboard.speed(),
+            direction: keyboard.direction(),
+            brightness: keyboard.brightness(),
+            offline: self.is_offline(),
+            degraded: false,
+        }
+    }
+
+    pub async fn dump_state(&self) -> StateDump {
+        self.keyboard.lock().await.dump_state()
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.clone()
     }
 
     pub async fn save_state(&self) -> Result<String> {
@@ -164,22 +297,201 @@ impl Keyboard {
         self.perform_action(KeyboardAction::LoadState(state, with_brightness));
     }
 
+    /// Loads a state on behalf of a background source, unless a user has just changed something
+    pub fn reload_state(&self, state: String, with_brightness: bool) {
+        self.perform_background_action(KeyboardAction::LoadState(state, with_brightness));
+    }
+
+    /// Re-applies a state cached before the keyboard was unplugged
+    pub fn restore_state(&self, state: String) {
+        self.perform_action(KeyboardAction::RestoreState(state));
+    }
+
+    pub async fn check_state(&self, state: &str) -> Result<()> {
+        self.keyboard.lock().await.check_state(state)
+    }
+
+    pub async fn import_profile(&self, saved: &SavedController) -> Result<String> {
+        self.keyboard.lock().await.import_profile(saved)
+    }
+
+    pub fn set_profile(&self, profile: Option<String>) {
+        *self.profile.lock().unwrap() = profile;
+    }
+
+    pub fn profile(&self) -> Option<String> {
+        self.profile.lock().unwrap().clone()
+    }
+
     pub fn persist_state(&self) {
         self.perform_action(KeyboardAction::PersistState);
     }
 
-    pub async fn device_id(&self) -> DeviceId {
+    pub async fn reload_keymap(&self) -> Result<()> {
+        self.keyboard.lock().await.reload_keymap().await
+    }
+
+    pub async fn device_id(&self) -> KeyboardId {
         self.keyboard.lock().await.device_id().clone()
     }
 
-    pub async fn into_config(self) -> Config {
-        Arc::try_unwrap(self.keyboard)
-            .unwrap()
-            .into_inner()
-            .into_config()
+    pub fn set_offline(&self) {
+        self.offline.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::Relaxed)
+    }
+}
+
+pub trait ActionTarget: Send + 'static {
+    fn name(&self) -> &str;
+    fn read_only(&self) -> bool;
+    fn frame_interval(&self) -> Option<Duration>;
+    fn handle(&mut self, action: KeyboardAction) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl ActionTarget for KeyboardController {
+    fn name(&self) -> &str {
+        &self.config().name
+    }
+
+    fn read_only(&self) -> bool {
+        self.config().read_only
+    }
+
+    fn frame_interval(&self) -> Option<Duration> {
+        self.config().frame_interval()
+    }
+
+    fn handle(&mut self, action: KeyboardAction) -> impl Future<Output = Result<()>> + Send {
+        handle_action(action, self)
     }
 }
 
+/// Queues an action in place of the pending one of the same kind, since only the latest
+/// value matters. Colors and mode colors are merged instead, as updates may cover parts of them.
+fn queue(
+    actions: &mut IndexMap<Discriminant<KeyboardAction>, KeyboardAction>,
+    action: KeyboardAction,
+) {
+    let id = mem::discriminant(&action);
+    let action = match (actions.shift_remove(&id), action) {
+        (
+            Some(KeyboardAction::UpdateColors(colors_old, offset_old, _)),
+            KeyboardAction::UpdateColors(colors_new, offset_new, with_brightness),
+        ) => {
+            let (colors, offset) =
+                merge_colors(colors_old, offset_old as i32, colors_new, offset_new as i32);
+            KeyboardAction::UpdateColors(colors, offset, with_brightness)
+        }
+        (
+            Some(KeyboardAction::UpdateModeColors(colors_old)),
+            KeyboardAction::UpdateModeColors(colors_new),
+        ) => KeyboardAction::UpdateModeColors(
+            colors_new
+                .into_iter()
+                .zip_longest(colors_old)
+                .map(|x| x.reduce(|new, old| new.or(old)))
+                .collect(),
+        ),
+        (_, action) => action,
+    };
+    actions.insert(id, action);
+}
+
+fn spawn_handler<T: ActionTarget>(
+    receiver: Receiver<()>,
+    actions: Actions,
+    target: Arc<AsyncMutex<T>>,
+) -> JoinHandle<()> {
+    let receiver = Arc::new(AsyncMutex::new(receiver));
+    supervise("keyboard actions", move || {
+        let (receiver, actions, target) = (receiver.clone(), actions.clone(), target.clone());
+        async move {
+            handle_actions(receiver, actions, target).await;
+            Ok(())
+        }
+    })
+}
+
+async fn handle_actions<T: ActionTarget>(
+    receiver: Arc<AsyncMutex<Receiver<()>>>,
+    actions: Actions,
+    target: Arc<AsyncMutex<T>>,
+) {
+    let mut receiver = receiver.lock().await;
+    // Colors wait for the next frame, while the other actions go ahead of them
+    let mut next_frame: Option<Instant> = None;
+
+    // The channel closes only once every `Keyboard` handle is dropped, so we
+    // drain one last time to apply anything queued right before that (e.g. `PersistState`)
+    'handle: loop {
+        let closed = receiver.recv().await.is_none();
+
+        'drain: loop {
+            // Actions are only taken while holding the target, so `flush` can rely on it
+            let mut target = target.lock().await;
+            let throttled = next_frame.filter(|x| *x > Instant::now());
+            let Some(action) = take_action(&mut actions.lock().unwrap(), throttled.is_some())
+            else {
+                // Colors left for the next frame are merged with the ones coming meanwhile
+                match throttled {
+                    Some(frame) if !actions.lock().unwrap().is_empty() => {
+                        drop(target);
+                        tokio::time::sleep(frame.saturating_duration_since(Instant::now())).await;
+                        continue 'drain;
+                    }
+                    _ => break 'drain,
+                }
+            };
+
+            let action_name = action.as_name();
+
+            if target.read_only() {
+                debug!(
+                    "{} is read-only, skipped action {}",
+                    target.name().bold(),
+                    action_name.bold()
+                );
+                continue;
+            }
+
+            if is_frame(&action) {
+                next_frame = target.frame_interval().map(|x| Instant::now() + x);
+            }
+            if let Err(error) = target.handle(action).await {
+                warn!(
+                    "{}\x1B[33m failed to execute action {}\x1B[33m: {error}",
+                    target.name().bold(),
+                    action_name.bold(),
+                )
+            }
+        }
+
+        if closed {
+            break 'handle;
+        }
+    }
+}
+
+/// Takes the first queued action, leaving colors queued while frames are throttled
+fn take_action(
+    actions: &mut IndexMap<Discriminant<KeyboardAction>, KeyboardAction>,
+    throttled: bool,
+) -> Option<KeyboardAction> {
+    let index = if throttled {
+        actions.values().position(|x| !is_frame(x))?
+    } else {
+        0
+    };
+    actions.shift_remove_index(index).map(|x| x.1)
+}
+
+fn is_frame(action: &KeyboardAction) -> bool {
+    matches!(action, KeyboardAction::UpdateColors(..))
+}
+
 pub async fn handle_action(
     action: KeyboardAction,
     keyboard: &mut KeyboardController,
@@ -198,7 +510,10 @@ pub async fn handle_action(
         }
         KeyboardAction::UpdateEffect(effect) => keyboard.update_effect(effect).await,
         KeyboardAction::UpdateColor(color) => keyboard.update_color(color).await,
+        KeyboardAction::UpdateModeColors(colors) => keyboard.update_mode_colors(colors).await,
         KeyboardAction::UpdateSpeed(speed) => keyboard.update_speed(speed).await,
+        KeyboardAction::UpdateDirection(direction) => keyboard.update_direction(direction).await,
+        KeyboardAction::RestoreState(data) => keyboard.restore_state(&data).await,
         KeyboardAction::PersistState => keyboard.persist_state().await,
         KeyboardAction::ResetBrightness => keyboard.reset_brightness().await,
     }
@@ -209,9 +524,12 @@ pub enum KeyboardAction {
     UpdateColors(Vec<Option<Rgb>>, usize, bool),
     UpdateEffect(u8),
     UpdateSpeed(u8),
+    UpdateDirection(u8),
     UpdateBrightness(u8),
     UpdateColor(Rgb<Srgb, u8>),
+    UpdateModeColors(Vec<Option<Rgb<Srgb, u8>>>),
     LoadState(String, bool),
+    RestoreState(String),
     PersistState,
     ResetBrightness,
 }
@@ -222,9 +540,12 @@ impl KeyboardAction {
             KeyboardAction::UpdateColors(_, _, _) => "UpdateColors",
             KeyboardAction::UpdateEffect(_) => "UpdateEffect",
             KeyboardAction::UpdateSpeed(_) => "UpdateSpeed",
+            KeyboardAction::UpdateDirection(_) => "UpdateDirection",
             KeyboardAction::UpdateBrightness(_) => "UpdateBrightness",
             KeyboardAction::UpdateColor(_) => "UpdateColor",
+            KeyboardAction::UpdateModeColors(_) => "UpdateModeColors",
             KeyboardAction::LoadState(_, _) => "LoadState",
+            KeyboardAction::RestoreState(_) => "RestoreState",
             KeyboardAction::PersistState => "PersistState",
             KeyboardAction::ResetBrightness => "ResetBrightness",
         }
@@ -233,12 +554,60 @@ impl KeyboardAction {
 
 #[derive(Clone)]
 pub struct Notifier {
-    pub notify: Arc<Notify>,
+    sender: Sender<()>,
 }
 
-impl Drop for Notifier {
-    fn drop(&mut self) {
-        self.notify.notify_one();
+impl Notifier {
+    fn new() -> (Self, Receiver<()>) {
+        let (sender, receiver) = mpsc::channel(1);
+        (Notifier { sender }, receiver)
+    }
+
+    fn notify(&self) {
+        // A full channel means the handler is already due to wake up
+        _ = self.sender.try_send(());
+    }
+}
+
+/// Who requested an action, so background sources do not override explicit changes
+#[derive(Clone, Copy, PartialEq)]
+enum Priority {
+    User,
+    Background,
+}
+
+/// Holds off background actions for a while after each user action
+#[derive(Clone, Default)]
+struct Arbiter {
+    last_user_action: Arc<Mutex<Option<Instant>>>,
+    deferred: Arc<Mutex<Option<KeyboardAction>>>,
+}
+
+impl Arbiter {
+    /// Admits the action or tells how long it has to wait
+    fn admit(&self, priority: Priority, grace: Duration) -> Result<(), Duration> {
+        let mut last_user_action = self.last_user_action.lock().unwrap();
+        match priority {
+            Priority::User => {
+                *last_user_action = Some(Instant::now());
+                Ok(())
+            }
+            Priority::Background => {
+                match last_user_action.and_then(|time| grace.checked_sub(time.elapsed())) {
+                    Some(wait) if !wait.is_zero() => Err(wait),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Replaces the held off action, returns whether none was waiting before
+    fn defer(&self, action: KeyboardAction) -> bool {
+        self.deferred.lock().unwrap().replace(action).is_none()
+    }
+
+    fn take_deferred(&self) -> Option<KeyboardAction> {
+        self.deferred.lock().unwrap().take()
     }
 }
 
@@ -283,6 +652,206 @@ fn merge_colors(
 mod tests {
     use super::*;
 
+    #[derive(Default)]
+    struct Recorder {
+        actions: Vec<String>,
+        read_only: bool,
+        frame_interval: Option<Duration>,
+    }
+
+    impl ActionTarget for Recorder {
+        fn name(&self) -> &str {
+            "Recorder"
+        }
+
+        fn read_only(&self) -> bool {
+            self.read_only
+        }
+
+        fn frame_interval(&self) -> Option<Duration> {
+            self.frame_interval
+        }
+
+        async fn handle(&mut self, action: KeyboardAction) -> Result<()> {
+            self.actions.push(format!("{action:?}"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn drains_pending_actions_on_drop() {
+        let recorder = Arc::new(AsyncMutex::new(Recorder::default()));
+        let actions = Actions::default();
+        let (notifier, receiver) = Notifier::new();
+        let handler = spawn_handler(receiver, actions.clone(), recorder.clone());
+
+        actions.lock().unwrap().insert(
+            mem::discriminant(&KeyboardAction::PersistState),
+            KeyboardAction::PersistState,
+        );
+        notifier.notify();
+        drop(notifier);
+
+        handler.await.unwrap();
+        assert_eq!(recorder.lock().await.actions, vec!["PersistState"]);
+    }
+
+    #[tokio::test]
+    async fn collapses_bursts_of_scalar_updates() {
+        let recorder = Arc::new(AsyncMutex::new(Recorder::default()));
+        let actions = Actions::default();
+        let (notifier, receiver) = Notifier::new();
+
+        // Queued before the handler starts, as a client would while a write is in flight
+        for value in 0..100 {
+            let mut queued = actions.lock().unwrap();
+            queue(&mut queued, KeyboardAction::UpdateBrightness(value));
+            queue(&mut queued, KeyboardAction::UpdateSpeed(value));
+            queue(&mut queued, KeyboardAction::UpdateEffect(value % 7));
+        }
+        queue(
+            &mut actions.lock().unwrap(),
+            KeyboardAction::UpdateBrightness(255),
+        );
+        assert_eq!(actions.lock().unwrap().len(), 3);
+
+        let handler = spawn_handler(receiver, actions.clone(), recorder.clone());
+        notifier.notify();
+        drop(notifier);
+
+        handler.await.unwrap();
+        assert_eq!(
+            recorder.lock().await.actions,
+            vec![
+                "UpdateSpeed(99)",
+                "UpdateEffect(1)",
+                "UpdateBrightness(255)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_frames_beyond_the_rate() {
+        let recorder = Arc::new(AsyncMutex::new(Recorder {
+            frame_interval: Some(Duration::from_millis(200)),
+            ..Default::default()
+        }));
+        let actions = Actions::default();
+        let (notifier, receiver) = Notifier::new();
+        let handler = spawn_handler(receiver, actions.clone(), recorder.clone());
+
+        let red = Some(Rgb::new(1., 0., 0.));
+        let frame = |offset| KeyboardAction::UpdateColors(vec![red], offset, true);
+        queue(&mut actions.lock().unwrap(), frame(0));
+        notifier.notify();
+        while recorder.lock().await.actions.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let started = Instant::now();
+        for offset in 0..10 {
+            queue(&mut actions.lock().unwrap(), frame(offset));
+        }
+        queue(
+            &mut actions.lock().unwrap(),
+            KeyboardAction::UpdateBrightness(10),
+        );
+        notifier.notify();
+        drop(notifier);
+
+        handler.await.unwrap();
+        let recorded = &recorder.lock().await.actions;
+        assert_eq!(recorded.len(), 3);
+        assert_eq!(recorded[1], "UpdateBrightness(10)");
+        let merged = KeyboardAction::UpdateColors(vec![red; 10], 0, true);
+        assert_eq!(recorded[2], format!("{merged:?}"));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn finds_every_led_of_a_modifier() {
+        let snapshot = KeyboardSnapshot {
+            // Both 216 and 225 are reported as "Left Shift"
+            keymap: vec![216, 4, 5, 225, 6, 7],
+            ..KeyboardSnapshot::for_test(Config::fixture("keyboard"))
+        };
+
+        assert_eq!(snapshot.find_key("left shift"), vec![0, 3]);
+        assert_eq!(snapshot.find_key("A"), vec![1]);
+        assert!(snapshot.find_key("Escape").is_empty());
+
+        // Keys past the end of a short keymap are skipped
+        let snapshot = KeyboardSnapshot {
+            keymap: vec![216, 4],
+            ..snapshot
+        };
+        assert_eq!(snapshot.find_key("left shift"), vec![0]);
+    }
+
+    #[test]
+    fn background_actions_wait_for_user_grace() {
+        let arbiter = Arbiter::default();
+        let grace = Duration::from_millis(50);
+        assert!(arbiter.admit(Priority::Background, grace).is_ok());
+
+        assert!(arbiter.admit(Priority::User, grace).is_ok());
+        let wait = arbiter.admit(Priority::Background, grace).unwrap_err();
+        assert!(wait <= grace);
+        assert!(arbiter.admit(Priority::User, grace).is_ok());
+
+        std::thread::sleep(grace);
+        assert!(arbiter.admit(Priority::Background, grace).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_latest_deferred_action() {
+        let arbiter = Arbiter::default();
+        assert!(arbiter.defer(KeyboardAction::UpdateEffect(1)));
+        assert!(!arbiter.defer(KeyboardAction::UpdateEffect(2)));
+        assert!(matches!(
+            arbiter.take_deferred(),
+            Some(KeyboardAction::UpdateEffect(2))
+        ));
+        assert!(arbiter.take_deferred().is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_nothing_when_read_only() {
+        use crate::{
+            consts::OPENRGB_PROTOCOL_VERSION,
+            handlers::controller_data,
+            keyboard::{firmware::VirtualFirmware, mock::mock_device},
+        };
+
+        let mut config = Config::fixture("keyboard");
+        config.read_only = true;
+        let leds = config.count_leds() as usize;
+        let mut firmware = VirtualFirmware::new(&config);
+        let (device, written) = mock_device::<33>(move |report| firmware.answer(report));
+        let identity = (config.vendor_id, config.product_id, None);
+        let controller = KeyboardController::load(config, device, KeyboardId::Virtual(0))
+            .await
+            .unwrap();
+        let keyboard = Keyboard::from_controller(controller, identity);
+        let before = keyboard.snapshot().await;
+        written.lock().unwrap().clear();
+
+        keyboard.update_colors(vec![Some(Rgb::new(1., 0., 0.)); leds], 0, true);
+        keyboard.update_effect(2);
+        keyboard.update_brightness(10);
+        keyboard.flush().await;
+        assert!(written.lock().unwrap().is_empty());
+
+        // The state read on startup is still served to clients
+        let snapshot = keyboard.snapshot().await;
+        assert_eq!(snapshot.colors.len(), leds);
+        assert_eq!(snapshot.colors, before.colors);
+        assert_eq!(
+            controller_data(&snapshot, OPENRGB_PROTOCOL_VERSION),
+            controller_data(&before, OPENRGB_PROTOCOL_VERSION)
+        );
+    }
+
     #[test]
     fn merges_non_overlapping() {
         let red = Some(Rgb::new(1., 0., 0.));
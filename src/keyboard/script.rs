@@ -0,0 +1,58 @@
+use anyhow::Result;
+use futures::future::{self, BoxFuture};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{
+    Semaphore,
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::keyboard::device::{KeyboardDevice, ReportReader, ReportWriter};
+
+type Script = Box<dyn FnMut(&[u8]) -> Option<Vec<u8>> + Send>;
+
+/// A device without hardware, answering each written report (without the report id)
+/// with whatever the script returns for it
+pub fn scripted_device<const N: usize>(
+    script: impl FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    timeout: Duration,
+    requests: Arc<Semaphore>,
+) -> KeyboardDevice<N> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let writer = ScriptWriter {
+        script: Box::new(script),
+        answers: sender,
+    };
+    let reader = ScriptReader { answers: receiver };
+    KeyboardDevice::from_parts(reader, writer, timeout, requests)
+}
+
+struct ScriptWriter {
+    script: Script,
+    answers: UnboundedSender<Vec<u8>>,
+}
+
+struct ScriptReader {
+    answers: UnboundedReceiver<Vec<u8>>,
+}
+
+impl ReportWriter for ScriptWriter {
+    fn write_report<'a>(&'a mut self, report: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        if let Some(answer) = (self.script)(&report[1..]) {
+            _ = self.answers.send(answer);
+        }
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+impl ReportReader for ScriptReader {
+    fn read_report<'a>(&'a mut self, buffer: &'a mut [u8]) -> BoxFuture<'a, Result<usize>> {
+        Box::pin(async move {
+            let Some(answer) = self.answers.recv().await else {
+                return future::pending().await;
+            };
+            let length = answer.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&answer[..length]);
+            Ok(length)
+        })
+    }
+}
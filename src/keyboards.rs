@@ -1,121 +1,875 @@
 use anyhow::Result;
-use async_hid::{Device, DeviceEvent, DeviceId, HidBackend};
+use async_hid::{Device, DeviceEvent, HidBackend};
 use colored::Colorize;
 use futures::StreamExt;
 use indexmap::IndexMap;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio::sync::{
-    Mutex as AsyncMutex, MutexGuard,
+    Mutex as AsyncMutex, MutexGuard, Semaphore,
     broadcast::{self, Receiver, Sender},
 };
 
 use crate::{
     config::Config,
     consts::{QMK_USAGE_ID, QMK_USAGE_PAGE},
-    keyboard::Keyboard,
+    error::Error,
+    group::{Controller, Group, GroupConfig, Member},
+    keyboard::{Identity, Keyboard, KeyboardId},
+    supervisor::supervise,
 };
 
+const OFFLINE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where keyboards are listed: the ones of `--device-order` first, then the others by the
+/// positions of their definitions
+#[derive(Default)]
+struct Ranking {
+    listed: Vec<Identity>,
+    definitions: HashMap<(u16, u16), usize>,
+}
+
+impl Ranking {
+    fn new(configs: &IndexMap<(u16, u16), Config>, listed: Vec<Identity>) -> Self {
+        let definitions = configs.keys().enumerate().map(|(i, &x)| (x, i)).collect();
+        Ranking {
+            listed,
+            definitions,
+        }
+    }
+
+    /// A listed model without a serial number takes in all of its devices
+    fn slot(&self, identity: &Identity) -> (usize, usize, Option<String>) {
+        let (vendor_id, product_id, serial) = identity;
+        let listed = self.listed.iter().position(|x| matches(x, identity));
+        let rank = self.definitions.get(&(*vendor_id, *product_id)).copied();
+        (
+            listed.unwrap_or(usize::MAX),
+            rank.unwrap_or(usize::MAX),
+            serial.clone(),
+        )
+    }
+}
+
+type Ranks = Arc<Ranking>;
+
+/// Devices picked with `--include` and `--exclude`, where excluding takes precedence
+#[derive(Default)]
+pub struct Filter {
+    pub include: Vec<Identity>,
+    pub exclude: Vec<Identity>,
+}
+
+impl Filter {
+    fn allows(&self, identity: &Identity) -> bool {
+        if self.exclude.iter().any(|x| matches(x, identity)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|x| matches(x, identity))
+    }
+
+    /// Whether no device of a model is allowed, so its definition is not waited for
+    fn rejects_model(&self, key: (u16, u16)) -> bool {
+        let model = |x: &&Identity| (x.0, x.1) == key;
+        let included = self.include.is_empty() || self.include.iter().any(|x| model(&x));
+        !included || self.exclude.iter().filter(model).any(|x| x.2.is_none())
+    }
+}
+
+/// States of unplugged keyboards, restored once they are back
+type States = Arc<Mutex<HashMap<Identity, String>>>;
+
+/// The client connection a state change came from, `None` for other sources
+pub type Origin = Option<usize>;
+
+/// Definitions along with how many devices are connected for each of them, as several
+/// identical keyboards share a definition
+#[derive(Default)]
+struct Pool {
+    configs: HashMap<(u16, u16), Config>,
+    connected: HashMap<(u16, u16), usize>,
+    /// Names given to models or single devices with `--name`
+    names: Vec<(Identity, String)>,
+    filter: Filter,
+}
+
+impl Pool {
+    /// Finds the definition of a device, unless it is filtered out to be left to other tools
+    fn config(&self, device: &Device) -> Option<Config> {
+        let key = (device.vendor_id, device.product_id);
+        let mut config = self.configs.get(&key)?.clone();
+        let identity = (key.0, key.1, device.serial_number.clone());
+        if !self.filter.allows(&identity) {
+            debug!("Ignoring excluded keyboard {}!", config.name.bold());
+            return None;
+        }
+        config.display_name = self.display_name(&identity);
+        Some(config)
+    }
+
+    /// A name given to the very device takes precedence over the one given to its model
+    fn display_name(&self, identity: &Identity) -> Option<String> {
+        let named = |serial: bool| {
+            (self.names.iter()).find(|(x, _)| x.2.is_some() == serial && matches(x, identity))
+        };
+        named(true).or_else(|| named(false)).map(|x| x.1.clone())
+    }
+
+    /// Finds the definition a group member refers to, by its device or by its name
+    fn definition(&self, member: &str) -> Option<&Config> {
+        match parse_identity(member) {
+            Some((vendor_id, product_id, _)) => self.configs.get(&(vendor_id, product_id)),
+            None => self.configs.values().find(|x| x.name == member),
+        }
+    }
+
+    fn connect(&mut self, key: (u16, u16)) {
+        *self.connected.entry(key).or_default() += 1;
+    }
+
+    fn disconnect(&mut self, key: (u16, u16)) {
+        if let Some(count) = self.connected.get_mut(&key) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Replaces the definition of a model, which its devices pick up once they reconnect.
+    /// Returns whether any of them is connected at the moment.
+    fn update(&mut self, config: Config) -> bool {
+        let key = (config.vendor_id, config.product_id);
+        self.configs.insert(key, config);
+        self.connected.get(&key).is_some_and(|&count| count > 0)
+    }
+
+    /// Lists the definitions without a single connected device
+    fn missing(&self) -> impl Iterator<Item = &Config> {
+        self.configs.iter().filter_map(|(key, config)| {
+            let count = self.connected.get(key).copied().unwrap_or_default();
+            (count == 0 && !self.filter.rejects_model(*key)).then_some(config)
+        })
+    }
+
+    fn pending(&self) -> usize {
+        self.missing().count()
+    }
+}
+
 #[derive(Clone)]
 pub struct Keyboards {
-    pub keyboards: Arc<AsyncMutex<IndexMap<DeviceId, Keyboard>>>,
-    configs: Arc<Mutex<HashMap<(u16, u16), Config>>>,
+    pub keyboards: Arc<AsyncMutex<IndexMap<KeyboardId, Keyboard>>>,
+    pool: Arc<Mutex<Pool>>,
+    ranks: Ranks,
+    states: States,
+    groups: Arc<Vec<GroupConfig>>,
     sender: Sender<()>,
+    changes: Sender<Origin>,
+    /// Keyboards connected for the first time after the startup
+    attached: Sender<Keyboard>,
+    /// Whether the keyboards are simulated, so no devices are looked for
+    simulated: bool,
+    /// Permits for the requests awaiting an answer, shared by all the keyboards
+    requests: Arc<Semaphore>,
 }
 
 impl Keyboards {
-    pub async fn from_configs(mut configs: HashMap<(u16, u16), Config>) -> Result<Self> {
+    /// Loads keyboards for the definitions, which are listed in the preferred device order
+    pub async fn from_configs(
+        configs: IndexMap<(u16, u16), Config>,
+        groups: Vec<GroupConfig>,
+        order: Vec<Identity>,
+        names: Vec<(Identity, String)>,
+        filter: Filter,
+        max_requests: usize,
+    ) -> Result<Self> {
+        let requests = Arc::new(Semaphore::new(max_requests));
         let mut keyboards = IndexMap::new();
+        let ranks: Ranks = Arc::new(Ranking::new(&configs, order));
+        let mut pool = Pool {
+            configs: configs.into_iter().collect(),
+            names,
+            filter,
+            ..Default::default()
+        };
+        check_groups(&pool, &groups);
 
         let backend = HidBackend::default();
         let mut stream = backend.enumerate().await?;
 
         while let Some(device) = stream.next().await {
-            if is_compatible(&device)
-                && let Some(config) = configs.remove(&(device.vendor_id, device.product_id))
-            {
+            if !is_compatible(&device) {
+                continue;
+            }
+
+            if keyboards.contains_key(&KeyboardId::Device(device.id.clone())) {
+                continue;
+            }
+            if let Some(config) = pool.config(&device) {
                 debug!("Keyboard {} connected!", config.name.bold());
-                match Keyboard::from_config(config, device).await {
+                let key = (config.vendor_id, config.product_id);
+                match Keyboard::from_config(config, device, requests.clone()).await {
                     Err(error) => warn!("Failed to initialize keyboard: {error}"),
                     Ok(keyboard) => {
+                        pool.connect(key);
                         keyboards.insert(keyboard.device_id().await, keyboard);
                     }
                 }
             }
         }
+        sort_by_slot(&mut keyboards, &ranks, Keyboard::identity);
 
-        Ok(Keyboards {
-            configs: Arc::new(Mutex::new(configs)),
+        Ok(Keyboards::new(
+            keyboards, pool, ranks, groups, requests, false,
+        ))
+    }
+
+    /// Simulates a keyboard for every definition instead of looking for devices, so they
+    /// can be tried out with clients on a machine without the hardware
+    pub async fn simulate(
+        configs: IndexMap<(u16, u16), Config>,
+        groups: Vec<GroupConfig>,
+        order: Vec<Identity>,
+        names: Vec<(Identity, String)>,
+        filter: Filter,
+        max_requests: usize,
+    ) -> Result<Self> {
+        let requests = Arc::new(Semaphore::new(max_requests));
+        let mut keyboards = IndexMap::new();
+        let ranks: Ranks = Arc::new(Ranking::new(&configs, order));
+        let mut pool = Pool {
+            names,
+            filter,
+            ..Default::default()
+        };
+        for (index, (key, mut config)) in configs.into_iter().enumerate() {
+            // Simulated keyboards have no serial numbers to be picked by
+            if !pool.filter.allows(&(key.0, key.1, None)) {
+                continue;
+            }
+            debug!("Simulating keyboard {}...", config.name.bold());
+            pool.configs.insert(key, config.clone());
+            config.display_name = pool.display_name(&(key.0, key.1, None));
+            pool.connect(key);
+            let keyboard = Keyboard::from_virtual(config, index, requests.clone()).await?;
+            keyboards.insert(KeyboardId::Virtual(index), keyboard);
+        }
+        sort_by_slot(&mut keyboards, &ranks, Keyboard::identity);
+        check_groups(&pool, &groups);
+
+        Ok(Keyboards::new(
+            keyboards, pool, ranks, groups, requests, true,
+        ))
+    }
+
+    fn new(
+        keyboards: IndexMap<KeyboardId, Keyboard>,
+        pool: Pool,
+        ranks: Ranks,
+        groups: Vec<GroupConfig>,
+        requests: Arc<Semaphore>,
+        simulated: bool,
+    ) -> Self {
+        Keyboards {
+            pool: Arc::new(Mutex::new(pool)),
+            ranks,
+            states: States::default(),
+            groups: Arc::new(groups),
             keyboards: Arc::new(AsyncMutex::new(keyboards)),
             sender: broadcast::channel(32).0,
-        })
+            changes: broadcast::channel(32).0,
+            attached: broadcast::channel(32).0,
+            simulated,
+            requests,
+        }
     }
 
-    pub fn watch(&self) -> Result<()> {
+    pub fn watch(&self) {
+        if self.simulated {
+            return;
+        }
         let keyboards = self.keyboards.clone();
-        let configs = self.configs.clone();
+        let pool = self.pool.clone();
+        let ranks = self.ranks.clone();
+        let states = self.states.clone();
         let notifier = self.sender.clone();
+        let attached = self.attached.clone();
+        let requests = self.requests.clone();
+
+        supervise("hotplug watcher", move || {
+            let keyboards = keyboards.clone();
+            let pool = pool.clone();
+            let ranks = ranks.clone();
+            let states = states.clone();
+            let notifier = notifier.clone();
+            let attached = attached.clone();
+            let requests = requests.clone();
+            watch_devices(keyboards, pool, ranks, states, notifier, attached, requests)
+        });
+    }
+
+    /// Enumerates the devices again, picking up the ones the hotplug watcher has missed,
+    /// and rereads the keymaps of the connected ones. Returns how many keyboards were added.
+    pub async fn rescan(&self) -> Result<usize> {
+        let online: Vec<_> = self
+            .keyboards
+            .lock()
+            .await
+            .values()
+            .filter(|x| !x.is_offline())
+            .cloned()
+            .collect();
+        for keyboard in online {
+            if let Err(error) = keyboard.reload_keymap().await {
+                let name = keyboard.config().name.clone();
+                warn!("Failed to reload the keymap of {}: {error}", name.bold());
+            }
+        }
+
+        if self.simulated {
+            _ = self.sender.send(());
+            return Ok(0);
+        }
 
         let backend = HidBackend::default();
-        let mut watcher = backend.watch()?;
-
-        tokio::spawn(async move {
-            loop {
-                if let Some(event) = watcher.next().await {
-                    match event {
-                        DeviceEvent::Connected(id) => {
-                            let devices = backend.query_devices(&id).await.ok();
-                            let device = devices.and_then(|x| x.filter(is_compatible).next());
-                            let config = device.as_ref().and_then(|device| {
-                                let key = &(device.vendor_id, device.product_id);
-                                configs.lock().unwrap().remove(key)
-                            });
-
-                            if let (Some(config), Some(device)) = (config, device) {
-                                debug!("Keyboard {} connected!", config.name.bold());
-                                match Keyboard::from_config(config, device).await {
-                                    Err(error) => warn!("Failed to initialize keyboard: {error}"),
-                                    Ok(keyboard) => {
-                                        keyboards.lock().await.insert(id, keyboard);
-
-                                        _ = notifier.send(());
-                                    }
-                                }
-                            }
-                        }
-                        DeviceEvent::Disconnected(id) => {
-                            if let Some(device) = keyboards.lock().await.shift_remove(&id) {
-                                let config = device.into_config().await;
-                                debug!("Keyboard {} disconnected!", config.name.bold());
-
-                                configs
-                                    .lock()
-                                    .unwrap()
-                                    .insert((config.vendor_id, config.product_id), config);
-
-                                _ = notifier.send(());
-                            }
-                        }
-                    }
+        let mut stream = backend.enumerate().await?;
+
+        let mut added = 0;
+        while let Some(device) = stream.next().await {
+            if !is_compatible(&device) {
+                continue;
+            }
+            if attach(
+                device,
+                &self.keyboards,
+                &self.pool,
+                &self.ranks,
+                &self.states,
+                &self.attached,
+                &self.requests,
+            )
+            .await
+            {
+                added += 1;
+            }
+        }
+        // Keymaps name the LEDs, so clients have to fetch the devices again either way
+        _ = self.sender.send(());
+
+        Ok(added)
+    }
+
+    /// Lists the devices presented to clients, where grouped keyboards appear once as their group
+    pub async fn controllers(&self) -> Vec<Controller> {
+        let keyboards = self.keyboards.lock().await;
+        let claims = self.claim(&keyboards);
+        let standalone = standalone(&keyboards, &claims).into_iter().cloned();
+        (standalone.map(Controller::Keyboard))
+            .chain(self.assemble(claims).map(Controller::Group))
+            .collect()
+    }
+
+    /// Resolves only the device at the client facing index
+    pub async fn controller(&self, index: usize) -> Option<Controller> {
+        let keyboards = self.keyboards.lock().await;
+        let claims = self.claim(&keyboards);
+        let standalone = standalone(&keyboards, &claims);
+        if let Some(keyboard) = standalone.get(index) {
+            return Some(Controller::Keyboard((*keyboard).clone()));
+        }
+        let group = self.assemble(claims).nth(index - standalone.len())?;
+        Some(Controller::Group(group))
+    }
+
+    /// Counts the devices presented to clients
+    pub async fn count(&self) -> usize {
+        let keyboards = self.keyboards.lock().await;
+        let claims = self.claim(&keyboards);
+        standalone(&keyboards, &claims).len() + self.assemble(claims).count()
+    }
+
+    /// Picks a keyboard for every group member, so identical keyboards fill one member each
+    fn claim<'a>(&'a self, keyboards: &'a IndexMap<KeyboardId, Keyboard>) -> Claims<'a> {
+        let mut claimed: Vec<&KeyboardId> = Vec::new();
+        let mut claims = Vec::new();
+        for group in self.groups.iter() {
+            let mut members = Vec::new();
+            for member in &group.members {
+                let found = keyboards.iter().find(|(id, keyboard)| {
+                    !claimed.contains(id)
+                        && selects(member, keyboard.identity(), &keyboard.config())
+                });
+                if let Some((id, _)) = found {
+                    claimed.push(id);
                 }
+                members.push((member, found.map(|x| x.1)));
             }
-        });
+            claims.push((group, members));
+        }
+        Claims { claimed, claims }
+    }
 
-        Ok(())
+    fn assemble<'a>(&'a self, claims: Claims<'a>) -> impl Iterator<Item = Group> + 'a {
+        claims.claims.into_iter().filter_map(|(group, members)| {
+            let pool = self.pool.lock().unwrap();
+            let members: Vec<_> = members
+                .into_iter()
+                .filter_map(|(member, keyboard)| {
+                    if let Some(keyboard) = keyboard {
+                        return Some(Member::Online(keyboard.clone()));
+                    }
+                    let config = pool.definition(member)?.clone();
+                    Some(Member::Missing(Box::new(config)))
+                })
+                .collect();
+
+            (!members.is_empty()).then(|| Group::new(group.name.clone(), members))
+        })
+    }
+
+    /// Swaps in reloaded definitions and lets clients know the device list may have changed
+    pub fn update_configs(&self, configs: impl IntoIterator<Item = Config>) {
+        let mut pool = self.pool.lock().unwrap();
+        for config in configs {
+            let name = config.name.clone();
+            if pool.update(config) {
+                info!("Reconnect {} to apply its updated definition!", name.bold());
+            }
+        }
+        drop(pool);
+
+        _ = self.sender.send(());
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pool.lock().unwrap().pending()
+    }
+
+    pub fn missing(&self) -> Vec<Config> {
+        self.pool.lock().unwrap().missing().cloned().collect()
     }
 
     pub fn subscribe(&self) -> Receiver<()> {
         self.sender.subscribe()
     }
 
-    pub async fn items(&self) -> MutexGuard<'_, IndexMap<DeviceId, Keyboard>> {
+    /// Lets other clients know that the effect or settings of a device have changed
+    pub fn notify_change(&self, origin: Origin) {
+        _ = self.changes.send(origin);
+    }
+
+    pub fn subscribe_changes(&self) -> Receiver<Origin> {
+        self.changes.subscribe()
+    }
+
+    /// Receives the keyboards connected for the first time, as opposed to the reconnected
+    /// ones getting back the state they had
+    pub fn subscribe_attached(&self) -> Receiver<Keyboard> {
+        self.attached.subscribe()
+    }
+
+    pub async fn items(&self) -> MutexGuard<'_, IndexMap<KeyboardId, Keyboard>> {
         self.keyboards.lock().await
     }
 }
 
+fn check_groups(pool: &Pool, groups: &[GroupConfig]) {
+    for group in groups {
+        let (mut leds, mut columns) = (0, 0);
+        for member in &group.members {
+            let Some(config) = pool.definition(member) else {
+                warn!(
+                    "Group {} refers to an unknown keyboard {member}!",
+                    group.name
+                );
+                continue;
+            };
+            leds += config.count_leds();
+            columns += config.matrix.0;
+        }
+        // LEDs and their positions are sent to clients as bytes
+        if leds > 256 || columns > 256 {
+            warn!(
+                "Group {} has {leds} LEDs in {columns} columns, only 256 of each can be addressed!",
+                group.name
+            );
+        }
+    }
+}
+
+/// The keyboards picked for each group, along with the ones taken from the device list
+struct Claims<'a> {
+    claimed: Vec<&'a KeyboardId>,
+    claims: Vec<(&'a GroupConfig, Members<'a>)>,
+}
+
+/// The keyboard picked for each member of a group, unless none is connected
+type Members<'a> = Vec<(&'a String, Option<&'a Keyboard>)>;
+
+fn standalone<'a>(
+    keyboards: &'a IndexMap<KeyboardId, Keyboard>,
+    claims: &Claims,
+) -> Vec<&'a Keyboard> {
+    (keyboards.iter())
+        .filter(|(id, _)| !claims.claimed.contains(id))
+        .map(|(_, keyboard)| keyboard)
+        .collect()
+}
+
+/// Whether a group member refers to a keyboard, given either as `vvvv:pppp[:<serial>]` or by
+/// the name of its definition
+fn selects(member: &str, identity: &Identity, config: &Config) -> bool {
+    match parse_identity(member) {
+        Some(pattern) => matches(&pattern, identity),
+        None => config.name == member,
+    }
+}
+
 fn is_compatible(device: &Device) -> bool {
     device.usage_id == QMK_USAGE_ID && device.usage_page == QMK_USAGE_PAGE
 }
+
+/// Orders keyboards by their definitions and then by serial numbers, so a physical keyboard
+/// keeps its index whatever order the keyboards connect in
+fn sort_by_slot<K, T>(
+    keyboards: &mut IndexMap<K, T>,
+    ranks: &Ranking,
+    identity: impl Fn(&T) -> &Identity,
+) {
+    keyboards.sort_by(|_, a, _, b| ranks.slot(identity(a)).cmp(&ranks.slot(identity(b))));
+}
+
+/// Parses the devices of an option, given as `vvvv:pppp` or `vvvv:pppp:<serial>`
+pub fn parse_devices(option: &str, devices: &[String]) -> Result<Vec<Identity>> {
+    devices
+        .iter()
+        .map(|device| {
+            parse_identity(device).ok_or_else(|| {
+                Error::Config(format!(
+                    "Invalid device `{device}` in `{option}`, expected `vvvv:pppp[:<serial>]`!"
+                ))
+                .into()
+            })
+        })
+        .collect()
+}
+
+/// Parses the names of `--name`, given as `vvvv:pppp=<name>` or `vvvv:pppp:<serial>=<name>`
+pub fn parse_names(names: &[String]) -> Result<Vec<(Identity, String)>> {
+    names
+        .iter()
+        .map(|entry| {
+            let parsed = entry
+                .split_once('=')
+                .and_then(|(device, name)| Some((parse_identity(device)?, name.trim())));
+            match parsed {
+                Some((identity, name)) if !name.is_empty() => Ok((identity, name.to_string())),
+                _ => Err(Error::Config(format!(
+                    "Invalid name `{entry}` in `--name`, expected `vvvv:pppp[:<serial>]=<name>`!"
+                ))
+                .into()),
+            }
+        })
+        .collect()
+}
+
+fn parse_identity(device: &str) -> Option<Identity> {
+    let mut parts = device.trim().splitn(3, ':');
+    let mut id = || u16::from_str_radix(parts.next()?, 16).ok();
+    let (vendor_id, product_id) = (id()?, id()?);
+    Some((vendor_id, product_id, parts.next().map(str::to_string)))
+}
+
+/// Whether a device is the one given by `pattern`, or of its model when it has no serial
+fn matches(pattern: &Identity, identity: &Identity) -> bool {
+    (pattern.0, pattern.1) == (identity.0, identity.1)
+        && (pattern.2.is_none() || pattern.2 == identity.2)
+}
+
+/// Initializes a device which has a definition, restoring the state it had before it was
+/// unplugged. Returns whether the device was added.
+async fn attach(
+    device: Device,
+    keyboards: &AsyncMutex<IndexMap<KeyboardId, Keyboard>>,
+    pool: &Mutex<Pool>,
+    ranks: &Ranks,
+    states: &States,
+    attached: &Sender<Keyboard>,
+    requests: &Arc<Semaphore>,
+) -> bool {
+    let id = KeyboardId::Device(device.id.clone());
+    if keyboards
+        .lock()
+        .await
+        .get(&id)
+        .is_some_and(|x| !x.is_offline())
+    {
+        return false;
+    }
+    let Some(config) = pool.lock().unwrap().config(&device) else {
+        return false;
+    };
+
+    debug!("Keyboard {} connected!", config.name.bold());
+    let key = (config.vendor_id, config.product_id);
+    match Keyboard::from_config(config, device, requests.clone()).await {
+        Err(error) => {
+            warn!("Failed to initialize keyboard: {error}");
+            false
+        }
+        Ok(keyboard) => {
+            pool.lock().unwrap().connect(key);
+            match states.lock().unwrap().remove(keyboard.identity()) {
+                Some(state) => keyboard.restore_state(state),
+                None => _ = attached.send(keyboard.clone()),
+            }
+            let mut keyboards = keyboards.lock().await;
+            keyboards.insert(id, keyboard);
+            sort_by_slot(&mut keyboards, ranks, Keyboard::identity);
+            true
+        }
+    }
+}
+
+async fn watch_devices(
+    keyboards: Arc<AsyncMutex<IndexMap<KeyboardId, Keyboard>>>,
+    pool: Arc<Mutex<Pool>>,
+    ranks: Ranks,
+    states: States,
+    notifier: Sender<()>,
+    attached: Sender<Keyboard>,
+    requests: Arc<Semaphore>,
+) -> Result<()> {
+    let backend = HidBackend::default();
+    let mut watcher = backend.watch()?;
+
+    while let Some(event) = watcher.next().await {
+        match event {
+            DeviceEvent::Connected(id) => {
+                if keyboards
+                    .lock()
+                    .await
+                    .get(&KeyboardId::Device(id.clone()))
+                    .is_some_and(|x| !x.is_offline())
+                {
+                    continue;
+                }
+                let devices = backend.query_devices(&id).await.ok();
+                let device = devices.and_then(|x| x.filter(is_compatible).next());
+                if let Some(device) = device {
+                    let attach = attach(
+                        device, &keyboards, &pool, &ranks, &states, &attached, &requests,
+                    );
+                    if attach.await {
+                        _ = notifier.send(());
+                    }
+                }
+            }
+            DeviceEvent::Disconnected(id) => {
+                let id = KeyboardId::Device(id);
+                let (config, identity, state) = match keyboards.lock().await.get(&id) {
+                    Some(keyboard) if !keyboard.is_offline() => {
+                        keyboard.set_offline();
+                        let identity = keyboard.identity().clone();
+                        (keyboard.config(), identity, keyboard.save_state().await)
+                    }
+                    _ => continue,
+                };
+                debug!("Keyboard {} disconnected!", config.name.bold());
+
+                // Other keyboards of the same model may still be connected
+                pool.lock()
+                    .unwrap()
+                    .disconnect((config.vendor_id, config.product_id));
+                match state {
+                    Ok(state) => _ = states.lock().unwrap().insert(identity, state),
+                    Err(error) => warn!("Failed to cache the state of {}: {error}", config.name),
+                }
+
+                _ = notifier.send(());
+
+                // Keep the keyboard listed as offline for a while, so clients
+                // that have not re-enumerated yet do not address the wrong device
+                let keyboards = keyboards.clone();
+                let notifier = notifier.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(OFFLINE_TIMEOUT).await;
+                    let mut keyboards = keyboards.lock().await;
+                    if keyboards.get(&id).is_some_and(|x| x.is_offline()) {
+                        keyboards.shift_remove(&id);
+                        _ = notifier.send(());
+                    }
+                });
+            }
+        }
+    }
+
+    Err(Error::Device("Stopped receiving device notifications!".into()).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_definitions_between_identical_keyboards() {
+        let config = Config::fixture("keyboard");
+        let key = (config.vendor_id, config.product_id);
+        let mut pool = Pool {
+            configs: HashMap::from([(key, config)]),
+            ..Default::default()
+        };
+        assert_eq!(pool.pending(), 1);
+
+        pool.connect(key);
+        pool.connect(key);
+        pool.disconnect(key);
+        assert_eq!(pool.pending(), 0);
+        assert_eq!(pool.configs.len(), 1);
+
+        pool.disconnect(key);
+        assert_eq!(pool.pending(), 1);
+    }
+
+    #[test]
+    fn replaces_definitions_of_the_same_model() {
+        let config = Config::fixture("keyboard");
+        let key = (config.vendor_id, config.product_id);
+        let mut pool = Pool::default();
+        assert!(!pool.update(config.clone()));
+
+        let mut updated = config;
+        updated.name = "Updated Keyboard".into();
+        pool.connect(key);
+        assert!(pool.update(updated));
+        assert_eq!(pool.configs.len(), 1);
+        assert_eq!(pool.configs[&key].name, "Updated Keyboard");
+        assert_eq!(pool.pending(), 0);
+    }
+
+    #[test]
+    fn keeps_indices_whatever_the_connection_order() {
+        let ranks = Ranking {
+            definitions: HashMap::from([((1, 1), 0), ((1, 2), 1)]),
+            ..Default::default()
+        };
+        let numpad: Identity = (1, 2, None);
+        let left: Identity = (1, 1, Some("A".into()));
+        let right: Identity = (1, 1, Some("B".into()));
+
+        let connect = |order: &[&Identity]| {
+            let mut keyboards = IndexMap::new();
+            for (i, &identity) in order.iter().enumerate() {
+                keyboards.insert(i, identity.clone());
+                sort_by_slot(&mut keyboards, &ranks, |x| x);
+            }
+            keyboards.into_values().collect::<Vec<_>>()
+        };
+
+        let expected = vec![left.clone(), right.clone(), numpad.clone()];
+        assert_eq!(connect(&[&left, &right, &numpad]), expected);
+        assert_eq!(connect(&[&numpad, &right, &left]), expected);
+        // A missing keyboard leaves no gap, the later ones move up
+        assert_eq!(connect(&[&numpad, &right]), vec![right, numpad]);
+    }
+
+    #[test]
+    fn puts_listed_devices_first() {
+        let order = ["0001:0002", "1:1:B"].map(String::from);
+        let ranks = Ranking {
+            listed: parse_devices("--device-order", &order).unwrap(),
+            definitions: HashMap::from([((1, 1), 0), ((1, 2), 1)]),
+        };
+        let numpad: Identity = (1, 2, None);
+        let left: Identity = (1, 1, Some("A".into()));
+        let right: Identity = (1, 1, Some("B".into()));
+        let other: Identity = (2, 1, None);
+
+        let mut keyboards: IndexMap<_, _> = [&other, &left, &right, &numpad]
+            .into_iter()
+            .enumerate()
+            .collect();
+        sort_by_slot(&mut keyboards, &ranks, |x| x);
+        let sorted: Vec<_> = keyboards.into_values().collect();
+        assert_eq!(sorted, [&numpad, &right, &left, &other]);
+
+        assert!(parse_devices("--device-order", &["1:2:Serial:With:Colons".into()]).is_ok());
+        assert!(parse_devices("--device-order", &["0001".into()]).is_err());
+        assert!(parse_devices("--device-order", &["keyboard:0002".into()]).is_err());
+    }
+
+    #[test]
+    fn names_devices_before_their_models() {
+        let names = ["1:1=Deck", "0001:0001:B = Right Deck"].map(String::from);
+        let pool = Pool {
+            names: parse_names(&names).unwrap(),
+            ..Default::default()
+        };
+
+        let name = |identity: Identity| pool.display_name(&identity);
+        assert_eq!(
+            name((1, 1, Some("B".into()))).as_deref(),
+            Some("Right Deck")
+        );
+        assert_eq!(name((1, 1, Some("A".into()))).as_deref(), Some("Deck"));
+        assert_eq!(name((1, 2, None)), None);
+
+        assert!(parse_names(&["1:1=".into()]).is_err());
+        assert!(parse_names(&["Left Deck".into()]).is_err());
+    }
+
+    #[test]
+    fn excludes_devices_over_including_them() {
+        let parse = |devices: &[&str]| {
+            let devices: Vec<_> = devices.iter().map(|x| x.to_string()).collect();
+            parse_devices("--include", &devices).unwrap()
+        };
+        let filter = Filter {
+            include: parse(&["1:1", "1:2"]),
+            exclude: parse(&["1:1:B", "1:2"]),
+        };
+
+        assert!(filter.allows(&(1, 1, Some("A".into()))));
+        assert!(!filter.allows(&(1, 1, Some("B".into()))));
+        assert!(!filter.allows(&(1, 2, None)));
+        assert!(!filter.allows(&(2, 1, None)));
+
+        assert!(!filter.rejects_model((1, 1)));
+        assert!(filter.rejects_model((1, 2)));
+        assert!(filter.rejects_model((2, 1)));
+        assert!(!Filter::default().rejects_model((2, 1)));
+    }
+
+    #[tokio::test]
+    async fn groups_identical_keyboards() {
+        let config = Config::fixture("keyboard");
+        let key = (config.vendor_id, config.product_id);
+        let group = |members: &[&str]| GroupConfig {
+            name: "Desk".into(),
+            members: members.iter().map(|x| x.to_string()).collect(),
+        };
+        let groups = vec![
+            group(&["Test Keyboard", "362d:0210"]),
+            group(&["Test Keyboard"]),
+        ];
+        let configs = IndexMap::from([(key, config.clone())]);
+        let keyboards = Keyboards::simulate(configs, groups, vec![], vec![], Filter::default(), 8)
+            .await
+            .unwrap();
+        let second = Keyboard::from_virtual(config, 1, keyboards.requests.clone())
+            .await
+            .unwrap();
+        (keyboards.keyboards.lock().await).insert(KeyboardId::Virtual(1), second);
+
+        // Both keyboards are taken by the first group, which leaves the second one degraded
+        assert_eq!(keyboards.count().await, 2);
+        let first = keyboards.controller(0).await.unwrap().snapshot().await;
+        assert_eq!(first.config.count_leds(), 12);
+        assert!(!first.degraded && !first.offline);
+        let second = keyboards.controller(1).await.unwrap().snapshot().await;
+        assert!(second.offline);
+        assert!(keyboards.controller(2).await.is_none());
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use futures::future;
+use log::{debug, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{error::Error, utils::Stream};
+
+/// Accepts clients on every address the server listens on
+pub struct Listener {
+    listeners: Vec<TcpListener>,
+}
+
+impl Listener {
+    /// Listens on the address, and on the same one in the other IP family for loopback and
+    /// unspecified addresses, so dual-stack clients can connect either way
+    pub fn bind(address: SocketAddr) -> Result<Self> {
+        let listener = listen(address)
+            .map_err(|x| Error::Bind(format!("Failed to listen on {address}: {x}")))?;
+        if !address.ip().is_loopback() {
+            warn!(
+                "Listening on {address}, anyone on the network can control the keyboards unless `--auth-token` is set!"
+            );
+        }
+
+        let mut listeners = vec![listener];
+        if let Some(ip) = counterpart(address.ip()) {
+            // Binding port 0 must end up on the same port in both families
+            let port = listeners[0].local_addr()?.port();
+            match listen(SocketAddr::new(ip, port)) {
+                Ok(listener) => listeners.push(listener),
+                Err(error) => debug!("Not listening on {ip}: {error}"),
+            }
+        }
+
+        Ok(Self { listeners })
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let accepts = self.listeners.iter().map(|x| Box::pin(x.accept()));
+        future::select_all(accepts).await.0
+    }
+
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|x| x.local_addr().ok())
+            .collect()
+    }
+}
+
+/// Accepts clients over a Unix domain socket, which is removed once the server stops
+pub struct LocalListener {
+    path: PathBuf,
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+}
+
+impl LocalListener {
+    #[cfg(unix)]
+    pub fn bind(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        // A socket left behind by a crashed server would make binding fail
+        if std::fs::metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)
+            .map_err(|x| Error::Bind(format!("Failed to listen on {path:?}: {x}")))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(_path: &Path) -> Result<Self> {
+        Err(Error::Config("Unix sockets are not supported on this platform!".into()).into())
+    }
+
+    #[cfg(unix)]
+    async fn accept(&self) -> io::Result<impl Stream + 'static> {
+        Ok(self.listener.accept().await?.0)
+    }
+
+    #[cfg(not(unix))]
+    async fn accept(&self) -> io::Result<impl Stream + 'static> {
+        std::future::pending::<io::Result<TcpStream>>().await
+    }
+}
+
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Waits for a local client, or forever when there is no socket to listen on
+pub async fn accept_local(listener: &Option<LocalListener>) -> io::Result<impl Stream + 'static> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn counterpart(ip: IpAddr) -> Option<IpAddr> {
+    match ip {
+        IpAddr::V4(Ipv4Addr::LOCALHOST) => Some(Ipv6Addr::LOCALHOST.into()),
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED) => Some(Ipv6Addr::UNSPECIFIED.into()),
+        IpAddr::V6(Ipv6Addr::LOCALHOST) => Some(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED) => Some(Ipv4Addr::UNSPECIFIED.into()),
+        _ => None,
+    }
+}
+
+fn listen(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    // IPv6 sockets would take over IPv4 as well on some systems, which is left to its own socket
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn accepts_clients_of_both_families() {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addresses = listener.addresses();
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0].port(), addresses[1].port());
+
+        for address in addresses {
+            let client = TcpStream::connect(address).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_clients_over_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("colorhoster-{}.sock", std::process::id()));
+        let listener = Some(LocalListener::bind(&path).unwrap());
+
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let mut server = accept_local(&listener).await.unwrap();
+        server.write_response(40, &[3, 0, 0, 0]).await.unwrap();
+
+        let mut response = [0; 20];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..4], b"ORGB");
+        assert_eq!(response[16..], [3, 0, 0, 0]);
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// A log file which is moved aside once it grows past `max_size` bytes, keeping the last
+/// `keep` of them as `<name>.1` (the most recent) to `<name>.<keep>`. Records arrive in
+/// several writes, so they are buffered up to the end of a line and never split between files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    buffer: Vec<u8>,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            buffer: Vec::new(),
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep > 0 {
+            for index in (1..self.keep).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Writes out the first `length` buffered bytes, rotating the file beforehand if needed
+    fn commit(&mut self, length: usize) -> io::Result<()> {
+        // A single oversized message is still written whole, rather than split between files
+        if self.size > 0 && self.size + length as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&self.buffer[..length])?;
+        self.buffer.drain(..length);
+        self.size += length as u64;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if let Some(end) = self.buffer.iter().rposition(|&x| x == b'\n') {
+            self.commit(end + 1)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.commit(self.buffer.len())?;
+        }
+        self.file.flush()
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_latest_rotated_files() {
+        let directory = std::env::temp_dir().join("colorhoster-rotation");
+        _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("colorhoster.log");
+
+        let mut log = RotatingFile::open(&path, 8, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(directory.join(name)).ok();
+        assert_eq!(read("colorhoster.log").as_deref(), Some("fourth\n"));
+        assert_eq!(read("colorhoster.log.1").as_deref(), Some("third\n"));
+        assert_eq!(read("colorhoster.log.2").as_deref(), Some("second\n"));
+        assert_eq!(read("colorhoster.log.3"), None);
+
+        // The size of an existing file counts towards the limit after a restart
+        let mut log = RotatingFile::open(&path, 8, 0).unwrap();
+        log.write_all(b"fifth\n").unwrap();
+        assert_eq!(read("colorhoster.log").as_deref(), Some("fifth\n"));
+        assert_eq!(read("colorhoster.log.2").as_deref(), Some("second\n"));
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn rotates_only_between_lines() {
+        let directory = std::env::temp_dir().join("colorhoster-rotation-lines");
+        _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("colorhoster.log");
+
+        let mut log = RotatingFile::open(&path, 8, 1).unwrap();
+        for piece in ["first", "\n", "sec", "ond", "\n"] {
+            log.write_all(piece.as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(directory.join(name)).unwrap();
+        assert_eq!(read("colorhoster.log"), "second\n");
+        assert_eq!(read("colorhoster.log.1"), "first\n");
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
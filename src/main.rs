@@ -1,36 +1,61 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
+mod announce;
+mod bridge;
 mod cli;
+mod commands;
 mod config;
 mod consts;
+mod definitions;
+mod error;
+mod group;
 mod handlers;
+mod http;
 mod keyboard;
 mod keyboards;
+mod listener;
+mod logs;
+mod orp;
+mod pidfile;
+mod profiles;
+mod recorder;
+mod render;
 mod report;
+mod supervisor;
 mod utils;
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use ceviche::controller::*;
 use ceviche::{Service, ServiceEvent};
 use colored::Colorize;
-use itertools::Itertools;
+use futures::future;
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use socket2::{SockRef, TcpKeepalive};
 use std::env;
-use std::fs;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
-use tokio::{
-    io::AsyncReadExt,
-    net::{TcpListener, TcpStream},
-};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_util::sync::CancellationToken;
 
+use bridge::{Bridge, Session};
 use cli::{CLI, ServiceAction};
-use config::Config;
-use consts::Request;
-use handlers::{HandlerContext, handle};
-use keyboards::Keyboards;
-use utils::{ErrorExt, StreamExt};
+use consts::{DEFAULT_MAX_CLIENTS, DEFAULT_MAX_REQUEST_LENGTH, DEFAULT_MAX_REQUESTS, Request};
+use error::Error;
+use handlers::{ClientRate, HandlerContext, RateLimit, handle, pace};
+use keyboards::{Filter, Keyboards, Origin, parse_devices, parse_names};
+use listener::{Listener, LocalListener, accept_local};
+use pidfile::PidFile;
+use profiles::ProfileLimits;
+use recorder::Recorder;
+use utils::{ErrorExt, Stream, StreamExt, TimedStream};
+
+const PERSIST_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn main() {
     let mut controller = Controller::new(
@@ -41,8 +66,16 @@ fn main() {
 
     let args = CLI::parse_args(env::args());
 
+    if let Some(command) = args.command.clone() {
+        utils::setup_logger(&args);
+        if let Err(error) = commands::run(command, args) {
+            error!("Error: {error}");
+        }
+        return;
+    }
+
     if let Some(ServiceAction::Create) = args.service {
-        utils::setup_logger();
+        utils::setup_logger(&args);
         match args.save_to_config() {
             Err(error) => error!("Failed to write service config: {error}"),
             Ok(true) => debug!("Service config created: {:?}", CLI::config_path()),
@@ -72,7 +105,7 @@ fn main() {
     };
 
     if let Err(error) = result {
-        utils::setup_logger();
+        utils::setup_logger(&args);
         error!("Error: {error}");
     }
 }
@@ -84,8 +117,8 @@ fn service_main(
     args: Vec<String>,
     _standalone_mode: bool,
 ) -> u32 {
-    utils::setup_logger();
     let args = CLI::parse_args(args);
+    utils::setup_logger(&args);
     let interrupt = CancellationToken::new();
     let runtime = Runtime::new().expect("Failed to create async runtime!");
 
@@ -111,7 +144,12 @@ fn service_main(
         Ok(Ok(())) => return 0,
         Ok(Err(error)) => {
             error!("Error: {}", error);
-            return 1;
+            error!(
+                "Exiting with code {} due to {} error.",
+                error.exit_code(),
+                error.class()
+            );
+            return error.exit_code();
         }
         Err(error) => {
             error!("Task execution failed: {}", error);
@@ -121,109 +159,488 @@ fn service_main(
 }
 
 async fn run(args: CLI, interrupt: CancellationToken) -> Result<()> {
-    let keyboards = load_keyboards(args.directory, args.json).await?;
+    // Removed once the server stops, which is also what a stop request or Ctrl+C leads to
+    let _pidfile = args.pidfile.as_deref().map(PidFile::create).transpose()?;
+
+    if let Some(upstream) = args.upstream.clone() {
+        return run_bridge(args, upstream, interrupt).await;
+    }
+
+    let keyboards = load_keyboards(&args).await?;
     reset_brightness(&keyboards, args.brightness).await?;
+    #[cfg(unix)]
+    rescan_on_hangup(keyboards.clone(), interrupt.clone())?;
+    if let Err(error) = definitions::watch(args.clone(), keyboards.clone(), interrupt.clone()) {
+        warn!("Failed to watch keyboard definitions: {error}");
+    }
 
-    let profiles_dir = args
-        .profiles
-        .unwrap_or_else(|| CLI::current_dir().join(PathBuf::from("./profiles")));
+    let profiles_dir = args.profiles_dir();
 
     tokio::fs::create_dir_all(&profiles_dir).await?;
+    if args.watch_profiles {
+        profiles::watch(
+            profiles_dir.clone(),
+            keyboards.clone(),
+            args.brightness,
+            interrupt.clone(),
+        )?;
+    }
+
+    let recorder = args.record.as_deref().map(Recorder::create).transpose()?;
+
+    if let Some(port) = args.http_port {
+        let address = SocketAddr::new(args.host, port);
+        http::serve(
+            address,
+            keyboards.clone(),
+            args.brightness,
+            args.auth_token.clone(),
+            interrupt.clone(),
+        )
+        .await?;
+    }
+
+    let listener = Listener::bind(args.address())?;
+    debug!("Started TCP server at {:?}!", listener.addresses());
+    if args.announce {
+        if let Err(error) = announce::announce(args.address(), interrupt.clone()) {
+            warn!("Failed to announce the server: {error}");
+        }
+    }
+
+    // Keyboards attached later get the default profile as well, so it is not held back
+    if let Some(profile) = &args.default_profile {
+        profiles::apply_default(
+            profiles_dir.clone(),
+            profile.clone(),
+            &keyboards,
+            args.brightness,
+            interrupt.clone(),
+        )
+        .await;
+    }
+
+    let local = args
+        .socket
+        .as_deref()
+        .map(LocalListener::bind)
+        .transpose()?;
+    let connections = AtomicUsize::new(0);
+    let context = || HandlerContext {
+        id: connections.fetch_add(1, Ordering::Relaxed),
+        client: None,
+        protocol_version: 0,
+        requested_version: None,
+        keyboards: keyboards.clone(),
+        interrupt: interrupt.clone(),
+        with_brightness: args.brightness,
+        profiles_dir: profiles_dir.clone(),
+        profile_format: args.profile_format(),
+        auth_token: args.auth_token.clone(),
+        authenticated: args.auth_token.is_none(),
+        profile_limits: ProfileLimits {
+            count: args.max_profiles,
+            bytes: args.max_profile_bytes,
+        },
+        max_request_length: args.max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_LENGTH),
+        client_timeout: args.client_timeout.map(Duration::from_secs),
+        profile_retention: args.profile_retention(),
+        recorder: recorder.clone(),
+        rate_limit: args.max_client_rate.map(|requests| RateLimit {
+            requests,
+            throttle: args.throttle_clients,
+        }),
+        rate: ClientRate::default(),
+    };
+
+    // Clients are served during the startup grace, only the keyboards are waited for
+    let startup = async {
+        let count = wait_for_keyboards(&keyboards, args.startup_grace).await;
+        require_devices(&keyboards, count, args.require_all_devices)?;
+        info!("{}", startup_message(count));
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::pin!(startup);
+    let mut starting = true;
+
+    let clients = client_limit(&args);
+    loop {
+        tokio::select! {
+            result = &mut startup, if starting => {
+                starting = false;
+                result?;
+            }
+            client = listener.accept() => {
+                let (stream, _) = client?;
+                if let Some(timeout) = args.client_timeout.map(Duration::from_secs) {
+                    let keepalive = TcpKeepalive::new()
+                        .with_time(timeout)
+                        .with_interval(timeout);
+                    if let Err(error) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+                        warn!("Failed to enable TCP keepalive: {error}");
+                    }
+                }
+                serve(stream, context(), &clients);
+            }
+            client = accept_local(&local) => serve(client?, context(), &clients),
+            _ = interrupt.cancelled() => break,
+        };
+    }
+
+    if args.persist_on_exit {
+        persist_on_exit(&keyboards).await;
+    }
+    Ok(())
+}
+
+fn serve(stream: impl Stream + 'static, mut ctx: HandlerContext, clients: &Arc<Semaphore>) {
+    let Some(permit) = admit(clients) else {
+        return;
+    };
+    tokio::spawn(async move {
+        let result = handle_connection(stream, &mut ctx).await;
+        report_disconnect(ctx.client, result);
+        drop(permit);
+    });
+}
+
+fn client_limit(args: &CLI) -> Arc<Semaphore> {
+    Arc::new(Semaphore::new(
+        args.max_clients.unwrap_or(DEFAULT_MAX_CLIENTS),
+    ))
+}
+
+/// Takes a slot for a new client, refusing it when every slot is taken
+fn admit(clients: &Arc<Semaphore>) -> Option<OwnedSemaphorePermit> {
+    let permit = clients.clone().try_acquire_owned().ok();
+    if permit.is_none() {
+        warn!("Refused a client, as too many are connected already (see `--max-clients`)!");
+    }
+    permit
+}
 
-    let address = format!("127.0.0.1:{}", args.port);
-    let listener = TcpListener::bind(&address).await?;
-    debug!("Started TCP server at {}!", address);
-    info!("The application is running successfully!");
+async fn run_bridge(args: CLI, upstream: String, interrupt: CancellationToken) -> Result<()> {
+    let address = args.address();
+    let listener = Listener::bind(address)?;
+    let bridge = Bridge::new(
+        upstream,
+        args.max_request_bytes.unwrap_or(DEFAULT_MAX_REQUEST_LENGTH),
+    );
+    info!(
+        "Bridging devices of {} at {}!",
+        bridge.address().bold(),
+        address
+    );
 
+    let clients = client_limit(&args);
     loop {
         let (stream, _) = tokio::select! {
             client = listener.accept() => client?,
             _ = interrupt.cancelled() => return Ok(()),
         };
-
-        let mut ctx = HandlerContext {
-            client: None,
-            keyboards: keyboards.clone(),
-            interrupt: interrupt.clone(),
-            with_brightness: args.brightness,
-            profiles_dir: profiles_dir.clone(),
+        let Some(permit) = admit(&clients) else {
+            continue;
         };
 
+        let bridge = bridge.clone();
+        let interrupt = interrupt.clone();
         tokio::spawn(async move {
-            match handle_connection(stream, &mut ctx).await {
-                Err(error) if error.is_disconnect() => {
-                    debug!(
-                        "Client {} disconnected.",
-                        ctx.client.unwrap_or("Unknown".to_string()).bold()
-                    )
-                }
-                Err(error) => warn!(
-                    "{}\x1B[33m disconnected due to an error: {error}",
-                    ctx.client.unwrap_or("Unknown".to_string()).bold()
-                ),
-                Ok(()) => (),
-            }
+            let mut session = Session::new(bridge).await;
+            let result = session.serve(stream, interrupt).await;
+            report_disconnect(session.client, result);
+            drop(permit);
         });
     }
 }
 
-async fn handle_connection(mut stream: TcpStream, ctx: &mut HandlerContext) -> Result<()> {
+fn report_disconnect(client: Option<String>, result: Result<()>) {
+    let client = client.unwrap_or("Unknown".to_string());
+    match result {
+        Err(error) if error.is_disconnect() => {
+            debug!("Client {} disconnected.", client.bold())
+        }
+        Err(error) => warn!(
+            "{}\x1B[33m disconnected due to an error: {error}",
+            client.bold()
+        ),
+        Ok(()) => (),
+    }
+}
+
+async fn handle_connection(stream: impl Stream, ctx: &mut HandlerContext) -> Result<()> {
     let mut device_notification = ctx.keyboards.subscribe();
+    let mut changes = ctx.keyboards.subscribe_changes();
+    // Clients may stay idle between requests, but not stall in the middle of one
+    let mut stream = TimedStream::new(stream, ctx.client_timeout);
 
     loop {
         let magic = tokio::select! {
-            data = stream.read_u32_le() => data?,
+            data = stream.idle().read_u32_le() => data?,
             _ = ctx.interrupt.cancelled() => return Ok(()),
             _ = device_notification.recv() => {
                 stream.write_response(Request::DeviceListUpdated.into(), &[]).await?;
                 continue;
             }
+            change = changes.recv() => {
+                // Changes made in a quick succession are announced once
+                let mut foreign = is_foreign(change, ctx.id);
+                while let Ok(change) = changes.try_recv() {
+                    foreign |= is_foreign(Ok(change), ctx.id);
+                }
+                if foreign {
+                    stream.write_response(Request::DeviceListUpdated.into(), &[]).await?;
+                }
+                continue;
+            }
         };
         if magic != 1111970383 {
-            return Err(anyhow!("Invalid packet header!"));
+            return Err(Error::Protocol("Invalid packet header!".into()).into());
         }
 
+        pace(ctx).await;
         let device = stream.read_u32_le().await?;
         let kind = stream.read_u32_le().await?;
-
         handle(kind, device, &mut stream, ctx).await?;
     }
 }
 
-async fn load_keyboards(directory: Option<PathBuf>, json: Vec<PathBuf>) -> Result<Keyboards> {
-    let configs = directory
-        .unwrap_or(CLI::current_dir())
-        .read_dir()?
-        .filter_map(|path| {
-            let path = path.as_ref().ok()?.path();
-            if path.extension()?.to_str() == Some("json") {
-                Some(path)
-            } else {
-                None
-            }
-        })
-        .chain(json.into_iter())
-        .filter_map(|x| fs::read_to_string(x).ok())
-        .unique()
-        .map(|x| Config::from_str(&x).map(|config| ((config.vendor_id, config.product_id), config)))
-        .collect::<Result<HashMap<_, _>>>()?;
+/// Whether a change was made by anyone but the connection, where missed changes count as well
+fn is_foreign(change: Result<Origin, RecvError>, id: usize) -> bool {
+    !matches!(change, Ok(Some(origin)) if origin == id)
+}
+
+async fn load_keyboards(args: &CLI) -> Result<Keyboards> {
+    if args.gamma.is_some_and(|x| x <= 0.0 || !x.is_finite()) {
+        return Err(Error::Config("`--gamma` must be a positive number!".into()).into());
+    }
+    let max_requests = args.max_requests.unwrap_or(DEFAULT_MAX_REQUESTS);
+    if max_requests == 0 {
+        return Err(Error::Config("`--max-requests` must be at least 1!".into()).into());
+    }
+
+    let configs = definitions::read(args)?;
+    let order = parse_devices("--device-order", &args.device_order)?;
+    let names = parse_names(&args.names)?;
+    let filter = Filter {
+        include: parse_devices("--include", &args.include)?,
+        exclude: parse_devices("--exclude", &args.exclude)?,
+    };
 
     if configs.is_empty() {
-        return Err(anyhow!("No keyboard `.json` files found!"));
+        return Err(Error::Config("No valid keyboard `.json` files found!".into()).into());
     }
 
-    let keyboards = Keyboards::from_configs(configs).await?;
-    keyboards.watch()?;
+    if args.dry_run {
+        return Keyboards::simulate(
+            configs,
+            args.groups.clone(),
+            order,
+            names,
+            filter,
+            max_requests,
+        )
+        .await;
+    }
+
+    let keyboards = Keyboards::from_configs(
+        configs,
+        args.groups.clone(),
+        order,
+        names,
+        filter,
+        max_requests,
+    )
+    .await?;
+    keyboards.watch();
     Ok(keyboards)
 }
 
+/// Rescans for keyboards on `SIGHUP`, e.g. `kill -HUP` after plugging one in
+#[cfg(unix)]
+fn rescan_on_hangup(keyboards: Keyboards, interrupt: CancellationToken) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                signal = hangup.recv() => if signal.is_none() { return },
+                _ = interrupt.cancelled() => return,
+            }
+            match keyboards.rescan().await {
+                Ok(added) => info!("Rescan found {added} new keyboard(s)."),
+                Err(error) => warn!("Failed to rescan for keyboards: {error}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Saves the state of every keyboard to its firmware, giving up on the ones that do not
+/// answer within `PERSIST_TIMEOUT` so they cannot hold the shutdown
+async fn persist_on_exit(keyboards: &Keyboards) {
+    let online: Vec<_> = keyboards
+        .items()
+        .await
+        .values()
+        .filter(|x| !x.is_offline())
+        .cloned()
+        .collect();
+    let persist = future::join_all(online.iter().map(async |keyboard| {
+        keyboard.persist_state();
+        keyboard.flush().await;
+    }));
+
+    match tokio::time::timeout(PERSIST_TIMEOUT, persist).await {
+        Ok(_) => debug!("Saved the state of {} keyboard(s).", online.len()),
+        Err(_) => warn!("Gave up saving the state of keyboards after {PERSIST_TIMEOUT:?}!"),
+    }
+}
+
+async fn wait_for_keyboards(keyboards: &Keyboards, grace: u64) -> usize {
+    let mut device_notification = keyboards.subscribe();
+    let deadline = tokio::time::sleep(Duration::from_secs(grace));
+    tokio::pin!(deadline);
+
+    while keyboards.pending() > 0 {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = device_notification.recv() => {}
+        }
+    }
+
+    // Keyboards unplugged meanwhile stay listed as offline for a while
+    let keyboards = keyboards.items().await;
+    keyboards.values().filter(|x| !x.is_offline()).count()
+}
+
+fn require_devices(keyboards: &Keyboards, count: usize, all: bool) -> Result<()> {
+    if all && keyboards.pending() > 0 {
+        return Err(Error::NoDevices(format!(
+            "Only {count} keyboard(s) initialized, but all devices are required!"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn startup_message(count: usize) -> String {
+    format!("The application is running successfully with {count} keyboard(s)!")
+}
+
 async fn reset_brightness(keyboards: &Keyboards, with_brightness: bool) -> Result<()> {
-    if !with_brightness {
-        let keyboards = keyboards.items().await;
-        for keyboard in keyboards.values() {
+    let keyboards = keyboards.items().await;
+    for keyboard in keyboards.values() {
+        if !keyboard.config().writes_brightness(with_brightness) {
             keyboard.reset_brightness();
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn read_header(server: &mut TimedStream<TcpStream>) -> Result<()> {
+        server.read_u32_le().await?;
+        server.read_u32_le().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reaps_stalled_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = TimedStream::new(server, Some(Duration::from_millis(200)));
+        // A slow client is fine as long as the request arrives within the timeout
+        client.write_u32_le(0).await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_u32_le(1).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            client.write_u32_le(2).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        assert!(read_header(&mut server).await.is_ok());
+
+        // Waiting for the next request is not limited, a stall in the middle of it is
+        assert_eq!(server.idle().read_u32_le().await.unwrap(), 2);
+        let error = server.read_u32_le().await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(anyhow::Error::from(error).is_disconnect());
+    }
+
+    #[test]
+    fn refuses_clients_over_the_limit() {
+        let clients = Arc::new(Semaphore::new(2));
+        let first = admit(&clients).unwrap();
+        let _second = admit(&clients).unwrap();
+        assert!(admit(&clients).is_none());
+
+        drop(first);
+        assert!(admit(&clients).is_some());
+    }
+
+    #[test]
+    fn forwards_changes_of_other_clients() {
+        assert!(!is_foreign(Ok(Some(1)), 1));
+        assert!(is_foreign(Ok(Some(2)), 1));
+        assert!(is_foreign(Ok(None), 1));
+        assert!(is_foreign(Err(RecvError::Lagged(3)), 1));
+    }
+
+    async fn simulated() -> Keyboards {
+        let config = Config::fixture("keyboard");
+        let key = (config.vendor_id, config.product_id);
+        let configs = [(key, config)].into_iter().collect();
+        Keyboards::simulate(configs, vec![], vec![], vec![], Filter::default(), 8)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn starts_once_every_keyboard_is_up() {
+        let keyboards = simulated().await;
+        let wait = wait_for_keyboards(&keyboards, 60);
+        let count = tokio::time::timeout(Duration::from_secs(1), wait)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(require_devices(&keyboards, count, true).is_ok());
+        assert!(startup_message(count).contains(" 1 keyboard(s)"));
+    }
+
+    #[tokio::test]
+    async fn counts_only_online_keyboards() {
+        let keyboards = simulated().await;
+        (keyboards.items().await.values()).for_each(|x| x.set_offline());
+
+        let count = wait_for_keyboards(&keyboards, 0).await;
+        assert_eq!(count, 0);
+        assert!(startup_message(count).contains(" 0 keyboard(s)"));
+    }
+
+    #[tokio::test]
+    async fn requires_all_devices_after_the_grace() {
+        let keyboards = simulated().await;
+        let mut missing = Config::fixture("keyboard");
+        missing.product_id += 1;
+        keyboards.update_configs([missing]);
+        assert_eq!(keyboards.pending(), 1);
+
+        assert_eq!(wait_for_keyboards(&keyboards, 0).await, 1);
+        assert!(require_devices(&keyboards, 1, false).is_ok());
+        let error = require_devices(&keyboards, 1, true).unwrap_err();
+        assert_eq!(error.exit_code(), 4);
+    }
+}
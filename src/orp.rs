@@ -0,0 +1,385 @@
+use anyhow::Result;
+use palette::{encoding::Srgb, rgb::Rgb};
+
+use crate::{config::Config, consts::OPENRGB_PROTOCOL_VERSION, error::Error};
+
+/// Magic bytes at the start of OpenRGB profiles
+const HEADER: &[u8; 16] = b"OPENRGB_PROFILE\0";
+
+/// A device as saved in an OpenRGB profile
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedController {
+    pub name: String,
+    pub serial: String,
+    pub active_mode: usize,
+    pub modes: Vec<SavedMode>,
+    pub zones: Vec<SavedZone>,
+    pub leds: Vec<String>,
+    pub colors: Vec<Rgb<Srgb, u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedMode {
+    pub name: String,
+    pub value: i32,
+    pub flags: u32,
+    pub speed_range: (u32, u32),
+    /// Only saved since protocol version 3
+    pub brightness_range: Option<(u32, u32)>,
+    pub speed: u32,
+    /// Only saved since protocol version 3
+    pub brightness: Option<u32>,
+    pub direction: u32,
+    pub colors: Vec<Rgb<Srgb, u8>>,
+}
+
+impl SavedController {
+    pub fn active_mode(&self) -> Result<&SavedMode> {
+        self.modes.get(self.active_mode).ok_or_else(|| {
+            Error::Protocol(format!("Profile of {} has no active mode!", self.name)).into()
+        })
+    }
+}
+
+/// Builds a profile out of `GetControllerData` descriptions of the current protocol version
+pub fn serialize(controllers: &[Vec<u8>]) -> Vec<u8> {
+    let mut buffer = HEADER.to_vec();
+    buffer.extend_from_slice(&OPENRGB_PROTOCOL_VERSION.to_le_bytes());
+    for controller in controllers {
+        buffer.extend_from_slice(controller);
+    }
+    buffer
+}
+
+pub fn parse(data: &[u8]) -> Result<Vec<SavedController>> {
+    let mut reader = Reader { data, offset: 0 };
+    if reader.take(HEADER.len())? != HEADER {
+        return Err(Error::Protocol("Not an OpenRGB profile!".into()).into());
+    }
+    let version = reader.u32()?;
+    if version > OPENRGB_PROTOCOL_VERSION {
+        return Err(Error::Protocol(format!("Unsupported profile version {version}!")).into());
+    }
+
+    let mut controllers = Vec::new();
+    while reader.offset < data.len() {
+        let size = reader.peek_u32()? as usize;
+        if size < 4 {
+            return Err(Error::Protocol("Malformed OpenRGB profile!".into()).into());
+        }
+        let controller = reader.take(size)?;
+        controllers.push(parse_controller(controller, version)?);
+    }
+    Ok(controllers)
+}
+
+/// Picks the controller saved for a keyboard, preferring the very same device and then
+/// the one of the same model. Controllers are saved under the names shown to clients.
+pub fn find<'a>(
+    controllers: &'a [SavedController],
+    config: &Config,
+) -> Result<&'a SavedController> {
+    let id = config.hardware_id();
+    let model = format!("{:04x}:{:04x}", config.vendor_id, config.product_id);
+    let named = || {
+        (controllers.iter()).filter(|x| x.name == config.name || x.name == config.display_name())
+    };
+    named()
+        .find(|x| x.serial == id)
+        .or_else(|| named().find(|x| x.serial.split(':').take(2).eq(model.split(':'))))
+        .or_else(|| named().next())
+        .ok_or_else(|| {
+            Error::Protocol(format!("Profile has nothing saved for {}!", config.name)).into()
+        })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedZone {
+    pub name: String,
+    pub leds: u32,
+    /// Width, height and the LED index within the zone of each position, if it has a matrix
+    pub matrix: Option<(u32, u32, Vec<u32>)>,
+}
+
+/// Reads a description laid out like `controller_data` in the handlers
+fn parse_controller(data: &[u8], protocol: u32) -> Result<SavedController> {
+    let mut reader = Reader { data, offset: 8 };
+    let name = reader.str()?;
+    if protocol >= 1 {
+        reader.str()?; // Vendor
+    }
+    reader.str()?; // Description
+    reader.str()?; // Version
+    let serial = reader.str()?;
+    reader.str()?; // Location
+
+    let mode_count = reader.u16()?;
+    let active_mode = reader.u32()? as usize;
+    let mut modes = Vec::new();
+    for _ in 0..mode_count {
+        let name = reader.str()?;
+        let value = reader.u32()? as i32;
+        let flags = reader.u32()?;
+        let speed_range = (reader.u32()?, reader.u32()?);
+        let brightness_range = if protocol >= 3 {
+            Some((reader.u32()?, reader.u32()?))
+        } else {
+            None
+        };
+        reader.take(2 * 4)?; // Range of the color count
+        let speed = reader.u32()?;
+        let brightness = if protocol >= 3 {
+            Some(reader.u32()?)
+        } else {
+            None
+        };
+        let direction = reader.u32()?;
+        reader.u32()?; // Color mode
+        let colors = reader.colors()?;
+        modes.push(SavedMode {
+            name,
+            value,
+            flags,
+            speed_range,
+            brightness_range,
+            speed,
+            brightness,
+            direction,
+            colors,
+        });
+    }
+
+    let mut zones = Vec::new();
+    for _ in 0..reader.u16()? {
+        let name = reader.str()?;
+        reader.take(3 * 4)?; // Type and the range of the LED count
+        let leds = reader.u32()?;
+        let matrix = match reader.u16()? {
+            0 => None,
+            _ => {
+                let height = reader.u32()?;
+                let width = reader.u32()?;
+                let map = (0..width as u64 * height as u64)
+                    .map(|_| reader.u32())
+                    .collect::<Result<_>>()?;
+                Some((width, height, map))
+            }
+        };
+        if protocol >= 4 {
+            for _ in 0..reader.u16()? {
+                reader.str()?;
+                reader.take(3 * 4)?;
+            }
+        }
+        zones.push(SavedZone { name, leds, matrix });
+    }
+
+    let mut leds = Vec::new();
+    for _ in 0..reader.u16()? {
+        leds.push(reader.str()?);
+        reader.u32()?;
+    }
+
+    Ok(SavedController {
+        name,
+        serial,
+        active_mode,
+        modes,
+        zones,
+        leds,
+        colors: reader.colors()?,
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + count)
+            .ok_or_else(|| Error::Protocol("Malformed OpenRGB profile!".into()))?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn peek_u32(&mut self) -> Result<u32> {
+        let value = self.u32()?;
+        self.offset -= 4;
+        Ok(value)
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let length = self.u16()? as usize;
+        let bytes = self.take(length)?;
+        let text = String::from_utf8_lossy(bytes);
+        Ok(text.trim_end_matches('\0').to_string())
+    }
+
+    fn colors(&mut self) -> Result<Vec<Rgb<Srgb, u8>>> {
+        (0..self.u16()?)
+            .map(|_| {
+                let bytes = self.take(4)?;
+                Ok(Rgb::new(bytes[0], bytes[1], bytes[2]))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handlers::controller_data, keyboard::KeyboardSnapshot};
+
+    fn snapshot() -> KeyboardSnapshot {
+        let config = Config::fixture("gradient");
+        let leds = config.count_leds() as u8;
+        KeyboardSnapshot {
+            colors: (0..leds).map(|x| Rgb::new(x, 0, 255)).collect(),
+            mode_colors: vec![Rgb::new(0, 255, 0), Rgb::new(255, 255, 0)],
+            effect: 3,
+            direction: 1,
+            ..KeyboardSnapshot::for_test(config)
+        }
+    }
+
+    #[test]
+    fn reads_back_saved_controllers() {
+        let keyboard = snapshot();
+        let profile = serialize(&[controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION)]);
+        assert_eq!(&profile[..16], HEADER);
+
+        let controllers = parse(&profile).unwrap();
+        let saved = find(&controllers, &keyboard.config).unwrap();
+        assert_eq!(saved.serial, "362d:0210");
+        assert_eq!(saved.colors, keyboard.colors);
+
+        let mode = saved.active_mode().unwrap();
+        assert_eq!((mode.name.as_str(), mode.value), ("Reactive Gradient", 3));
+        assert_eq!(
+            (mode.speed, mode.brightness, mode.direction),
+            (128, Some(200), 1)
+        );
+        assert_eq!(
+            mode.colors,
+            [
+                keyboard.color,
+                keyboard.mode_colors[0],
+                keyboard.mode_colors[1]
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_profiles_of_older_versions() {
+        let keyboard = snapshot();
+        let mut profile = HEADER.to_vec();
+        profile.extend_from_slice(&2u32.to_le_bytes());
+        profile.extend_from_slice(&controller_data(&keyboard, 2));
+        profile.extend_from_slice(&controller_data(&keyboard, 2));
+
+        let controllers = parse(&profile).unwrap();
+        assert_eq!(controllers.len(), 2);
+        assert_eq!(controllers[1].active_mode().unwrap().brightness, None);
+        assert_eq!(controllers[1].colors, keyboard.colors);
+
+        profile.truncate(profile.len() - 1);
+        assert!(parse(&profile).is_err());
+        assert!(parse(b"{\"colors\": []}").is_err());
+    }
+
+    #[test]
+    fn prefers_the_same_device() {
+        let mut left = snapshot();
+        left.config.serial = Some("LEFT".into());
+        let mut right = snapshot();
+        right.config.serial = Some("RIGHT".into());
+        right.effect = 2;
+        let profile = serialize(&[
+            controller_data(&left, OPENRGB_PROTOCOL_VERSION),
+            controller_data(&right, OPENRGB_PROTOCOL_VERSION),
+        ]);
+        let controllers = parse(&profile).unwrap();
+
+        let saved = find(&controllers, &right.config).unwrap();
+        assert_eq!(saved.serial, "362d:0210:RIGHT");
+        assert_eq!(saved.active_mode, 2);
+
+        // Another keyboard of the same model takes the first one saved
+        let mut other = right.config.clone();
+        other.serial = Some("OTHER".into());
+        assert_eq!(find(&controllers, &other).unwrap().serial, "362d:0210:LEFT");
+    }
+
+    #[test]
+    fn imports_saved_devices_as_definitions() {
+        let keyboard = snapshot();
+        let profile = serialize(&[controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION)]);
+        let saved = &parse(&profile).unwrap()[0];
+        assert_eq!(saved.zones.len(), 1);
+        assert_eq!(saved.leds.len(), keyboard.colors.len());
+
+        let configs = Config::all_from_profile(&profile).unwrap();
+        let (config, original) = (&configs[0], &keyboard.config);
+        assert_eq!(config.hardware_id(), original.hardware_id());
+        assert_eq!(config.matrix, original.matrix);
+        assert_eq!(config.leds, original.leds);
+        assert_eq!(config.effects, original.effects);
+        assert_eq!(
+            (config.speed, config.brightness),
+            (original.speed, original.brightness)
+        );
+
+        let mut other = saved.clone();
+        other.serial = "unknown".into();
+        assert!(Config::from_saved(&other).is_err());
+    }
+
+    #[test]
+    fn imports_linear_zones_a_row_each() {
+        let keyboard = snapshot();
+        let profile = serialize(&[controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION)]);
+        let mut saved = parse(&profile).unwrap().remove(0);
+        let zone = |name: &str, leds| SavedZone {
+            name: name.into(),
+            leds,
+            matrix: None,
+        };
+        saved.zones = vec![zone("Top", 3), zone("Bottom", 2)];
+
+        let config = Config::from_saved(&saved).unwrap();
+        assert_eq!(config.matrix, (3, 2));
+        assert_eq!(
+            config.leds,
+            [
+                (0, (0, 0)),
+                (1, (0, 1)),
+                (2, (0, 2)),
+                (3, (1, 0)),
+                (4, (1, 1))
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_nothing_for_other_keyboards() {
+        let keyboard = snapshot();
+        let profile = serialize(&[controller_data(&keyboard, OPENRGB_PROTOCOL_VERSION)]);
+        let controllers = parse(&profile).unwrap();
+
+        let mut other = keyboard.config.clone();
+        other.name = "Other Keyboard".into();
+        assert!(find(&controllers, &other).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use log::warn;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A file holding the id of the running process for process supervisors, removed once dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            warn!("Failed to remove the PID file {:?}: {error}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_the_pid_on_drop() {
+        let path = std::env::temp_dir().join("colorhoster-test.pid");
+        let pidfile = PidFile::create(&path).unwrap();
+        let pid = fs::read_to_string(&path).unwrap();
+        assert_eq!(pid.trim(), process::id().to_string());
+
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,577 @@
+use anyhow::Result;
+use colored::Colorize;
+use itertools::Itertools;
+use log::{debug, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_util::sync::CancellationToken;
+
+use crate::{error::Error, keyboard::Keyboard, keyboards::Keyboards, orp, utils::collect_changes};
+
+const DEBOUNCE: Duration = Duration::from_millis(300);
+const TRASH: &str = ".trash";
+
+/// How profiles saved by clients are stored
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProfileFormat {
+    /// OpenRGB's own binary profiles, which the OpenRGB app can load as well
+    #[default]
+    Orp,
+    /// Keyboard states of ColorHoster, as stored by its older versions
+    Json,
+}
+
+impl ProfileFormat {
+    const ALL: [ProfileFormat; 2] = [ProfileFormat::Orp, ProfileFormat::Json];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ProfileFormat::Orp => "orp",
+            ProfileFormat::Json => "json",
+        }
+    }
+}
+
+/// A profile as read from the disk, in either format
+#[derive(Debug, PartialEq)]
+pub enum ProfileData {
+    Orp(Vec<u8>),
+    Json(String),
+}
+
+/// Files a profile may be stored in, one per format
+fn files(directory: &Path, profile: &str) -> impl Iterator<Item = PathBuf> {
+    ProfileFormat::ALL
+        .into_iter()
+        .map(move |x| directory.join(format!("{profile}.{}", x.extension())))
+}
+
+/// Makes sure a profile named by a client stays a plain file inside the profiles directory
+pub fn check_name(profile: &str) -> Result<()> {
+    let escapes = profile.contains(['/', '\\', ':']) || profile.contains("..");
+    if profile.is_empty() || profile.starts_with('.') || escapes {
+        return Err(Error::Protocol(format!("Invalid profile name {profile}!")).into());
+    }
+    Ok(())
+}
+
+/// Bounds on the profiles clients are allowed to store
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProfileLimits {
+    pub count: Option<usize>,
+    pub bytes: Option<u64>,
+}
+
+impl ProfileLimits {
+    /// Checks whether `profile` of `size` bytes may be written, overwriting an existing one is
+    /// only checked against the byte limit
+    pub fn check(&self, directory: &Path, profile: &str, size: usize) -> Result<()> {
+        if self.count.is_none() && self.bytes.is_none() {
+            return Ok(());
+        }
+
+        let mut others = HashSet::new();
+        let mut bytes = size as u64;
+        let mut exists = false;
+        for entry in directory.read_dir()?.filter_map(|x| x.ok()) {
+            let Some(name) = profile_name(entry.path()) else {
+                continue;
+            };
+            // Saving replaces the profile in every format
+            if name == profile {
+                exists = true;
+                continue;
+            }
+            others.insert(name);
+            bytes += entry.metadata()?.len();
+        }
+        let count = others.len();
+
+        if let Some(limit) = self.count.filter(|&x| !exists && count >= x) {
+            return Err(
+                Error::Protocol(format!("The limit of {limit} profiles is reached!")).into(),
+            );
+        }
+        if let Some(limit) = self.bytes.filter(|&x| bytes > x) {
+            return Err(Error::Protocol(format!(
+                "Profiles would take {bytes} bytes, over the limit of {limit}!"
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Deletes a profile. With a retention window it is moved to the trash instead,
+/// where it can be restored from until the window passes.
+pub fn delete(directory: &Path, profile: &str, retention: Option<Duration>) -> Result<()> {
+    let paths: Vec<_> = files(directory, profile).filter(|x| x.exists()).collect();
+    if paths.is_empty() {
+        return Err(io::Error::from(io::ErrorKind::NotFound).into());
+    }
+    let Some(retention) = retention else {
+        for path in paths {
+            std::fs::remove_file(&path)?;
+        }
+        return Ok(());
+    };
+
+    let trash = directory.join(TRASH);
+    std::fs::create_dir_all(&trash)?;
+    purge_trash(&trash, retention)?;
+
+    for path in paths {
+        let trashed = trash.join(path.file_name().unwrap_or_default());
+        std::fs::rename(&path, &trashed)?;
+        // Moving keeps the modification time, which is used as the time of deletion
+        std::fs::File::options()
+            .write(true)
+            .open(&trashed)?
+            .set_modified(SystemTime::now())?;
+    }
+    Ok(())
+}
+
+/// Moves a deleted profile back from the trash, unless its retention window has passed
+pub fn restore(directory: &Path, profile: &str, retention: Option<Duration>) -> Result<()> {
+    let trash = directory.join(TRASH);
+    let trashed: Vec<_> = files(&trash, profile).filter(|x| x.exists()).collect();
+    if trashed.is_empty() {
+        return Err(Error::Config(format!("Profile {profile} is not in the trash!")).into());
+    }
+    if retention.is_some_and(|x| trashed.iter().any(|path| is_expired(path, x))) {
+        for path in trashed {
+            std::fs::remove_file(&path)?;
+        }
+        return Err(Error::Config(format!("Profile {profile} was deleted too long ago!")).into());
+    }
+    if files(directory, profile).any(|x| x.exists()) {
+        return Err(Error::Config(format!("Profile {profile} already exists!")).into());
+    }
+
+    for path in trashed {
+        std::fs::rename(&path, directory.join(path.file_name().unwrap_or_default()))?;
+    }
+    Ok(())
+}
+
+/// Names of the stored profiles, whatever format they are in
+pub fn list(directory: &Path) -> Result<Vec<String>> {
+    Ok(directory
+        .read_dir()?
+        .filter_map(|x| profile_name(x.ok()?.path()))
+        .unique()
+        .collect())
+}
+
+/// Stores a profile, replacing it in the other formats so only the latest save is loaded
+pub async fn write(
+    directory: &Path,
+    profile: &str,
+    format: ProfileFormat,
+    data: &[u8],
+) -> Result<()> {
+    let path = directory.join(format!("{profile}.{}", format.extension()));
+    tokio::fs::write(&path, data).await?;
+    for other in files(directory, profile).filter(|x| x != &path && x.exists()) {
+        tokio::fs::remove_file(other).await?;
+    }
+    Ok(())
+}
+
+fn purge_trash(trash: &Path, retention: Duration) -> Result<()> {
+    for entry in trash.read_dir()?.filter_map(|x| x.ok()) {
+        if is_expired(&entry.path(), retention) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn is_expired(path: &Path, retention: Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.elapsed().ok())
+        .is_some_and(|x| x > retention)
+}
+
+pub fn watch(
+    directory: PathBuf,
+    keyboards: Keyboards,
+    with_brightness: bool,
+    interrupt: CancellationToken,
+) -> Result<()> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                return;
+            }
+            event
+                .paths
+                .into_iter()
+                .filter_map(profile_name)
+                .for_each(|x| _ = sender.send(x));
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        // The watcher stops as soon as it is dropped, so it has to live in the task
+        let _watcher = watcher;
+
+        loop {
+            let changed = tokio::select! {
+                changed = collect_changes(&mut receiver, DEBOUNCE) => changed,
+                _ = interrupt.cancelled() => return,
+            };
+            if changed.is_empty() {
+                return;
+            }
+
+            for profile in changed {
+                let Some(data) = read_profile(&directory, &profile).await else {
+                    continue;
+                };
+
+                for keyboard in keyboards.items().await.values() {
+                    if keyboard.profile().as_ref() != Some(&profile) {
+                        continue;
+                    }
+
+                    let name = keyboard.config().name.clone();
+                    match state_for(keyboard, &data).await {
+                        Err(error) => warn!(
+                            "Skipped reloading profile {}\x1B[33m on {}\x1B[33m: {error}",
+                            profile.bold(),
+                            name.bold()
+                        ),
+                        Ok(state) => {
+                            debug!("Reloading profile {} on {}...", profile.bold(), name.bold());
+                            keyboard.reload_state(state, with_brightness);
+                        }
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Loads a profile onto every connected keyboard it fits, skipping the rest. Keyboards
+/// connected for the first time later on get it as well, until the interrupt.
+pub async fn apply_default(
+    directory: PathBuf,
+    profile: String,
+    keyboards: &Keyboards,
+    with_brightness: bool,
+    interrupt: CancellationToken,
+) {
+    let mut attached = keyboards.subscribe_attached();
+    if let Some(data) = read_default(&directory, &profile).await {
+        for keyboard in keyboards.items().await.values() {
+            load_default(keyboard, &profile, &data, with_brightness).await;
+        }
+    }
+
+    tokio::spawn(async move {
+        loop {
+            let keyboard = tokio::select! {
+                keyboard = attached.recv() => keyboard,
+                _ = interrupt.cancelled() => return,
+            };
+            let keyboard = match keyboard {
+                Ok(keyboard) => keyboard,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            // The profile is read again, as it may have changed since the startup
+            if let Some(data) = read_default(&directory, &profile).await {
+                load_default(&keyboard, &profile, &data, with_brightness).await;
+            }
+        }
+    });
+}
+
+async fn read_default(directory: &Path, profile: &str) -> Option<ProfileData> {
+    let data = read_profile(directory, profile).await;
+    if data.is_none() {
+        warn!("Default profile {} does not exist!", profile.bold());
+    }
+    data
+}
+
+async fn load_default(
+    keyboard: &Keyboard,
+    profile: &str,
+    data: &ProfileData,
+    with_brightness: bool,
+) {
+    let name = keyboard.config().name.clone();
+    match state_for(keyboard, data).await {
+        Err(error) => warn!(
+            "Skipped default profile {}\x1B[33m on {}\x1B[33m: {error}",
+            profile.bold(),
+            name.bold()
+        ),
+        Ok(state) => {
+            debug!(
+                "Loading default profile {} on {}...",
+                profile.bold(),
+                name.bold()
+            );
+            keyboard.load_state(state, with_brightness);
+            keyboard.set_profile(Some(profile.to_string()));
+        }
+    }
+}
+
+/// Reads a profile in whichever format it is stored in
+pub async fn read_profile(directory: &Path, profile: &str) -> Option<ProfileData> {
+    let path = |format: ProfileFormat| directory.join(format!("{profile}.{}", format.extension()));
+    if let Ok(data) = tokio::fs::read(path(ProfileFormat::Orp)).await {
+        return Some(ProfileData::Orp(data));
+    }
+    let data = tokio::fs::read_to_string(path(ProfileFormat::Json)).await;
+    data.ok().map(ProfileData::Json)
+}
+
+/// The state a profile sets on a keyboard, failing when the profile does not fit it
+async fn state_for(keyboard: &Keyboard, data: &ProfileData) -> Result<String> {
+    match data {
+        ProfileData::Json(state) => {
+            keyboard.check_state(state).await?;
+            Ok(state.clone())
+        }
+        ProfileData::Orp(data) => {
+            let controllers = orp::parse(data)?;
+            let saved = orp::find(&controllers, &keyboard.config())?;
+            keyboard.import_profile(saved).await
+        }
+    }
+}
+
+fn profile_name(path: PathBuf) -> Option<String> {
+    let extension = path.extension()?.to_str()?;
+    if !ProfileFormat::ALL
+        .iter()
+        .any(|x| x.extension() == extension)
+    {
+        return None;
+    }
+    Some(path.file_stem()?.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consts::DEFAULT_MAX_REQUESTS, keyboards::Filter};
+
+    #[tokio::test]
+    async fn debounces_repeated_writes() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        sender.send("default".to_string()).unwrap();
+        sender.send("default".to_string()).unwrap();
+        sender.send("gaming".to_string()).unwrap();
+
+        let changed = collect_changes(&mut receiver, Duration::from_millis(10)).await;
+        assert_eq!(
+            changed,
+            HashSet::from(["default".to_string(), "gaming".to_string()])
+        );
+    }
+
+    fn directory(name: &str, profiles: &[(&str, usize)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("colorhoster-{name}"));
+        _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        for (profile, size) in profiles {
+            std::fs::write(directory.join(format!("{profile}.json")), "x".repeat(*size)).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn limits_profile_count() {
+        let directory = directory("count", &[("a", 1), ("b", 1)]);
+        let limits = ProfileLimits {
+            count: Some(2),
+            bytes: None,
+        };
+
+        assert!(limits.check(&directory, "c", 1).is_err());
+        assert!(limits.check(&directory, "a", 1).is_ok());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn limits_profile_bytes() {
+        let directory = directory("bytes", &[("a", 60), ("b", 30)]);
+        let limits = ProfileLimits {
+            count: None,
+            bytes: Some(100),
+        };
+
+        assert!(limits.check(&directory, "c", 10).is_ok());
+        assert!(limits.check(&directory, "c", 11).is_err());
+        assert!(limits.check(&directory, "a", 70).is_ok());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_default_profile() {
+        let directory = directory("default", &[("boot", 4)]);
+        assert_eq!(
+            read_profile(&directory, "boot").await,
+            Some(ProfileData::Json("xxxx".to_string()))
+        );
+
+        write(&directory, "boot", ProfileFormat::Orp, b"orp")
+            .await
+            .unwrap();
+        assert!(!directory.join("boot.json").exists());
+        assert_eq!(
+            read_profile(&directory, "boot").await,
+            Some(ProfileData::Orp(b"orp".to_vec()))
+        );
+        assert_eq!(read_profile(&directory, "missing").await, None);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_edited_profiles() {
+        let config = crate::config::Config::fixture("keyboard");
+        let configs = [((config.vendor_id, config.product_id), config)].into();
+        let keyboards = Keyboards::simulate(
+            configs,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Filter::default(),
+            DEFAULT_MAX_REQUESTS,
+        )
+        .await
+        .unwrap();
+        let keyboard = keyboards.items().await[0].clone();
+        let mut state: serde_json::Value =
+            serde_json::from_str(&keyboard.save_state().await.unwrap()).unwrap();
+        assert_ne!(keyboard.snapshot().await.speed, 42);
+        state["speed"] = 42.into();
+
+        let directory = directory("reload", &[]);
+        let interrupt = CancellationToken::new();
+        keyboard.set_profile(Some("desk".to_string()));
+        watch(
+            directory.clone(),
+            keyboards.clone(),
+            false,
+            interrupt.clone(),
+        )
+        .unwrap();
+        std::fs::write(directory.join("desk.json"), state.to_string()).unwrap();
+
+        let reloaded = async {
+            while keyboard.snapshot().await.speed != 42 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reloaded)
+            .await
+            .expect("Profile was not reloaded!");
+
+        interrupt.cancel();
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_default_profile_on_startup() {
+        let config = crate::config::Config::fixture("keyboard");
+        let configs = [((config.vendor_id, config.product_id), config)].into();
+        let keyboards = Keyboards::simulate(
+            configs,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Filter::default(),
+            DEFAULT_MAX_REQUESTS,
+        )
+        .await
+        .unwrap();
+        let keyboard = keyboards.items().await[0].clone();
+        let state = keyboard.save_state().await.unwrap();
+
+        let directory = directory("startup", &[]);
+        std::fs::write(directory.join("boot.json"), &state).unwrap();
+        let interrupt = CancellationToken::new();
+        let apply = |profile: &str| {
+            let profile = profile.to_string();
+            apply_default(
+                directory.clone(),
+                profile,
+                &keyboards,
+                false,
+                interrupt.clone(),
+            )
+        };
+
+        apply("missing").await;
+        assert_eq!(keyboard.profile(), None);
+        apply("boot").await;
+        assert_eq!(keyboard.profile().as_deref(), Some("boot"));
+
+        interrupt.cancel();
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn deletes_profiles_permanently_without_retention() {
+        let directory = directory("delete", &[("a", 1)]);
+        delete(&directory, "a", None).unwrap();
+        assert!(!directory.join("a.json").exists());
+        assert!(restore(&directory, "a", None).is_err());
+        assert!(delete(&directory, "a", None).is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn restores_trashed_profiles_within_retention() {
+        let directory = directory("trash", &[("a", 3), ("b", 1)]);
+        let retention = Some(Duration::from_secs(60));
+
+        delete(&directory, "a", retention).unwrap();
+        assert!(!directory.join("a.json").exists());
+        assert!(directory.join(TRASH).join("a.json").exists());
+        restore(&directory, "a", retention).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(directory.join("a.json")).unwrap(),
+            "xxx"
+        );
+
+        delete(&directory, "b", retention).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(restore(&directory, "b", Some(Duration::from_millis(10))).is_err());
+        assert!(!directory.join(TRASH).join("b.json").exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn extracts_profile_names() {
+        assert_eq!(
+            profile_name(PathBuf::from("/profiles/default.json")),
+            Some("default".to_string())
+        );
+        assert_eq!(profile_name(PathBuf::from("/profiles/default.json~")), None);
+        assert_eq!(
+            profile_name(PathBuf::from("/profiles/gaming.orp")),
+            Some("gaming".to_string())
+        );
+    }
+}
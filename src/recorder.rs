@@ -0,0 +1,156 @@
+use anyhow::Result;
+use log::warn;
+use palette::rgb::Rgb;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::error::Error;
+
+/// A single `update_colors` call captured by the recorder
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    /// Milliseconds since the recording started
+    pub time: u64,
+    pub device: usize,
+    pub offset: usize,
+    pub colors: Vec<Option<(u8, u8, u8)>>,
+}
+
+impl Frame {
+    /// Fits the colors to a keyboard of `count` LEDs: the ones past the end are dropped, and
+    /// full frames recorded on a smaller keyboard are padded with black
+    pub fn fit(&self, count: usize) -> Vec<Option<Rgb>> {
+        let mut colors: Vec<Option<Rgb>> = self
+            .colors
+            .iter()
+            .take(count.saturating_sub(self.offset))
+            .map(|x| x.map(|(r, g, b)| Rgb::new(r, g, b).into_format()))
+            .collect();
+        if self.offset == 0 {
+            colors.resize(count, Some(Rgb::new(0.0, 0.0, 0.0)));
+        }
+        colors
+    }
+}
+
+/// Appends color frames sent by clients to a file, one JSON object per line
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .map_err(|x| Error::Config(format!("Cannot record to {path:?}: {x}")))?;
+        Ok(Self {
+            start: Instant::now(),
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn record(&self, device: usize, offset: usize, colors: &[Option<Rgb>]) {
+        let frame = Frame {
+            time: self.start.elapsed().as_millis() as u64,
+            device,
+            offset,
+            colors: colors
+                .iter()
+                .map(|x| x.map(|x| x.into_format::<u8>().into_components()))
+                .collect(),
+        };
+
+        let mut line = serde_json::to_vec(&frame).unwrap_or_default();
+        line.push(b'\n');
+        if let Err(error) = self.file.lock().unwrap().write_all(&line) {
+            warn!("Failed to record a frame: {error}");
+        }
+    }
+}
+
+pub fn read_frames(path: &Path) -> Result<Vec<Frame>> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .filter(|x| !x.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|x| Error::Config(format!("Invalid frame on line {}: {x}", i + 1)).into())
+        })
+        .collect()
+}
+
+/// Emits the frames in order, keeping the pacing they were recorded with
+pub async fn replay(frames: &[Frame], mut emit: impl AsyncFnMut(&Frame)) {
+    let Some(first) = frames.first() else {
+        return;
+    };
+
+    let start = Instant::now();
+    for frame in frames {
+        let delay = Duration::from_millis(frame.time.saturating_sub(first.time));
+        tokio::time::sleep_until(start + delay).await;
+        emit(frame).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_recorded_frames_in_order() {
+        let path = std::env::temp_dir().join(format!("colorhoster-{}.jsonl", std::process::id()));
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.record(0, 0, &[Some(Rgb::new(1.0, 0.0, 0.0)), None]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        recorder.record(0, 1, &[Some(Rgb::new(0.0, 0.0, 1.0))]);
+
+        let frames = read_frames(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].time - frames[0].time >= 100);
+
+        let start = Instant::now();
+        let mut emitted = Vec::new();
+        replay(&frames, async |frame| {
+            emitted.push((start.elapsed(), frame.clone()))
+        })
+        .await;
+
+        assert_eq!(emitted.len(), 2);
+        assert_eq!(emitted[0].1.colors, vec![Some((255, 0, 0)), None]);
+        assert_eq!(emitted[1].1.colors, vec![Some((0, 0, 255))]);
+        assert!(emitted[0].0 < Duration::from_millis(50));
+        assert!(emitted[1].0 >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn fits_frames_to_the_keyboard() {
+        let red = Some((255, 0, 0));
+        let full = Frame {
+            time: 0,
+            device: 0,
+            offset: 0,
+            colors: vec![red; 4],
+        };
+        assert_eq!(full.fit(2).len(), 2);
+        assert_eq!(full.fit(6)[5], Some(Rgb::new(0.0, 0.0, 0.0)));
+
+        let single = Frame {
+            offset: 3,
+            colors: vec![red],
+            ..full
+        };
+        assert_eq!(single.fit(6).len(), 1);
+        assert!(single.fit(3).is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::Result;
+use std::{fs::File, io::BufWriter, path::Path};
+
+use crate::keyboard::KeyboardSnapshot;
+
+const KEY_SIZE: u32 = 16;
+const KEY_GAP: u32 = 2;
+const BACKGROUND: [u8; 3] = [24, 24, 24];
+
+/// Renders every keyboard's per-key colors on the matrix grid, stacking the keyboards vertically
+pub fn write_png(path: &Path, snapshots: &[KeyboardSnapshot]) -> Result<(u32, u32)> {
+    let width = snapshots
+        .iter()
+        .map(|x| x.config.matrix.0 * KEY_SIZE)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let height = snapshots
+        .iter()
+        .map(|x| x.config.matrix.1 * KEY_SIZE)
+        .sum::<u32>()
+        .max(1);
+
+    let mut pixels = BACKGROUND.repeat((width * height) as usize);
+    let mut top = 0;
+    for snapshot in snapshots {
+        for &(led, (row, col)) in &snapshot.config.leds {
+            let Some(color) = snapshot.colors.get(led as usize) else {
+                continue;
+            };
+            let (x, y) = (col as u32 * KEY_SIZE, top + row as u32 * KEY_SIZE);
+            for dy in KEY_GAP / 2..KEY_SIZE - KEY_GAP / 2 {
+                for dx in KEY_GAP / 2..KEY_SIZE - KEY_GAP / 2 {
+                    let index = (((y + dy) * width + x + dx) * 3) as usize;
+                    pixels[index..index + 3].copy_from_slice(&[color.red, color.green, color.blue]);
+                }
+            }
+        }
+        top += snapshot.config.matrix.1 * KEY_SIZE;
+    }
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn writes_matrix_sized_png() {
+        let snapshot = KeyboardSnapshot::for_test(Config::fixture("keyboard"));
+
+        let path = std::env::temp_dir().join("colorhoster-snapshot.png");
+        write_png(&path, &[snapshot]).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().width, 3 * KEY_SIZE);
+        assert_eq!(reader.info().height, 2 * KEY_SIZE);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::Result;
+use colored::Colorize;
+use log::{error, warn};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Spawns a background task that is started again whenever it panics or fails.
+/// The supervisor finishes once the task returns successfully.
+pub fn supervise<F, Fut>(name: &'static str, task: F) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    supervise_with(name, INITIAL_BACKOFF, task)
+}
+
+fn supervise_with<F, Fut>(name: &'static str, backoff: Duration, task: F) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut delay = backoff;
+        loop {
+            match tokio::spawn(task()).await {
+                Err(error) if error.is_panic() => error!(
+                    "Task {} panicked, restarting in {}ms...",
+                    name.bold(),
+                    delay.as_millis()
+                ),
+                Ok(Err(error)) => warn!(
+                    "Task {} failed: {error}, restarting in {}ms...",
+                    name.bold(),
+                    delay.as_millis()
+                ),
+                _ => return,
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_BACKOFF);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    #[tokio::test]
+    async fn restarts_panicked_tasks() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let supervisor = supervise_with("flaky", Duration::from_millis(1), move || {
+            let counter = counter.clone();
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("Transient failure!");
+                }
+                Ok(())
+            }
+        });
+
+        supervisor.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn restarts_failed_tasks() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let supervisor = supervise_with("failing", Duration::from_millis(1), move || {
+            let counter = counter.clone();
+            async move {
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(anyhow::anyhow!("Transient failure!")),
+                    _ => Ok(()),
+                }
+            }
+        });
+
+        supervisor.await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+}
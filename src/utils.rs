@@ -1,4 +1,11 @@
-use std::io::IsTerminal;
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    io::{self, ErrorKind, IsTerminal, Write},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use anyhow::{Error, Result};
 use chrono::Local;
@@ -6,12 +13,19 @@ use colored::Colorize;
 use fern::{
     Output,
     colors::{Color, ColoredLevelConfig},
-    log_file,
 };
 use palette::{encoding::Srgb, rgb::Rgb};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc::UnboundedReceiver,
+    time::Sleep,
+};
+
+use crate::{
+    cli::CLI,
+    consts::{DEFAULT_LOG_FILES, DEFAULT_LOG_MAX_SIZE},
+    error::is_io_disconnect,
+    logs::RotatingFile,
 };
 
 #[cfg(windows)]
@@ -19,7 +33,7 @@ const LOG_FILE: &'static str = "C:\\Windows\\Temp\\colorhoster.log";
 #[cfg(any(unix, target_os = "macos"))]
 const LOG_FILE: &'static str = "/tmp/colorhoster.log";
 
-pub fn setup_logger() -> () {
+pub fn setup_logger(args: &CLI) -> () {
     let colors = ColoredLevelConfig::new()
         .info(Color::Green)
         .warn(Color::Yellow)
@@ -28,7 +42,10 @@ pub fn setup_logger() -> () {
     let output = if std::io::stdout().is_terminal() {
         Output::from(std::io::stdout())
     } else {
-        Output::from(log_file(LOG_FILE).expect("Failed to open log file!"))
+        let max_size = args.log_max_size.unwrap_or(DEFAULT_LOG_MAX_SIZE) * 1024 * 1024;
+        let keep = args.log_files.unwrap_or(DEFAULT_LOG_FILES);
+        let file = RotatingFile::open(LOG_FILE.as_ref(), max_size, keep);
+        Output::from(Box::new(file.expect("Failed to open log file!")) as Box<dyn Write + Send>)
     };
 
     fern::Dispatch::new()
@@ -49,12 +66,31 @@ pub fn setup_logger() -> () {
                 message
             ))
         })
-        .level(log::LevelFilter::Debug)
+        .level(args.log_level())
         .chain(output)
         .apply()
         .expect("Failed to setup logger!");
 }
 
+/// Waits for the first change and then keeps collecting until no new changes
+/// arrive within `debounce`, so editors writing a file in several steps trigger a single reload
+pub async fn collect_changes<T: Eq + Hash>(
+    receiver: &mut UnboundedReceiver<T>,
+    debounce: Duration,
+) -> HashSet<T> {
+    let mut changed = HashSet::new();
+    let Some(change) = receiver.recv().await else {
+        return changed;
+    };
+    changed.insert(change);
+
+    while let Ok(Some(change)) = tokio::time::timeout(debounce, receiver.recv()).await {
+        changed.insert(change);
+    }
+
+    changed
+}
+
 pub trait BufferExt {
     fn extend_from_str(&mut self, str: &str);
     fn extend_from_color(&mut self, color: &Rgb<Srgb, u8>);
@@ -86,35 +122,61 @@ impl BufferExt for Vec<u8> {
     }
 
     fn read_u32_le(&self, offset: usize) -> Result<u32> {
-        Ok(u32::from_le_bytes(
-            self[offset..offset + 4].try_into().unwrap(),
-        ))
+        Ok(u32::from_le_bytes(read_bytes(self, offset)?))
     }
 
     fn read_u16_le(&self, offset: usize) -> Result<u16> {
-        Ok(u16::from_le_bytes(
-            self[offset..offset + 2].try_into().unwrap(),
-        ))
+        Ok(u16::from_le_bytes(read_bytes(self, offset)?))
     }
 
     fn read_rgb(&self, offset: usize) -> Result<Rgb<Srgb, u8>> {
-        Ok(Rgb::new(self[offset], self[offset + 1], self[offset + 2]))
+        let [red, green, blue] = read_bytes(self, offset)?;
+        Ok(Rgb::new(red, green, blue))
     }
 }
 
+/// Requests are sized by clients, so reading past their end is a protocol error
+fn read_bytes<const N: usize>(buffer: &[u8], offset: usize) -> Result<[u8; N]> {
+    offset
+        .checked_add(N)
+        .and_then(|end| buffer.get(offset..end))
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| {
+            crate::error::Error::Protocol(format!(
+                "Request of {} bytes is too short to read {N} at {offset}!",
+                buffer.len()
+            ))
+            .into()
+        })
+}
+
+/// A connection to a client, over TCP or a Unix domain socket
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
 pub trait StreamExt {
-    async fn read_rgb(&mut self) -> Result<Rgb<Srgb, f32>>;
-    async fn write_response(&mut self, kind: u32, data: &[u8]) -> Result<()>;
-    async fn read_str(&mut self, len: usize) -> Result<String>;
+    fn read_rgb(&mut self) -> impl Future<Output = Result<Rgb<Srgb, f32>>> + Send;
+    fn read_rgb_f32(&mut self) -> impl Future<Output = Result<Rgb<Srgb, f32>>> + Send;
+    fn write_response(&mut self, kind: u32, data: &[u8])
+    -> impl Future<Output = Result<()>> + Send;
+    fn read_str(&mut self, len: usize) -> impl Future<Output = Result<String>> + Send;
 }
 
-impl StreamExt for TcpStream {
+impl<T: Stream> StreamExt for T {
     async fn read_rgb(&mut self) -> Result<Rgb<Srgb, f32>> {
         let mut buf: [u8; 4] = [0; 4];
         self.read_exact(&mut buf).await?;
         Ok(Rgb::new(buf[0], buf[1], buf[2]).into_format())
     }
 
+    async fn read_rgb_f32(&mut self) -> Result<Rgb<Srgb, f32>> {
+        let red = self.read_f32_le().await?.clamp(0.0, 1.0);
+        let green = self.read_f32_le().await?.clamp(0.0, 1.0);
+        let blue = self.read_f32_le().await?.clamp(0.0, 1.0);
+        Ok(Rgb::new(red, green, blue))
+    }
+
     async fn write_response(&mut self, kind: u32, data: &[u8]) -> Result<()> {
         self.write_all(b"ORGB").await?;
         self.write_u32_le(0).await?;
@@ -127,19 +189,119 @@ impl StreamExt for TcpStream {
     async fn read_str(&mut self, len: usize) -> Result<String> {
         let mut buf: Vec<u8> = vec![0; len];
         self.read_exact(&mut buf).await?;
-        Ok(String::from_utf8_lossy(&buf[..len - 1]).to_string())
+        Ok(String::from_utf8_lossy(&buf[..len.saturating_sub(1)]).to_string())
+    }
+}
+
+/// A client connection whose reads fail with `TimedOut` once the client stalls for longer
+/// than the timeout, while writes and the time spent on handling requests are not limited
+pub struct TimedStream<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: Stream> TimedStream<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        TimedStream {
+            inner,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    /// The connection without the timeout, for waiting on clients between their requests
+    pub fn idle(&mut self) -> &mut S {
+        self.deadline = None;
+        &mut self.inner
+    }
+}
+
+impl<S: Stream> AsyncRead for TimedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            this.deadline = None;
+            return Poll::Ready(result);
+        }
+        let Some(timeout) = this.timeout else {
+            return Poll::Pending;
+        };
+
+        let deadline = (this.deadline).get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.deadline = None;
+                let message = "Client stopped responding mid-request!";
+                Poll::Ready(Err(io::Error::new(ErrorKind::TimedOut, message)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: Stream> AsyncWrite for TimedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
     }
 }
 
 pub trait ErrorExt {
     fn is_disconnect(&self) -> bool;
+    fn exit_code(&self) -> u32;
+    fn class(&self) -> &'static str;
 }
 
 impl ErrorExt for Error {
     fn is_disconnect(&self) -> bool {
-        self.downcast_ref::<std::io::Error>().map_or(false, |e| {
-            e.kind() == std::io::ErrorKind::UnexpectedEof
-                || e.kind() == std::io::ErrorKind::ConnectionReset
-        })
+        if let Some(error) = self.downcast_ref::<crate::error::Error>() {
+            return error.is_disconnect();
+        }
+
+        self.downcast_ref::<std::io::Error>()
+            .map_or(false, is_io_disconnect)
+    }
+
+    fn exit_code(&self) -> u32 {
+        self.downcast_ref::<crate::error::Error>()
+            .map_or(1, |x| x.exit_code())
+    }
+
+    fn class(&self) -> &'static str {
+        self.downcast_ref::<crate::error::Error>()
+            .map_or("unexpected", |x| x.class())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_reads_past_the_buffer() {
+        let buffer = vec![1, 0, 0, 0, 255, 128];
+        assert_eq!(buffer.read_u32_le(0).unwrap(), 1);
+        assert_eq!(buffer.read_u16_le(4).unwrap(), 0x80FF);
+        assert_eq!(buffer.read_rgb(3).unwrap(), Rgb::new(0, 255, 128));
+
+        assert!(buffer.read_u32_le(3).is_err());
+        assert!(buffer.read_rgb(4).is_err());
+        assert!(buffer.read_u16_le(usize::MAX).is_err());
     }
 }